@@ -41,6 +41,7 @@ fn main() {
     let mut run_cmd = Command::new("qemu-system-x86_64");
     run_cmd
         .args(["-m", "256"])
+        .args(["-smp", "4"])
         .args(["-hda", &bios.display().to_string()])
         /*.args(["-drive", "if=none,id=disk,file=test_disk.img"])
         .args([
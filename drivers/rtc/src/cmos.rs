@@ -0,0 +1,119 @@
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::DateTime;
+
+/// The port selecting the CMOS register to access. Its highest bit disables NMIs.
+const CMOS_ADDRESS_PORT: u16 = 0x70;
+/// The port the selected CMOS register is accessed through.
+const CMOS_DATA_PORT: u16 = 0x71;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+
+/// Status register A bit that is set while the clock is updating.
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status register B bit that is set if the hours are in the 24-hour format.
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status register B bit that is set if the values are binary instead of BCD.
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// The bit of the hours register that is set for PM hours in the 12-hour format.
+const HOURS_PM: u8 = 1 << 7;
+
+/// The raw values of the clock registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ClockRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+/// Reads a CMOS register.
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+/// Reads the clock registers once no update is in progress.
+fn read_clock_registers() -> ClockRegisters {
+    while read_register(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    ClockRegisters {
+        seconds: read_register(REGISTER_SECONDS),
+        minutes: read_register(REGISTER_MINUTES),
+        hours: read_register(REGISTER_HOURS),
+        day: read_register(REGISTER_DAY),
+        month: read_register(REGISTER_MONTH),
+        year: read_register(REGISTER_YEAR),
+    }
+}
+
+/// Converts a binary-coded decimal to binary.
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+/// Reads the date and time from the CMOS clock in UTC. The century is read from the century
+/// register if there is one, otherwise it's assumed to be the 21st.
+pub(crate) fn read_date_time(century_register: Option<u8>) -> DateTime {
+    let (registers, century, status_b) = without_interrupts(|| {
+        // The clock can update between reading two registers, so we read until two reads agree
+        let mut registers = read_clock_registers();
+        loop {
+            let next_registers = read_clock_registers();
+            if next_registers == registers {
+                break;
+            }
+            registers = next_registers;
+        }
+        let century = century_register.map(read_register);
+        (registers, century, read_register(REGISTER_STATUS_B))
+    });
+
+    let is_pm = registers.hours & HOURS_PM != 0;
+    let mut hours = registers.hours & !HOURS_PM;
+    let mut values = [
+        registers.seconds,
+        registers.minutes,
+        registers.day,
+        registers.month,
+        registers.year,
+    ];
+    let mut century = century.unwrap_or(20);
+    if status_b & STATUS_B_BINARY == 0 {
+        hours = from_bcd(hours);
+        if century_register.is_some() {
+            century = from_bcd(century);
+        }
+        for value in values.iter_mut() {
+            *value = from_bcd(*value);
+        }
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hours %= 12;
+        if is_pm {
+            hours += 12;
+        }
+    }
+    let [second, minute, day, month, year] = values;
+    DateTime {
+        year: century as u16 * 100 + year as u16,
+        month,
+        day,
+        hour: hours,
+        minute,
+        second,
+    }
+}
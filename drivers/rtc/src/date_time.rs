@@ -0,0 +1,84 @@
+use core::fmt;
+
+/// The number of seconds in a day.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    /// The month, from 1 to 12.
+    pub month: u8,
+    /// The day of the month, from 1.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Returns the seconds since the Unix epoch, the date has to be after it.
+    pub fn unix_seconds(&self) -> u64 {
+        days_from_civil(self.year as u64, self.month as u64, self.day as u64) * SECONDS_PER_DAY
+            + self.hour as u64 * 60 * 60
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Returns the date and time the given number of seconds after the Unix epoch.
+    pub fn from_unix_seconds(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(seconds / SECONDS_PER_DAY);
+        let time = seconds % SECONDS_PER_DAY;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / (60 * 60)) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats the date and time as ISO 8601.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Returns the number of days from the Unix epoch to the date.
+///
+/// Counts in 400-year eras starting in March, so leap days are at the end of the years.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    // The epoch is 719468 days after the start of the era of 0000-03-01
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the year, month and day the given number of days after the Unix epoch.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,31 @@
+#![no_std] // no standard library
+#![no_main]
+use internal_utils::structures::{driver::Driver, kernel_information::KernelInformation};
+use kernel::acpi::fadt::Fadt;
+use kernel::time::{set_wall_clock, wall_clock_nanos};
+
+mod cmos;
+
+mod date_time;
+pub use date_time::DateTime;
+
+/// Reads the CMOS clock and sets the kernel's wall-clock time from it, the time advances with
+/// the monotonic clock afterwards.
+pub extern "C" fn driver_init(_kernel_info: KernelInformation) -> Driver {
+    let century_register = Fadt::parse()
+        .map(|fadt| fadt.century_register)
+        .filter(|&register| register != 0);
+    let date_time = cmos::read_date_time(century_register);
+    set_wall_clock(date_time.unix_seconds() * 1_000_000_000);
+    Driver {
+        signature: [
+            0xf4, 0xf5, 0xf6, 0xf7, 0xf4, 0xf5, 0xf6, 0xf7, 0xf4, 0xf5, 0xf6, 0xf7, 0xf4, 0xf5,
+            0xf6, 0xf7,
+        ],
+    }
+}
+
+/// Returns the current date and time in UTC, `None` before the driver is initialized.
+pub fn now() -> Option<DateTime> {
+    wall_clock_nanos().map(|nanos| DateTime::from_unix_seconds(nanos / 1_000_000_000))
+}
@@ -18,6 +18,8 @@ pub struct KernelInformation {
     pub allocator: Arc<Mutex<dyn FullFrameAllocator>>,
     /// The start address of the kernel space in all page maps
     pub kernel_start: PhysAddr,
+    /// The physical address of the ACPI RSDP, if the bootloader found it.
+    pub rsdp_address: Optional<u64>,
 }
 
 #[derive(Clone, Copy)]
@@ -94,6 +96,7 @@ impl KernelInformation {
             memory_regions: &boot_info.memory_regions,
             allocator,
             kernel_start: PhysAddr::new(0x007F_C000_0000u64),
+            rsdp_address: boot_info.rsdp_addr,
         }
     }
 }
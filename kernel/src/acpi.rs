@@ -0,0 +1,134 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+use crate::debug;
+use crate::init::get_kernel_information;
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+mod power;
+pub use power::{reboot, shutdown};
+
+/// The signature the RSDP starts with.
+const RSDP_SIGNATURE: [u8; 8] = *b"RSD PTR ";
+/// The size of the RSDP in ACPI 1.0, the part its checksum covers.
+const RSDP_V1_SIZE: usize = 20;
+
+/// The Root System Description Pointer, it points to the table of all the other tables.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // The fields below only exist since ACPI 2.0
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// The header every ACPI table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// The table listing the addresses of all the other tables.
+#[derive(Debug, Clone, Copy)]
+struct RootTable {
+    address: PhysAddr,
+    /// 8 for the XSDT, 4 for the RSDT.
+    entry_size: u64,
+}
+
+static ROOT_TABLE: Mutex<Option<RootTable>> = Mutex::new(None);
+
+/// Reads a value from physical memory through the kernel's mapping of it.
+///
+/// # Safety
+/// The value has to be in physical memory and the kernel's paging table has to be active.
+pub(crate) unsafe fn read_physical<T>(address: PhysAddr) -> T {
+    let physical_memory_offset = get_kernel_information().physical_memory_offset;
+    read_unaligned((physical_memory_offset + address.as_u64()) as *const T)
+}
+
+/// Checks that the bytes in physical memory add up to 0, the way ACPI checksums work.
+unsafe fn is_checksum_valid(address: PhysAddr, length: usize) -> bool {
+    (0..length as u64)
+        .map(|offset| read_physical::<u8>(address + offset))
+        .fold(0u8, |sum, byte| sum.wrapping_add(byte))
+        == 0
+}
+
+/// Finds the root table through the RSDP the bootloader found, has to run with the kernel's
+/// paging table active.
+pub(crate) fn init() {
+    let rsdp_address = match get_kernel_information().rsdp_address.into_option() {
+        Some(address) => PhysAddr::new(address),
+        None => {
+            debug::log("No ACPI RSDP found");
+            return;
+        }
+    };
+    let rsdp: Rsdp = unsafe { read_physical(rsdp_address) };
+    if rsdp.signature != RSDP_SIGNATURE || !unsafe { is_checksum_valid(rsdp_address, RSDP_V1_SIZE) }
+    {
+        debug::log("Invalid ACPI RSDP");
+        return;
+    }
+    let root_table =
+        if rsdp.revision >= 2 && unsafe { is_checksum_valid(rsdp_address, size_of::<Rsdp>()) } {
+            RootTable {
+                address: PhysAddr::new(rsdp.xsdt_address),
+                entry_size: 8,
+            }
+        } else {
+            RootTable {
+                address: PhysAddr::new(rsdp.rsdt_address as u64),
+                entry_size: 4,
+            }
+        };
+    ROOT_TABLE.lock().replace(root_table);
+    debug::log("ACPI tables found");
+}
+
+/// Returns the physical address of the ACPI table with the signature, if there is a valid one.
+///
+/// The kernel's paging table has to be active.
+pub(crate) fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let root_table = (*ROOT_TABLE.lock())?;
+    unsafe {
+        let header: SdtHeader = read_physical(root_table.address);
+        let entry_count = (header.length as u64).checked_sub(size_of::<SdtHeader>() as u64)?
+            / root_table.entry_size;
+        let entries = root_table.address + size_of::<SdtHeader>();
+        (0..entry_count)
+            .map(|index| {
+                let entry = entries + index * root_table.entry_size;
+                PhysAddr::new(match root_table.entry_size {
+                    8 => read_physical::<u64>(entry),
+                    _ => read_physical::<u32>(entry) as u64,
+                })
+            })
+            .find(|&address| {
+                let header: SdtHeader = read_physical(address);
+                header.signature == *signature && is_checksum_valid(address, header.length as usize)
+            })
+    }
+}
@@ -0,0 +1,144 @@
+use x86_64::PhysAddr;
+
+use super::{find_table, read_physical, SdtHeader};
+
+/// FADT flag bit set if the reset register is supported.
+const FLAG_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+/// The FADT length needed for the reset register and value, ACPI 1.0 tables end before them.
+const FADT_RESET_VALUE_END: u32 = 129;
+/// The FADT length needed for the 64-bit address of the DSDT.
+const FADT_X_DSDT_END: u32 = 148;
+
+/// The address space of a generic address that is memory.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+/// The address space of a generic address that is an I/O port.
+const ADDRESS_SPACE_IO: u8 = 1;
+
+/// Where a register of the platform is, as described by ACPI.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// A register the kernel can write a byte to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Io(u16),
+    Memory(PhysAddr),
+}
+
+impl GenericAddress {
+    /// Returns the register, `None` if it's in an address space the kernel doesn't support.
+    pub fn register(&self) -> Option<Register> {
+        match self.address_space {
+            ADDRESS_SPACE_IO => Some(Register::Io(self.address as u16)),
+            ADDRESS_SPACE_MEMORY => Some(Register::Memory(PhysAddr::new(self.address))),
+            _ => None,
+        }
+    }
+}
+
+/// The raw Fixed ACPI Description Table, up to the reset value.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct RawFadt {
+    header: SdtHeader,
+    firmware_control: u32,
+    dsdt: u32,
+    reserved: u8,
+    preferred_power_profile: u8,
+    sci_interrupt: u16,
+    smi_command_port: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4_bios_request: u8,
+    performance_state_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_length: u8,
+    gpe1_length: u8,
+    gpe1_base: u8,
+    c_state_control: u8,
+    worst_c2_latency: u16,
+    worst_c3_latency: u16,
+    flush_size: u16,
+    flush_stride: u16,
+    duty_offset: u8,
+    duty_width: u8,
+    day_alarm: u8,
+    month_alarm: u8,
+    century: u8,
+    boot_architecture_flags: u16,
+    reserved2: u8,
+    flags: u32,
+    reset_register: GenericAddress,
+    reset_value: u8,
+    arm_boot_architecture_flags: u16,
+    minor_version: u8,
+    x_firmware_control: u64,
+    x_dsdt: u64,
+}
+
+/// The power management parts of the Fixed ACPI Description Table.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// The physical address of the DSDT, the AML code of the platform.
+    pub dsdt: PhysAddr,
+    /// The port ACPI is enabled through, 0 if ACPI is always enabled.
+    pub smi_command_port: u16,
+    /// The value enabling ACPI, written to the SMI command port.
+    pub acpi_enable: u8,
+    pub pm1a_control_block: u16,
+    /// 0 if there is no second PM1 control block.
+    pub pm1b_control_block: u16,
+    /// The CMOS register of the century, 0 if there is none.
+    pub century_register: u8,
+    /// The register resetting the system when the reset value is written to it.
+    pub reset_register: Option<Register>,
+    pub reset_value: u8,
+}
+
+impl Fadt {
+    /// Reads the FADT, the kernel's paging table has to be active.
+    pub fn parse() -> Option<Fadt> {
+        let address = find_table(b"FACP")?;
+        let raw: RawFadt = unsafe { read_physical(address) };
+        let length = raw.header.length;
+        let dsdt = if length >= FADT_X_DSDT_END && raw.x_dsdt != 0 {
+            raw.x_dsdt
+        } else {
+            raw.dsdt as u64
+        };
+        let reset_register =
+            if length >= FADT_RESET_VALUE_END && raw.flags & FLAG_RESET_REGISTER_SUPPORTED != 0 {
+                raw.reset_register.register()
+            } else {
+                None
+            };
+        Some(Fadt {
+            dsdt: PhysAddr::new(dsdt),
+            smi_command_port: raw.smi_command_port as u16,
+            acpi_enable: raw.acpi_enable,
+            pm1a_control_block: raw.pm1a_control_block as u16,
+            pm1b_control_block: raw.pm1b_control_block as u16,
+            century_register: raw.century,
+            reset_register,
+            reset_value: raw.reset_value,
+        })
+    }
+}
@@ -0,0 +1,42 @@
+use x86_64::PhysAddr;
+
+use super::fadt::GenericAddress;
+use super::{find_table, read_physical, SdtHeader};
+
+/// The raw HPET description table.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+struct RawHpet {
+    header: SdtHeader,
+    event_timer_block_id: u32,
+    base_address: GenericAddress,
+    hpet_number: u8,
+    minimum_tick: u16,
+    page_protection: u8,
+}
+
+/// A High Precision Event Timer, as described by its ACPI table.
+#[derive(Debug, Clone, Copy)]
+pub struct Hpet {
+    /// The physical address of the timer's registers.
+    pub base_address: PhysAddr,
+    /// The number of the timer block, for systems with more than one.
+    pub number: u8,
+    /// The smallest number of main counter ticks a periodic timer can be set to.
+    pub minimum_tick: u16,
+    /// The number of comparators of the timer block.
+    pub comparator_count: u8,
+}
+
+impl Hpet {
+    /// Reads the HPET table, the kernel's paging table has to be active.
+    pub fn parse() -> Option<Hpet> {
+        let raw: RawHpet = unsafe { read_physical(find_table(b"HPET")?) };
+        Some(Hpet {
+            base_address: PhysAddr::new(raw.base_address.address),
+            number: raw.hpet_number,
+            minimum_tick: raw.minimum_tick,
+            comparator_count: ((raw.event_timer_block_id >> 8) & 0x1F) as u8 + 1,
+        })
+    }
+}
@@ -0,0 +1,109 @@
+use core::mem::size_of;
+
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+use super::{find_table, read_physical, SdtHeader};
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+
+/// Local APIC flag bit of processors that are enabled.
+const LOCAL_APIC_ENABLED: u32 = 1;
+/// Local APIC flag bit of processors that can be enabled.
+const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// An I/O APIC, it delivers the IRQs starting at its GSI base to local APICs.
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt the I/O APIC handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is connected to a different global system interrupt than its number.
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptSourceOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// The polarity in bits 0-1 and the trigger mode in bits 2-3, 0 means the bus default.
+    pub flags: u16,
+}
+
+/// The interrupt controllers described by the Multiple APIC Description Table.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The APIC IDs of the usable processors.
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApicInfo>,
+    pub overrides: Vec<InterruptSourceOverride>,
+}
+
+impl Madt {
+    /// Reads the MADT, the kernel's paging table has to be active.
+    pub fn parse() -> Option<Madt> {
+        let address = find_table(b"APIC")?;
+        unsafe {
+            let header: SdtHeader = read_physical(address);
+            let mut madt = Madt {
+                local_apic_address: PhysAddr::new(read_physical::<u32>(
+                    address + size_of::<SdtHeader>(),
+                ) as u64),
+                local_apic_ids: Vec::new(),
+                io_apics: Vec::new(),
+                overrides: Vec::new(),
+            };
+            // The entries come after the local APIC address and the flags
+            let mut entry = address + size_of::<SdtHeader>() + 8u64;
+            let end = address + header.length as u64;
+            while entry + 2u64 <= end {
+                let entry_type: u8 = read_physical(entry);
+                let entry_length: u8 = read_physical(entry + 1u64);
+                if entry_length < 2 || entry + entry_length as u64 > end {
+                    break;
+                }
+                match entry_type {
+                    ENTRY_LOCAL_APIC => {
+                        let flags: u32 = read_physical(entry + 4u64);
+                        if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 {
+                            madt.local_apic_ids.push(read_physical(entry + 3u64));
+                        }
+                    }
+                    ENTRY_IO_APIC => madt.io_apics.push(IoApicInfo {
+                        id: read_physical(entry + 2u64),
+                        address: PhysAddr::new(read_physical::<u32>(entry + 4u64) as u64),
+                        gsi_base: read_physical(entry + 8u64),
+                    }),
+                    ENTRY_INTERRUPT_SOURCE_OVERRIDE => {
+                        madt.overrides.push(InterruptSourceOverride {
+                            irq: read_physical(entry + 3u64),
+                            gsi: read_physical(entry + 4u64),
+                            flags: read_physical(entry + 8u64),
+                        })
+                    }
+                    ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE => {
+                        madt.local_apic_address = PhysAddr::new(read_physical(entry + 4u64));
+                    }
+                    _ => {}
+                }
+                entry += entry_length as u64;
+            }
+            Some(madt)
+        }
+    }
+
+    /// Returns the global system interrupt the ISA IRQ is connected to, with the flags of its
+    /// override if it has one.
+    pub fn irq_to_gsi(&self, irq: u8) -> (u32, u16) {
+        self.overrides
+            .iter()
+            .find(|source_override| source_override.irq == irq)
+            .map_or((irq as u32, 0), |source_override| {
+                (source_override.gsi, source_override.flags)
+            })
+    }
+}
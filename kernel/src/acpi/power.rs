@@ -0,0 +1,120 @@
+use x86_64::instructions::{interrupts, port::Port};
+use x86_64::PhysAddr;
+
+use super::fadt::{Fadt, Register};
+use super::{read_physical, SdtHeader};
+use crate::hlt_loop;
+use crate::init::get_kernel_information;
+use crate::memory::switch_to_kernel_memory;
+
+/// PM1 control register bit that is set while ACPI is enabled.
+const PM1_SCI_ENABLED: u16 = 1;
+/// PM1 control register bit putting the system to the sleep type.
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+/// The position of the sleep type in the PM1 control register.
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+
+/// AML opcode of a name definition.
+const AML_NAME_OP: u8 = 0x08;
+/// AML opcode of a package.
+const AML_PACKAGE_OP: u8 = 0x12;
+/// AML prefix of a byte constant.
+const AML_BYTE_PREFIX: u8 = 0x0A;
+
+/// The status port of the PS/2 controller.
+const PS2_STATUS_PORT: u16 = 0x64;
+/// PS/2 status bit that is set while the controller didn't take the last input yet.
+const PS2_INPUT_FULL: u8 = 1 << 1;
+/// PS/2 controller command pulsing the CPU reset line.
+const PS2_RESET_COMMAND: u8 = 0xFE;
+
+/// Turns the system off by entering the S5 sleep state. Halts if that's not possible.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    switch_to_kernel_memory();
+    if let Some(fadt) = Fadt::parse() {
+        if let Some((sleep_type_a, sleep_type_b)) = find_s5_sleep_types(fadt.dsdt) {
+            enable_acpi(&fadt);
+            unsafe {
+                Port::<u16>::new(fadt.pm1a_control_block)
+                    .write((sleep_type_a << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+                if fadt.pm1b_control_block != 0 {
+                    Port::<u16>::new(fadt.pm1b_control_block)
+                        .write((sleep_type_b << PM1_SLEEP_TYPE_SHIFT) | PM1_SLEEP_ENABLE);
+                }
+            }
+        }
+    }
+    hlt_loop();
+}
+
+/// Restarts the system through the FADT's reset register, or the keyboard controller if there
+/// is none. Halts if neither works.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    switch_to_kernel_memory();
+    if let Some(fadt) = Fadt::parse() {
+        match fadt.reset_register {
+            Some(Register::Io(port)) => unsafe { Port::<u8>::new(port).write(fadt.reset_value) },
+            Some(Register::Memory(address)) => unsafe {
+                let physical_memory_offset = get_kernel_information().physical_memory_offset;
+                ((physical_memory_offset + address.as_u64()) as *mut u8)
+                    .write_volatile(fadt.reset_value)
+            },
+            None => {}
+        }
+    }
+    let mut status: Port<u8> = Port::new(PS2_STATUS_PORT);
+    unsafe {
+        while status.read() & PS2_INPUT_FULL != 0 {}
+        status.write(PS2_RESET_COMMAND);
+    }
+    hlt_loop();
+}
+
+/// Switches the platform from legacy mode to ACPI mode, if it's not in it already.
+fn enable_acpi(fadt: &Fadt) {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block);
+    unsafe {
+        if control.read() & PM1_SCI_ENABLED != 0 || fadt.smi_command_port == 0 {
+            return;
+        }
+        Port::<u8>::new(fadt.smi_command_port).write(fadt.acpi_enable);
+        while control.read() & PM1_SCI_ENABLED == 0 {}
+    }
+}
+
+/// Finds the sleep types of the S5 state in the `\_S5_` package of the DSDT.
+///
+/// Only the simple encoding firmware uses for it is understood, not AML in general.
+fn find_s5_sleep_types(dsdt: PhysAddr) -> Option<(u16, u16)> {
+    let header: SdtHeader = unsafe { read_physical(dsdt) };
+    let read = |offset: u64| unsafe { read_physical::<u8>(dsdt + offset) };
+    let start = core::mem::size_of::<SdtHeader>() as u64;
+    let end = header.length as u64;
+    let position = (start + 1..end.saturating_sub(4)).find(|&offset| {
+        (0..4).all(|index| read(offset + index) == b"_S5_"[index as usize])
+            && (read(offset - 1) == AML_NAME_OP
+                || (read(offset - 1) == b'\\' && read(offset - 2) == AML_NAME_OP))
+    })?;
+    let mut offset = position + 4;
+    if read(offset) != AML_PACKAGE_OP {
+        return None;
+    }
+    offset += 1;
+    // The package length takes as many more bytes as the top two bits of its first byte say
+    offset += ((read(offset) >> 6) + 1) as u64;
+    // Skipping the number of elements
+    offset += 1;
+    let mut read_element = || {
+        if read(offset) == AML_BYTE_PREFIX {
+            offset += 1;
+        }
+        let value = read(offset) as u16;
+        offset += 1;
+        value
+    };
+    let sleep_type_a = read_element();
+    let sleep_type_b = read_element();
+    Some((sleep_type_a & 0b111, sleep_type_b & 0b111))
+}
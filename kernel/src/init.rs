@@ -1,16 +1,18 @@
-use core::cell::RefCell;
-
-use alloc::{rc::Rc, sync::Arc, vec::Vec};
+use alloc::{sync::Arc, vec::Vec};
 use bootloader::BootInfo;
 use internal_utils::serial_println;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use x86_64::PhysAddr;
 
 use crate::{
-    interrupts,
+    acpi, interrupts,
     memory::{self, frame_allocator::BitmapFrameAllocator},
-    processes::thread::Thread,
+    processes::{fpu, thread::Thread, tls},
+    smp,
+    sync::IrqMutex,
     syscalls::system_call::{register_syscall, setup_syscalls},
+    time,
 };
 
 use internal_utils::structures::{
@@ -27,45 +29,62 @@ lazy_static! {
 
 pub(crate) static mut KERNEL_INFORMATION: Option<KernelInformation> = None;
 
-extern "C" fn test_syscall(a: u64, b: u64, caller: Rc<RefCell<Thread>>) -> u64 {
-    let thread = caller.borrow();
+extern "C" fn test_syscall(a: u64, b: u64, _c: u64, caller: Arc<IrqMutex<Thread>>) -> u64 {
+    let (process, thread_id) = {
+        let thread = caller.lock();
+        (thread.process.clone(), thread.id)
+    };
     serial_println!(
         "Syscall 0 from process {} and thread {}",
-        thread.process.as_ref().borrow().id,
-        thread.id
+        process.lock().id,
+        thread_id
     );
     0
 }
 
-extern "C" fn test_syscall2(a: u64, b: u64, caller: Rc<RefCell<Thread>>) -> u64 {
-    let thread = caller.borrow();
+extern "C" fn test_syscall2(a: u64, b: u64, _c: u64, caller: Arc<IrqMutex<Thread>>) -> u64 {
+    let (process, thread_id) = {
+        let thread = caller.lock();
+        (thread.process.clone(), thread.id)
+    };
     serial_println!(
         "Syscall 1 from process {} and thread {}",
-        thread.process.as_ref().borrow().id,
-        thread.id
+        process.lock().id,
+        thread_id
     );
     1
 }
 
 /// Initialises the components of the OS, **must** be called before any other functions.
 pub fn init(boot_info: &'static BootInfo) -> KernelInformation {
+    smp::init_boot_cpu();
+    // Released once the boot processor switches to the first thread
+    smp::lock_kernel();
     debug::print_memory_map(&boot_info.memory_regions);
     memory::save_kernel_memory();
     let mut allocator = BitmapFrameAllocator::init(boot_info);
+    // Reserved before anything else takes the low memory application processors start in
+    let trampoline_frame = allocator.allocate_frame_below(PhysAddr::new(smp::REAL_MODE_MEMORY_END));
     memory::init(boot_info, &mut allocator);
     let kernel_info = KernelInformation::new(boot_info, Arc::new(Mutex::new(allocator)));
+    unsafe {
+        KERNEL_INFORMATION = Some(kernel_info.clone());
+    }
     interrupts::reload_gdt();
     interrupts::init_idt();
     setup_syscalls();
+    fpu::enable();
+    tls::enable();
+    acpi::init();
+    // After the ACPI tables are found, so the HPET can be used
+    time::calibrate_tsc();
     interrupts::enable();
+    time::set_timer_frequency(time::DEFAULT_TIMER_FREQUENCY);
+    smp::init(trampoline_frame);
 
     register_syscall(0, test_syscall);
     register_syscall(1, test_syscall2);
 
-    unsafe {
-        KERNEL_INFORMATION = Some(kernel_info.clone());
-    }
-
     kernel_info
 }
 
@@ -4,22 +4,63 @@
 // We need to implement all interrupt handlers and add basic handling to them so we don't double fault.
 // Better handling for each of them will be added later.
 
+pub(crate) mod apic;
 mod cpu_handlers;
 mod interrupt_register;
 pub use interrupt_register::init_idt;
 pub(crate) mod gdt;
 mod pic_handlers;
+pub(crate) use gdt::set_privilege_stack;
 pub use gdt::{reload_gdt, GDT};
 mod pic;
 
+use x86_64::VirtAddr;
+
 use crate::debug;
+pub(crate) use pic::InterruptIndex;
 
-/// Initializes the PICs and enables interrupts
+/// Initializes the PICs, or the APIC if there is one, and enables interrupts
 pub fn enable() {
     unsafe {
         // can cause undefined behaviour if the offsets were not set correctly
         pic::PICS.lock().initialize();
     }
+    if apic::init() {
+        // The IRQs are delivered by the I/O APIC now
+        unsafe { pic::PICS.lock().disable() };
+        debug::log("APIC enabled");
+    }
     x86_64::instructions::interrupts::enable();
     debug::log("Interrupts enabled");
 }
+
+/// Sets up the GDT, TSS, IDT and local APIC of an application processor, interrupts stay
+/// disabled.
+pub(crate) fn init_application_processor(
+    cpu: usize,
+    privilege_stack: VirtAddr,
+    interrupt_stacks: [VirtAddr; 3],
+) {
+    gdt::load_application_processor_gdt(cpu, privilege_stack, interrupt_stacks);
+    init_idt();
+    apic::init_application_processor();
+}
+
+/// Checks if interrupts are delivered through the APIC instead of the PIC.
+pub(crate) fn is_apic_enabled() -> bool {
+    apic::is_enabled()
+}
+
+/// Makes the local APIC timer interrupt once per period.
+pub(crate) fn set_apic_timer_period(period_nanos: u64) {
+    apic::set_timer_period(period_nanos);
+}
+
+/// Acknowledges the interrupt that is being handled, so the next one can be delivered.
+pub(crate) fn end_of_interrupt(index: InterruptIndex) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { pic::PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
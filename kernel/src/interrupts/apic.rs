@@ -0,0 +1,297 @@
+use core::arch::x86_64::__cpuid;
+use core::hint::spin_loop;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use alloc::vec::Vec;
+use internal_utils::get_current_tick;
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::acpi::madt::{IoApicInfo, Madt};
+use crate::debug;
+use crate::interrupts::pic::InterruptIndex;
+use crate::memory::layout::{IO_APICS_ADDRESS, LOCAL_APIC_ADDRESS, MAX_IO_APICS};
+use crate::memory::map_device_memory;
+use crate::time::{timer_period_nanos, tsc_frequency};
+
+
+/// CPUID leaf 1 EDX bit of processors with a local APIC.
+const CPUID_APIC: u32 = 1 << 9;
+
+const REGISTER_ID: usize = 0x20;
+const REGISTER_TASK_PRIORITY: usize = 0x80;
+const REGISTER_END_OF_INTERRUPT: usize = 0xB0;
+const REGISTER_SPURIOUS_VECTOR: usize = 0xF0;
+const REGISTER_INTERRUPT_COMMAND_LOW: usize = 0x300;
+const REGISTER_INTERRUPT_COMMAND_HIGH: usize = 0x310;
+const REGISTER_LVT_TIMER: usize = 0x320;
+const REGISTER_TIMER_INITIAL_COUNT: usize = 0x380;
+const REGISTER_TIMER_CURRENT_COUNT: usize = 0x390;
+const REGISTER_TIMER_DIVIDE: usize = 0x3E0;
+
+/// Spurious vector register bit enabling the local APIC.
+const SPURIOUS_APIC_ENABLED: u32 = 1 << 8;
+/// LVT bit masking the interrupt.
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT timer bit making the timer periodic.
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Interrupt command delivery mode resetting the processor.
+const INTERRUPT_COMMAND_INIT: u32 = 0b101 << 8;
+/// Interrupt command delivery mode starting the processor at the page of the vector.
+const INTERRUPT_COMMAND_STARTUP: u32 = 0b110 << 8;
+/// Interrupt command bit set while the interrupt wasn't accepted yet.
+const INTERRUPT_COMMAND_PENDING: u32 = 1 << 12;
+/// Interrupt command bit asserting the level of the interrupt.
+const INTERRUPT_COMMAND_ASSERT: u32 = 1 << 14;
+/// Timer divide configuration dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// How long the APIC timer is measured for against the TSC, in milliseconds.
+const TIMER_CALIBRATION_MILLIS: u64 = 10;
+
+const IO_APIC_REGISTER_VERSION: u32 = 0x01;
+const IO_APIC_REGISTER_REDIRECTION: u32 = 0x10;
+/// Redirection entry bit for active low interrupts.
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+/// Redirection entry bit for level triggered interrupts.
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// The ISA IRQs that are routed through the I/O APIC and the vectors they are delivered at.
+const ROUTED_IRQS: [(u8, InterruptIndex); 3] = [
+    (1, InterruptIndex::Keyboard),
+    (14, InterruptIndex::AtaPrimary),
+    (15, InterruptIndex::AtaSecondary),
+];
+
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+/// The number of times per second the local APIC timer counts down.
+static TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The count the timer starts from, so it interrupts once per timer period.
+static TIMER_INITIAL_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Checks if interrupts are delivered through the APIC instead of the PIC.
+pub(crate) fn is_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+fn read_local_apic(register: usize) -> u32 {
+    unsafe { read_volatile((LOCAL_APIC_ADDRESS as usize + register) as *const u32) }
+}
+
+fn write_local_apic(register: usize, value: u32) {
+    unsafe { write_volatile((LOCAL_APIC_ADDRESS as usize + register) as *mut u32, value) }
+}
+
+/// Acknowledges the interrupt that is being handled.
+pub(crate) fn end_of_interrupt() {
+    write_local_apic(REGISTER_END_OF_INTERRUPT, 0);
+}
+
+/// Returns the ID of the local APIC of the running processor.
+pub(crate) fn local_apic_id() -> u8 {
+    (read_local_apic(REGISTER_ID) >> 24) as u8
+}
+
+/// Sends an interrupt command to the local APIC with the ID, and waits until it was accepted.
+fn send_interrupt_command(apic_id: u8, command: u32) {
+    write_local_apic(REGISTER_INTERRUPT_COMMAND_HIGH, (apic_id as u32) << 24);
+    write_local_apic(REGISTER_INTERRUPT_COMMAND_LOW, command);
+    while read_local_apic(REGISTER_INTERRUPT_COMMAND_LOW) & INTERRUPT_COMMAND_PENDING != 0 {
+        spin_loop();
+    }
+}
+
+/// Interrupts the processor with the local APIC ID at the vector of the index.
+pub(crate) fn send_ipi(apic_id: u8, index: InterruptIndex) {
+    send_interrupt_command(apic_id, index.as_u8() as u32);
+}
+
+/// Resets the processor with the local APIC ID, it waits for a startup IPI then.
+pub(crate) fn send_init_ipi(apic_id: u8) {
+    send_interrupt_command(apic_id, INTERRUPT_COMMAND_INIT | INTERRUPT_COMMAND_ASSERT);
+}
+
+/// Starts the processor with the local APIC ID in real mode, at the start of the page with the
+/// number.
+pub(crate) fn send_startup_ipi(apic_id: u8, page: u8) {
+    send_interrupt_command(
+        apic_id,
+        INTERRUPT_COMMAND_STARTUP | INTERRUPT_COMMAND_ASSERT | page as u32,
+    );
+}
+
+/// An I/O APIC mapped into memory.
+struct IoApic {
+    address: VirtAddr,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            write_volatile(self.address.as_mut_ptr::<u32>(), register);
+            read_volatile((self.address + 0x10u64).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            write_volatile(self.address.as_mut_ptr::<u32>(), register);
+            write_volatile((self.address + 0x10u64).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    /// Returns the number of interrupts the I/O APIC handles.
+    fn redirection_count(&self) -> u32 {
+        ((self.read(IO_APIC_REGISTER_VERSION) >> 16) & 0xFF) + 1
+    }
+
+    /// Checks if the I/O APIC handles the global system interrupt.
+    fn handles(&self, gsi: u32) -> bool {
+        self.gsi_base <= gsi && gsi < self.gsi_base + self.redirection_count()
+    }
+
+    /// Delivers the global system interrupt to the local APIC at the vector.
+    fn route(&self, gsi: u32, vector: u8, flags: u16, destination: u8) {
+        let mut low = vector as u32;
+        // 0b11 is active low for the polarity and level triggered for the trigger mode
+        if flags & 0b11 == 0b11 {
+            low |= REDIRECTION_ACTIVE_LOW;
+        }
+        if (flags >> 2) & 0b11 == 0b11 {
+            low |= REDIRECTION_LEVEL_TRIGGERED;
+        }
+        let register = IO_APIC_REGISTER_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.write(register + 1, (destination as u32) << 24);
+        self.write(register, low);
+    }
+}
+
+/// Maps the I/O APIC into the slot after the local APIC.
+fn map_io_apic(index: usize, info: &IoApicInfo) -> Option<IoApic> {
+    let address = VirtAddr::new(IO_APICS_ADDRESS + index as u64 * 0x1000);
+    map_device_memory(
+        Page::containing_address(address),
+        PhysFrame::containing_address(info.address),
+    )?;
+    // The registers don't have to be at the start of the page
+    Some(IoApic {
+        address: address + (info.address.as_u64() & 0xFFF),
+        gsi_base: info.gsi_base,
+    })
+}
+
+/// Switches interrupt delivery to the APIC, if the processor has one and the ACPI tables
+/// describe the I/O APICs. The legacy IRQs are routed to the vectors they had on the PIC and
+/// the local APIC timer takes over from the PIT.
+///
+/// Returns if the APIC is used, the PIC has to be disabled then.
+pub(crate) fn init() -> bool {
+    let has_apic = unsafe { __cpuid(1) }.edx & CPUID_APIC != 0;
+    if !has_apic || tsc_frequency() == 0 {
+        debug::log("No APIC, using the PIC");
+        return false;
+    }
+    let madt = match Madt::parse() {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => {
+            debug::log("No I/O APIC in the MADT, using the PIC");
+            return false;
+        }
+    };
+    let local_apic_mapped = map_device_memory(
+        Page::containing_address(VirtAddr::new(LOCAL_APIC_ADDRESS)),
+        PhysFrame::containing_address(madt.local_apic_address),
+    );
+    if local_apic_mapped.is_none() {
+        return false;
+    }
+    let io_apics: Vec<IoApic> = madt
+        .io_apics
+        .iter()
+        .take(MAX_IO_APICS)
+        .enumerate()
+        .filter_map(|(index, info)| map_io_apic(index, info))
+        .collect();
+
+    init_local_apic();
+    calibrate_timer();
+
+    let destination = local_apic_id();
+    for (irq, index) in ROUTED_IRQS {
+        let (gsi, flags) = madt.irq_to_gsi(irq);
+        if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+            io_apic.route(gsi, index.as_u8(), flags, destination);
+        }
+    }
+    APIC_ENABLED.store(true, Ordering::Relaxed);
+    start_timer();
+    true
+}
+
+/// Enables the local APIC of an application processor and starts its timer, it's calibrated on
+/// the boot processor already.
+pub(crate) fn init_application_processor() {
+    init_local_apic();
+    start_timer();
+}
+
+/// Enables the local APIC of the running processor, accepting all interrupts.
+fn init_local_apic() {
+    write_local_apic(REGISTER_TASK_PRIORITY, 0);
+    write_local_apic(
+        REGISTER_SPURIOUS_VECTOR,
+        SPURIOUS_APIC_ENABLED | InterruptIndex::ApicSpurious.as_u8() as u32,
+    );
+}
+
+/// Measures how fast the local APIC timer counts down against the TSC, and calculates the count
+/// for one timer period from it.
+fn calibrate_timer() {
+    write_local_apic(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write_local_apic(
+        REGISTER_LVT_TIMER,
+        LVT_MASKED | InterruptIndex::Timer.as_u8() as u32,
+    );
+    let calibration_cycles = tsc_frequency() * TIMER_CALIBRATION_MILLIS / 1000;
+    write_local_apic(REGISTER_TIMER_INITIAL_COUNT, u32::MAX);
+    let start = get_current_tick();
+    while get_current_tick() - start < calibration_cycles {}
+    let counted = u32::MAX - read_local_apic(REGISTER_TIMER_CURRENT_COUNT);
+    write_local_apic(REGISTER_TIMER_INITIAL_COUNT, 0);
+
+    TIMER_FREQUENCY.store(
+        counted as u64 * 1000 / TIMER_CALIBRATION_MILLIS,
+        Ordering::Relaxed,
+    );
+    store_timer_period(timer_period_nanos());
+}
+
+/// Calculates the count the timer starts from for the period.
+fn store_timer_period(period_nanos: u64) {
+    let initial_count =
+        TIMER_FREQUENCY.load(Ordering::Relaxed) as u128 * period_nanos as u128 / 1_000_000_000;
+    TIMER_INITIAL_COUNT.store(
+        initial_count.clamp(1, u32::MAX as u128) as u32,
+        Ordering::Relaxed,
+    );
+}
+
+/// Makes the local APIC timer of the running processor interrupt once per period.
+pub(crate) fn set_timer_period(period_nanos: u64) {
+    store_timer_period(period_nanos);
+    start_timer();
+}
+
+/// Starts the local APIC timer of the running processor, interrupting once per timer period.
+fn start_timer() {
+    write_local_apic(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write_local_apic(
+        REGISTER_LVT_TIMER,
+        LVT_TIMER_PERIODIC | InterruptIndex::Timer.as_u8() as u32,
+    );
+    write_local_apic(
+        REGISTER_TIMER_INITIAL_COUNT,
+        TIMER_INITIAL_COUNT.load(Ordering::Relaxed),
+    );
+}
@@ -8,3 +8,18 @@ mod general_protection_fault;
 pub use general_protection_fault::general_protection_fault_handler;
 mod non_maskable_interrupt;
 pub use non_maskable_interrupt::nmi_handler;
+mod divide_error;
+pub use divide_error::divide_error_handler;
+mod invalid_opcode;
+pub use invalid_opcode::invalid_opcode_handler;
+mod stack_segment_fault;
+pub use stack_segment_fault::stack_segment_fault_handler;
+mod alignment_check;
+pub use alignment_check::alignment_check_handler;
+mod device_not_available;
+mod process_fault;
+pub use device_not_available::device_not_available_handler;
+mod x87_floating_point;
+pub use x87_floating_point::x87_floating_point_handler;
+mod simd_floating_point;
+pub use simd_floating_point::simd_floating_point_handler;
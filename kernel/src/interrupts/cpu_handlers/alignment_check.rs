@@ -0,0 +1,18 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles an alignment check exception.
+pub extern "x86-interrupt" fn alignment_check_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::AlignmentCheck);
+    }
+    panic!(
+        "EXCEPTION: ALIGNMENT CHECK\n{:#?}\n{:#?}",
+        stack_frame, error_code
+    );
+}
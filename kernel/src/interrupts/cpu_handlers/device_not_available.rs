@@ -0,0 +1,15 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::memory::with_kernel_memory;
+use crate::processes::get_scheduler;
+use crate::smp::with_kernel_lock;
+
+/// Handles a device not available exception, raised by the first use of the FPU registers
+/// after a thread switch. The registers of the running thread are loaded and the instruction
+/// is retried.
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    let loaded = with_kernel_lock(|| with_kernel_memory(|| get_scheduler().load_fpu_state()));
+    if !loaded {
+        panic!("EXCEPTION: DEVICE NOT AVAILABLE\n{:#?}", stack_frame);
+    }
+}
@@ -0,0 +1,12 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles a divide error (division by zero or a quotient that doesn't fit).
+pub extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::DivideError);
+    }
+    panic!("EXCEPTION: DIVIDE ERROR\n{:#?}", stack_frame);
+}
@@ -1,10 +1,18 @@
-use internal_utils::serial_print;
 use x86_64::structures::idt::InterruptStackFrame;
 
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
 /// Handles a general protection fault.
 pub extern "x86-interrupt" fn general_protection_fault_handler(
-    _stack_frame: InterruptStackFrame,
-    _error_code: u64,
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
 ) {
-    serial_print!("GP Fault {},", _error_code);
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::GeneralProtectionFault);
+    }
+    panic!(
+        "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\n{:#?}",
+        stack_frame, error_code
+    );
 }
@@ -0,0 +1,12 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles an invalid opcode exception.
+pub extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::InvalidOpcode);
+    }
+    panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
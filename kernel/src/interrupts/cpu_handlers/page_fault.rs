@@ -1,10 +1,20 @@
 use internal_utils::serial_println;
+use x86_64::registers::control::Cr3;
 use x86_64::structures::idt::InterruptStackFrame;
 use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::VirtAddr;
 
+use super::process_fault::{is_user_mode, terminate_faulting_process};
 use crate::hlt_loop;
+use crate::memory::with_kernel_memory;
+use crate::processes::get_scheduler;
+use crate::processes::process::CpuFault;
+use crate::smp::with_kernel_lock;
 
 /// Handles a page fault.
+///
+/// Faults on reserved memory of the running process are resolved by mapping a frame, the
+/// faulting instruction is then retried. Other faults in user mode terminate the process.
 pub extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
@@ -12,9 +22,35 @@ pub extern "x86-interrupt" fn page_fault_handler(
     use x86_64::registers::control::Cr2;
     x86_64::instructions::interrupts::disable();
 
+    let address = Cr2::read();
+    if handle_demand_paging(address, error_code) {
+        return;
+    }
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::PageFault(address));
+    }
+
     serial_println!("EXCEPTION: PAGE FAULT");
     serial_println!("{:?}", error_code);
     serial_println!("Page: {:X?}", Cr2::read_raw());
     serial_println!("{:#?}", stack_frame);
     hlt_loop();
 }
+
+/// Tries to map the faulting page for the running process.
+fn handle_demand_paging(address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    let cr3 = Cr3::read().0.start_address();
+    with_kernel_lock(|| {
+        with_kernel_memory(|| {
+            let running_thread = get_scheduler().running_thread();
+            let thread = match running_thread {
+                Some(thread) => thread,
+                None => return false,
+            };
+            let process = thread.lock().process.clone();
+            let process = process.lock();
+            // The fault has to happen in the process's address space for the mapping to fix it
+            process.cr3 == cr3 && process.handle_page_fault(address, error_code)
+        })
+    })
+}
@@ -0,0 +1,28 @@
+use internal_utils::serial_println;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::memory::switch_to_kernel_memory;
+use crate::processes::dispatcher::{kill_process, switch_to_next_thread};
+use crate::processes::get_scheduler;
+use crate::processes::process::{CpuFault, ExitReason};
+use crate::smp::lock_kernel;
+
+/// Checks if the exception was caused by code running in ring 3.
+pub fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Terminates the running process because of the fault it caused, then runs the next thread.
+pub fn terminate_faulting_process(fault: CpuFault) -> ! {
+    // Released once the next thread runs
+    lock_kernel();
+    // The page tables of the process get freed, so there is no going back to them
+    switch_to_kernel_memory();
+    let running_thread = get_scheduler().running_thread();
+    if let Some(thread) = running_thread {
+        let process = thread.lock().process.clone();
+        serial_println!("Process {} terminated: {:?}", process.lock().id, fault);
+        kill_process(process, ExitReason::Fault(fault)).expect("Failed to clean up the process");
+    }
+    switch_to_next_thread();
+}
@@ -0,0 +1,12 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles a SIMD floating-point exception.
+pub extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::SimdFloatingPoint);
+    }
+    panic!("EXCEPTION: SIMD FLOATING POINT\n{:#?}", stack_frame);
+}
@@ -0,0 +1,18 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles a stack-segment fault.
+pub extern "x86-interrupt" fn stack_segment_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::StackSegmentFault);
+    }
+    panic!(
+        "EXCEPTION: STACK SEGMENT FAULT\n{:#?}\n{:#?}",
+        stack_frame, error_code
+    );
+}
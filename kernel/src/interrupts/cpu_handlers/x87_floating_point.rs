@@ -0,0 +1,12 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::process_fault::{is_user_mode, terminate_faulting_process};
+use crate::processes::process::CpuFault;
+
+/// Handles an x87 floating-point exception.
+pub extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    if is_user_mode(&stack_frame) {
+        terminate_faulting_process(CpuFault::X87FloatingPoint);
+    }
+    panic!("EXCEPTION: X87 FLOATING POINT\n{:#?}", stack_frame);
+}
@@ -5,90 +5,98 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::{PrivilegeLevel, VirtAddr};
 
 use crate::debug;
+use crate::smp::{current_cpu_index, BOOT_CPU, MAX_CPUS};
 
 /// the interrupt stack table index of the stack used for double faults
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 pub const NMI_IST_INDEX: u16 = 1;
 pub const TIMER_IST_INDEX: u16 = 2;
 
-lazy_static! {
-    /// The TSS of the OS.
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-
-        const STACK_SIZE: usize = 4096;
-        #[repr(align(16))]
-        struct Stack([u8; STACK_SIZE]);
+const NEW_TSS: TaskStateSegment = TaskStateSegment::new();
+const NEW_GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
 
-        // Stack used when an exception happens in user mode
-        tss.privilege_stack_table[0] = {
+/// The TSS of each CPU, its privilege stack is changed to the kernel stack of the running thread.
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [NEW_TSS; MAX_CPUS];
+/// The GDTs of the application processors, they only differ from `GDT` in their TSS.
+static mut APPLICATION_PROCESSOR_GDTS: [GlobalDescriptorTable; MAX_CPUS] = [NEW_GDT; MAX_CPUS];
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+/// Creates the TSS with its initial stacks.
+fn create_tss() -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    const STACK_SIZE: usize = 4096;
+    #[repr(align(16))]
+    struct Stack([u8; STACK_SIZE]);
 
-            // returns the highest address of the stack because the stack grows downwards
-            stack_start + STACK_SIZE
-        };
+    // Stack used when an exception happens in user mode
+    tss.privilege_stack_table[0] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
 
-        // set the interrupt stack table to the appropriate address
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+        // returns the highest address of the stack because the stack grows downwards
+        stack_start + STACK_SIZE
+    };
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+    // set the interrupt stack table to the appropriate address
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
 
-            // returns the highest address of the stack because the stack grows downwards
-            stack_start + STACK_SIZE
-        };
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
 
-        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+        // returns the highest address of the stack because the stack grows downwards
+        stack_start + STACK_SIZE
+    };
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+    tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
 
-            // returns the highest address of the stack because the stack grows downwards
-            stack_start + STACK_SIZE
-        };
+        // returns the highest address of the stack because the stack grows downwards
+        stack_start + STACK_SIZE
+    };
 
-        tss.interrupt_stack_table[TIMER_IST_INDEX as usize] = {
+    tss.interrupt_stack_table[TIMER_IST_INDEX as usize] = {
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
 
-            static mut STACK: Stack = Stack([0; STACK_SIZE]);
+        let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
 
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+        // returns the highest address of the stack because the stack grows downwards
+        stack_start + STACK_SIZE
+    };
 
-            // returns the highest address of the stack because the stack grows downwards
-            stack_start + STACK_SIZE
-        };
+    tss
+}
 
-        tss
-    };
+/// Creates a GDT with the TSS, the selectors are the same for every TSS.
+fn create_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+
+    let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+
+    let mut tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    tss_selector.set_rpl(PrivilegeLevel::Ring0);
+
+    (
+        gdt,
+        Selectors {
+            kernel_code_selector,
+            kernel_data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
+}
 
-    /// The GDT used by the OS.
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-
-        let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-
-        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
-
-        let mut tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        tss_selector.set_rpl(PrivilegeLevel::Ring0);
-
-        (
-            gdt,
-            Selectors {
-                kernel_code_selector,
-                kernel_data_selector,
-                user_code_selector,
-                user_data_selector,
-                tss_selector
-            },
-        )
-    };
+lazy_static! {
+    /// The GDT used by the OS on the boot processor.
+    pub static ref GDT: (GlobalDescriptorTable, Selectors) = create_gdt(unsafe { &TSS[BOOT_CPU] });
 }
 
 pub struct Selectors {
@@ -101,11 +109,20 @@ pub struct Selectors {
 
 /// Initialises the GDT and TSS.
 pub fn reload_gdt() {
-    use x86_64::instructions::segmentation::{Segment, CS};
-    use x86_64::instructions::tables::load_tss;
     debug::log("Loading GDT and segment registers");
+    unsafe {
+        TSS[BOOT_CPU] = create_tss();
+    }
     GDT.0.load();
     debug::log("GDT loaded");
+    load_segments();
+    debug::log("Segment registers loaded");
+}
+
+/// Loads the segment registers and the TSS from the GDT that was just loaded.
+fn load_segments() {
+    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::tables::load_tss;
     let selector = &GDT.1;
     unsafe {
         CS::set_reg(selector.kernel_code_selector);
@@ -114,5 +131,31 @@ pub fn reload_gdt() {
         DS::set_reg(selector.kernel_data_selector);
         ES::set_reg(selector.kernel_data_selector);
     }
-    debug::log("Segment registers loaded");
+}
+
+/// Loads the GDT and TSS of an application processor, with the privilege stack and the double
+/// fault, NMI and timer interrupt stacks.
+pub(crate) fn load_application_processor_gdt(
+    cpu: usize,
+    privilege_stack: VirtAddr,
+    interrupt_stacks: [VirtAddr; 3],
+) {
+    unsafe {
+        let tss = &mut TSS[cpu];
+        tss.privilege_stack_table[0] = privilege_stack;
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = interrupt_stacks[0];
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = interrupt_stacks[1];
+        tss.interrupt_stack_table[TIMER_IST_INDEX as usize] = interrupt_stacks[2];
+        APPLICATION_PROCESSOR_GDTS[cpu] = create_gdt(&TSS[cpu]).0;
+        APPLICATION_PROCESSOR_GDTS[cpu].load();
+    }
+    load_segments();
+}
+
+/// Sets the stack the running CPU switches to when an interrupt or an exception happens in user
+/// mode.
+pub(crate) fn set_privilege_stack(stack_top: VirtAddr) {
+    unsafe {
+        TSS[current_cpu_index()].privilege_stack_table[0] = stack_top;
+    }
 }
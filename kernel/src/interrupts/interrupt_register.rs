@@ -5,13 +5,16 @@ use crate::{
     debug,
     interrupts::{
         cpu_handlers::{
-            breakpoint_handler, double_fault_handler, general_protection_fault_handler,
-            nmi_handler, page_fault_handler,
+            alignment_check_handler, breakpoint_handler, device_not_available_handler,
+            divide_error_handler, double_fault_handler, general_protection_fault_handler,
+            invalid_opcode_handler, nmi_handler, page_fault_handler, simd_floating_point_handler,
+            stack_segment_fault_handler, x87_floating_point_handler,
         },
         pic::InterruptIndex,
         pic_handlers::{
-            _timer, ata_primary_interrupt_handler, ata_secondary_interrupt_handler,
-            keyboard_interrupt_handler,
+            _reschedule, _timer, apic_spurious_interrupt_handler, ata_primary_interrupt_handler,
+            ata_secondary_interrupt_handler, keyboard_interrupt_handler,
+            tlb_shootdown_interrupt_handler,
         },
     },
 };
@@ -41,6 +44,20 @@ lazy_static! {
 
         idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
 
+        idt.divide_error.set_handler_fn(divide_error_handler);
+
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+
         // ##################
         // # PIC interrupts #
         // ##################
@@ -59,6 +76,21 @@ lazy_static! {
         idt[InterruptIndex::AtaSecondary.as_usize()]
             .set_handler_fn(ata_secondary_interrupt_handler);
 
+        // ##################
+        // #      IPIs      #
+        // ##################
+        unsafe {
+            idt[InterruptIndex::Reschedule.as_usize()]
+                .set_handler_addr(VirtAddr::from_ptr(_reschedule as *const ()))
+                .set_stack_index(crate::interrupts::gdt::TIMER_IST_INDEX);
+        }
+
+        idt[InterruptIndex::TlbShootdown.as_usize()]
+            .set_handler_fn(tlb_shootdown_interrupt_handler);
+
+        idt[InterruptIndex::ApicSpurious.as_usize()]
+            .set_handler_fn(apic_spurious_interrupt_handler);
+
         idt
     };
 }
@@ -14,6 +14,14 @@ pub enum InterruptIndex {
 
     AtaPrimary = PIC_2_OFFSET + 6,
     AtaSecondary,
+
+    /// Makes a CPU look for another thread to run.
+    Reschedule = 0xF0,
+    /// Makes a CPU flush its TLB.
+    TlbShootdown,
+
+    /// The local APIC delivers spurious interrupts here, they must not be acknowledged.
+    ApicSpurious = 0xFF,
 }
 
 impl InterruptIndex {
@@ -4,4 +4,8 @@ mod keyboard;
 pub use keyboard::keyboard_interrupt_handler;
 mod ata;
 pub use ata::{ata_primary_interrupt_handler, ata_secondary_interrupt_handler};
+mod ipi;
+pub use ipi::{_reschedule, tlb_shootdown_interrupt_handler};
+mod spurious;
+pub use spurious::apic_spurious_interrupt_handler;
 mod addresses;
@@ -0,0 +1,44 @@
+use crate::interrupts::end_of_interrupt;
+use crate::interrupts::pic::InterruptIndex;
+use crate::memory::with_kernel_memory;
+use crate::processes::{get_scheduler, run_next_thread, RegistersState};
+use crate::smp::{flush_tlb_if_requested, with_kernel_lock};
+use core::arch::asm;
+use internal_utils::{pop_all, push_all};
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Handles a reschedule IPI, it uses the stack of the timer interrupt as neither interrupts the
+/// other.
+#[no_mangle]
+#[naked]
+pub unsafe extern "C" fn _reschedule() -> ! {
+    asm!(
+        // We have RFLAGS and RIP on the stack already.
+        push_all!(),
+        "mov rdi, rsp",
+        "call reschedule_interrupt_handler",
+        pop_all!(),
+        "iretq",
+        options(noreturn)
+    );
+}
+
+#[no_mangle]
+extern "C" fn reschedule_interrupt_handler(registers_state: *const RegistersState) {
+    let registers_state = unsafe { *registers_state };
+    with_kernel_lock(|| {
+        with_kernel_memory(|| {
+            let switch = get_scheduler().reschedule(registers_state);
+            end_of_interrupt(InterruptIndex::Reschedule);
+            if switch {
+                run_next_thread();
+            }
+        })
+    });
+}
+
+/// Flushes the TLB for the CPU that sent the IPI, it waits until it's done.
+pub extern "x86-interrupt" fn tlb_shootdown_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    flush_tlb_if_requested();
+    end_of_interrupt(InterruptIndex::TlbShootdown);
+}
@@ -3,40 +3,53 @@ use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use spin::Mutex;
 use x86_64::structures::idt::InterruptStackFrame;
 
-use crate::interrupts::pic::PICS;
+use crate::interrupts::end_of_interrupt;
 use crate::interrupts::{
     pic::InterruptIndex, pic_handlers::addresses::PS2_INTERRUPT_CONTROLLER_SCAN_CODE_PORT,
 };
 use crate::log_print;
 use crate::memory::with_kernel_memory;
+use crate::processes::signal::interrupt_foreground_process;
+
+/// The character Ctrl+C is decoded to.
+const END_OF_TEXT: char = '\u{3}';
+/// The character the backspace key is decoded to.
+const BACKSPACE: char = '\u{8}';
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = Mutex::new(
-        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
-    );
+    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(
+            layouts::Us104Key,
+            ScancodeSet1,
+            HandleControl::MapLettersToUnicode
+        ));
 }
 
-/// Handles a keyboard interrupt.
+/// Handles a keyboard interrupt, Ctrl+C interrupts the foreground process.
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
     use x86_64::instructions::port::Port;
     with_kernel_memory(|| {
-        let mut keyboard = KEYBOARD.lock();
-        let mut port = Port::new(PS2_INTERRUPT_CONTROLLER_SCAN_CODE_PORT);
-        let scancode: u8 = unsafe { port.read() };
-
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    // ! this introduces deadlock potential because print will lock the VgaTextBufferInterface
-                    DecodedKey::Unicode(character) => log_print!("{}", character),
-                    DecodedKey::RawKey(key) => log_print!("{:?}", key),
-                }
+        let key = {
+            let mut keyboard = KEYBOARD.lock();
+            let mut port = Port::new(PS2_INTERRUPT_CONTROLLER_SCAN_CODE_PORT);
+            let scancode: u8 = unsafe { port.read() };
+            match keyboard.add_byte(scancode) {
+                Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+                _ => None,
             }
+        };
+
+        match key {
+            Some(DecodedKey::Unicode(END_OF_TEXT)) => interrupt_foreground_process(),
+            // The other Ctrl combinations aren't printed, only the keys that edit the text are
+            Some(DecodedKey::Unicode(character))
+                if character.is_control() && !matches!(character, '\n' | '\t' | BACKSPACE) => {}
+            // ! this introduces deadlock potential because print will lock the VgaTextBufferInterface
+            Some(DecodedKey::Unicode(character)) => log_print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => log_print!("{:?}", key),
+            None => {}
         }
     });
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    end_of_interrupt(InterruptIndex::Keyboard);
 }
@@ -0,0 +1,4 @@
+use x86_64::structures::idt::InterruptStackFrame;
+
+/// Ignores a spurious interrupt of the local APIC, it doesn't need an end of interrupt.
+pub extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
@@ -1,6 +1,9 @@
-use crate::interrupts::pic::{InterruptIndex, PICS};
+use crate::interrupts::end_of_interrupt;
+use crate::interrupts::pic::InterruptIndex;
 use crate::memory::with_kernel_memory;
 use crate::processes::{get_scheduler, run_next_thread, RegistersState};
+use crate::smp::{current_cpu_index, with_kernel_lock, BOOT_CPU};
+use crate::time;
 use core::arch::asm;
 use internal_utils::get_current_tick;
 use internal_utils::{pop_all, push_all};
@@ -23,13 +26,18 @@ pub unsafe extern "C" fn _timer() -> ! {
 extern "C" fn timer_interrupt_handler(registers_state: *const RegistersState) {
     let registers_state = unsafe { *registers_state };
     let tick = get_current_tick();
+    // Every CPU has a timer, the time is kept by one of them
+    if current_cpu_index() == BOOT_CPU {
+        time::tick();
+    }
 
-    with_kernel_memory(|| {
-        get_scheduler().timer_tick(registers_state, tick);
-        unsafe {
-            PICS.lock()
-                .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-        }
-        run_next_thread();
+    with_kernel_lock(|| {
+        with_kernel_memory(|| {
+            let preempt = get_scheduler().timer_tick(registers_state, tick);
+            end_of_interrupt(InterruptIndex::Timer);
+            if preempt {
+                run_next_thread();
+            }
+        })
     });
 }
@@ -2,7 +2,6 @@
 #![no_main]
 #![allow(incomplete_features)]
 #![feature(
-    option_get_or_insert_default,
     abi_x86_interrupt,
     generic_const_exprs,
     core_intrinsics,
@@ -21,12 +20,16 @@ pub use init::{hlt_loop, init, register_driver, reload_drivers};
 
 use crate::logger::Logger;
 
+pub mod acpi;
 mod debug;
 mod interrupts;
 pub mod logger;
 mod memory;
 pub mod processes;
+pub mod smp;
+pub mod sync;
 pub mod syscalls;
+pub mod time;
 
 lazy_static! {
     pub static ref LOGGER: Arc<Mutex<Option<Box<dyn Logger>>>> = Arc::from(Mutex::new(None));
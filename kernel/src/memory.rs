@@ -1,6 +1,7 @@
 mod allocator;
 pub mod frame_allocator;
 mod heap;
+pub(crate) mod layout;
 mod memory_init;
 mod page_table;
 pub use memory_init::init;
@@ -10,10 +11,12 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::{
     registers::control::{Cr3, Cr3Flags},
-    structures::paging::PhysFrame,
+    structures::paging::{Mapper, Page, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr,
 };
 
+use crate::init::get_kernel_information;
+
 lazy_static! {
     static ref KERNEL_CR3: Mutex<PhysAddr> = Mutex::new(PhysAddr::new(0));
 }
@@ -23,6 +26,11 @@ pub(crate) fn save_kernel_memory() {
     *KERNEL_CR3.lock() = x86_64::registers::control::Cr3::read().0.start_address();
 }
 
+/// Returns the address of the kernel's paging table.
+pub(crate) fn get_kernel_memory() -> PhysAddr {
+    *KERNEL_CR3.lock()
+}
+
 /// Switches the paging table used to the kernel's paging table.
 pub(crate) fn switch_to_kernel_memory() {
     let kernel_cr3 = *KERNEL_CR3.lock();
@@ -49,3 +57,21 @@ pub(crate) fn with_kernel_memory<V>(action: impl FnOnce() -> V) -> V {
     };
     result
 }
+
+/// Maps a page of device registers to the frame, with caching disabled.
+///
+/// The kernel's paging table has to be active.
+pub(crate) fn map_device_memory(page: Page<Size4KiB>, frame: PhysFrame<Size4KiB>) -> Option<()> {
+    let kernel_info = get_kernel_information();
+    let mut allocator = kernel_info.allocator.lock();
+    let mut mapper = MEMORY_MAPPER.lock();
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    unsafe { mapper.as_mut()?.map_to(page, frame, flags, &mut *allocator) }
+        .ok()?
+        .flush();
+    Some(())
+}
@@ -1,3 +1,4 @@
+use alloc::collections::BTreeMap;
 use bootloader::{
     boot_info::{MemoryRegionKind, MemoryRegions},
     BootInfo,
@@ -22,6 +23,43 @@ lazy_static! {
         Mutex::new(None);
     static ref FOUR_KILOBYTES_FRAMES_BITFLAG: Mutex<Option<&'static mut [u64; 262_144]>> =
         Mutex::new(None);
+    /// The number of additional owners of the 4K frames that are shared between address spaces.
+    /// Frames with a single owner are not stored.
+    static ref SHARED_FRAMES: Mutex<BTreeMap<u64, u64>> = Mutex::new(BTreeMap::new());
+}
+
+/// Registers another owner of the frame, it will be freed only after every owner releases it.
+pub fn share_frame(frame: PhysFrame<Size4KiB>) {
+    *SHARED_FRAMES
+        .lock()
+        .entry(frame.start_address().as_u64())
+        .or_insert(0) += 1;
+}
+
+/// Checks if the frame has more than one owner.
+pub fn is_frame_shared(frame: PhysFrame<Size4KiB>) -> bool {
+    SHARED_FRAMES
+        .lock()
+        .contains_key(&frame.start_address().as_u64())
+}
+
+/// Removes an owner of the frame.
+///
+/// Returns `true` if the caller was the last owner, so the frame has to be deallocated.
+pub fn release_frame(frame: PhysFrame<Size4KiB>) -> bool {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    let address = frame.start_address().as_u64();
+    match shared_frames.get_mut(&address) {
+        Some(1) => {
+            shared_frames.remove(&address);
+            false
+        }
+        Some(owners) => {
+            *owners -= 1;
+            false
+        }
+        None => true,
+    }
 }
 
 /// A Frame Allocator that allocates according to the usage bitmap of the memory.
@@ -117,6 +155,21 @@ impl BitmapFrameAllocator {
         }
     }
 
+    /// Returns a usable 4K frame that ends below the address, for memory that has to be
+    /// reachable before paging is enabled. The first frame is never returned.
+    pub fn allocate_frame_below(&mut self, end: PhysAddr) -> Option<PhysFrame<Size4KiB>> {
+        let free_4k_frame = {
+            let fbl = FOUR_KILOBYTES_FRAMES_BITFLAG.lock();
+            let four_kilobytes_frames_bitflag_lock = fbl.as_ref()?;
+            (1..end.as_u64() >> 12).find(|frame| {
+                four_kilobytes_frames_bitflag_lock[(frame >> 6) as usize] & (1 << (frame & 63)) == 0
+            })?
+        };
+        let frame_address = PhysAddr::new(free_4k_frame << 12);
+        self.set_used(frame_address.as_u64(), Size4KiB::SIZE)?;
+        PhysFrame::from_start_address(frame_address).ok()
+    }
+
     /// Unconditionally sets the frame at the start_address as used in the bitflags
     fn set_used(&mut self, start_address: u64, size: u64) -> Option<()> {
         match size {
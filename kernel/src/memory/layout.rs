@@ -0,0 +1,47 @@
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+/// Where the local APIC's registers are mapped.
+pub(crate) const LOCAL_APIC_ADDRESS: u64 = 0x007F_8008_0000;
+/// Where the I/O APICs are mapped, a page each.
+pub(crate) const IO_APICS_ADDRESS: u64 = LOCAL_APIC_ADDRESS + Size4KiB::SIZE;
+/// The most I/O APICs that are mapped.
+pub(crate) const MAX_IO_APICS: usize = 8;
+/// Where the HPET's registers are mapped.
+pub(crate) const HPET_ADDRESS: u64 = IO_APICS_ADDRESS + MAX_IO_APICS as u64 * Size4KiB::SIZE;
+
+/// The start of the region the kernel stacks of threads are placed in.
+pub(crate) const KERNEL_STACKS_START: u64 = 0x007F_8010_0000;
+/// The end of the kernel stack region, the kernel is mapped after it.
+pub(crate) const KERNEL_STACKS_END: u64 = 0x007F_C000_0000;
+
+// The start and end of everything at a fixed address in the region every address space shares
+// can't overlap.
+const _: () = {
+    /// The start of the stack the bootloader set up, `kernel-stack-address` in Cargo.toml.
+    const BOOT_STACK_START: u64 = 0x007F_8000_0000;
+    /// The end of the stack the bootloader set up, with the `kernel-stack-size` of Cargo.toml.
+    const BOOT_STACK_END: u64 = BOOT_STACK_START + 0x1_4000;
+
+    let ranges = [
+        (BOOT_STACK_START, BOOT_STACK_END),
+        (LOCAL_APIC_ADDRESS, LOCAL_APIC_ADDRESS + Size4KiB::SIZE),
+        (
+            IO_APICS_ADDRESS,
+            IO_APICS_ADDRESS + MAX_IO_APICS as u64 * Size4KiB::SIZE,
+        ),
+        (HPET_ADDRESS, HPET_ADDRESS + Size4KiB::SIZE),
+        (KERNEL_STACKS_START, KERNEL_STACKS_END),
+    ];
+    let mut i = 0;
+    while i < ranges.len() {
+        let mut j = i + 1;
+        while j < ranges.len() {
+            assert!(
+                ranges[i].1 <= ranges[j].0 || ranges[j].1 <= ranges[i].0,
+                "The fixed virtual address ranges overlap"
+            );
+            j += 1;
+        }
+        i += 1;
+    }
+};
@@ -1,13 +1,31 @@
 pub mod dispatcher;
 
+pub mod elf;
+
+pub mod fpu;
+
+pub(crate) mod kernel_stack;
+
 mod memory_mapper;
 
+pub mod memory_area;
+
 pub mod process;
 
+mod process_table;
+pub use process_table::{ProcessTable, IDLE_PROCESS_ID, INIT_PROCESS_ID};
+
+pub mod signal;
+
 pub mod thread;
 
+pub mod tls;
+
 mod registers_state;
 pub use registers_state::RegistersState;
 
 mod scheduler;
-pub use scheduler::{add_process, get_scheduler, run_next_thread, run_processes};
+pub use scheduler::{
+    add_elf_process, add_process, exec_process, fork_process, get_scheduler, run_next_thread,
+    run_processes, set_quantum_millis, spawn_thread, Scheduler, DEFAULT_QUANTUM_MILLIS,
+};
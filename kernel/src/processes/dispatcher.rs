@@ -1,153 +1,448 @@
 use core::arch::asm;
-use core::cell::Ref;
-use core::cell::RefCell;
-use core::cell::RefMut;
 
-use alloc::rc::Rc;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
 use x86_64::structures::paging::page::AddressNotAligned;
-use x86_64::PhysAddr;
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::debug;
-use crate::interrupts::GDT;
+use crate::interrupts::{set_privilege_stack, GDT};
+use crate::memory::switch_to_kernel_memory;
+use crate::smp::{
+    current_cpu_index, is_address_space_in_use, kernel_lock_depth, push_return_registers,
+    set_address_space, set_kernel_lock_depth, set_syscall_stack,
+};
+use crate::sync::IrqMutex;
 use internal_utils::get_current_tick;
-use internal_utils::mov_all;
+use internal_utils::pop_all;
 
-use super::get_scheduler;
+use super::kernel_stack::free_released_kernel_stacks;
 use super::memory_mapper::clear_user_mode_mapping;
-use super::process::Process;
-use super::thread::Thread;
-use super::thread::ThreadState;
+use super::process::{CpuFault, ExitReason, Process};
+use super::signal::{
+    push_signal_frame, reset_foreground_process, send_signal, Signal, SignalAction,
+};
+use super::thread::{Thread, ThreadState, WaitTarget};
+use super::tls::set_fs_base;
 use super::RegistersState;
+use super::{get_scheduler, run_next_thread, Scheduler};
 
-/// Runs the thread immediately.
-pub fn switch_to_thread(thread: Rc<RefCell<Thread>>) -> ! {
-    let code_selector_id: u64;
-    let data_selector_id: u64;
+/// The paging tables of exited programs that another CPU still had loaded.
+static UNUSED_ADDRESS_SPACES: Mutex<Vec<PhysAddr>> = Mutex::new(Vec::new());
+
+/// Runs the thread immediately on the running CPU, which has to hold the kernel lock.
+///
+/// The lock is released once the thread runs, unless it continues inside of the kernel. The caller
+/// must not hold any other lock of the process subsystem.
+///
+/// The thread handles the deliverable signals of its process first, if they terminate the
+/// process the next thread runs instead.
+pub fn switch_to_thread(thread: Arc<IrqMutex<Thread>>) -> ! {
+    // Signal frames contain the FPU registers too
+    get_scheduler().save_fpu_state();
+    let thread = handle_signals(thread);
     let cr3: PhysAddr;
-    let state: RegistersState;
+    let mut state: RegistersState;
+    let kernel_stack_top: VirtAddr;
+    let kernel_context: Option<VirtAddr>;
+    let fs_base: VirtAddr;
     x86_64::instructions::interrupts::disable();
     {
         let tick = get_current_tick();
-        let mut thread_mut = thread.borrow_mut();
+        let process = thread.lock().process.clone();
+        let mut process = process.lock();
+        let mut thread_mut = thread.lock();
         thread_mut.last_tick = tick;
-        let mut process = thread_mut.process.borrow_mut();
+        kernel_context = thread_mut.kernel_context.take();
+        thread_mut.cpu = Some(current_cpu_index());
         process.last_tick = tick;
-        code_selector_id = if process.kernel_process {
+        cr3 = process.cr3;
+        state = thread_mut.registers_state;
+        fs_base = thread_mut.fs_base;
+        state.cs = if process.kernel_process {
             (GDT.1.kernel_code_selector.index() * 8) as u64
         } else {
             ((GDT.1.user_code_selector.index() * 8) | 3) as u64
         };
-        data_selector_id = if process.kernel_process {
+        state.ss = if process.kernel_process {
             (GDT.1.kernel_data_selector.index() * 8) as u64
         } else {
             ((GDT.1.user_data_selector.index() * 8) | 3) as u64
         };
-        cr3 = process.cr3;
-        state = thread_mut.registers_state;
+        // Interrupts enabled, nested task cleared
+        state.rflags = (state.rflags | 0x200) & !0x4000;
+        kernel_stack_top = thread_mut
+            .kernel_stack
+            .as_ref()
+            .expect("Switching to a terminated thread")
+            .top();
     }
 
-    get_scheduler().running_thread.replace(thread.clone());
+    // The thread enters the kernel on its own stack
+    set_privilege_stack(kernel_stack_top);
+    set_syscall_stack(kernel_stack_top);
+    set_address_space(cr3);
+    set_fs_base(fs_base);
+    free_released_kernel_stacks();
+    free_unused_address_spaces();
+
+    // The run queue keeps the thread alive, nothing left on this stack has to be dropped
+    get_scheduler().set_running_thread(Some(thread));
     unsafe {
-        // We decrement the counter forcefully because that function doesn't return by Rust.
-        Rc::decrement_strong_count(Rc::into_raw(thread));
+        if let Some(stack_pointer) = kernel_context {
+            // The thread blocked with the kernel's page tables active
+            switch_to_kernel_memory();
+            resume_kernel_context(stack_pointer.as_u64());
+        }
+        // Another CPU can take over the stack we're on once the kernel lock is released, so
+        // the registers are loaded from the CPU's own stack
+        let registers_state = push_return_registers(state);
         asm!(
-            "mov cr3, r10",
-            "push r14", // data selector
-            "push r12", // process stack pointer
-            "or r11, 0x200",
-            "and r11, 0xffffffffffffbfff",
-            "push r11", // rflags
-            "push r13", // code selector
-            "push r15", // instruction address to return to
+            "mov cr3, {cr3}",
+            "mov rsp, {registers_state}",
+            "call release_kernel_lock",
             // Loading register state before jumping into thread
-            mov_all!(),
+            pop_all!(),
             "iretq",
-            in("r9") (&state as *const RegistersState as *const u8),
-            in("r10") (cr3.as_u64()),
-            in("r11") (state.rflags),
-            in("r12") (state.rsp.as_u64()),
-            in("r13") (code_selector_id),
-            in("r14") (data_selector_id),
-            in("r15") (state.rip.as_u64()),
+            cr3 = in(reg) cr3.as_u64(),
+            registers_state = in(reg) registers_state,
             options(noreturn)
         );
     }
 }
 
-/// Removes the thread from it's process. If this thread is the last one, the process is cleaned up.
-pub fn exit_thread(thread: Rc<RefCell<Thread>>) -> Result<(), AddressNotAligned> {
-    debug::log("Exiting thread");
-    let borrowed_thread = thread.borrow();
-    let mut borrowed_process = borrowed_thread.process.borrow_mut();
+/// Makes the thread call the handlers of the deliverable signals of its process when it returns
+/// to user mode. Signals the process doesn't handle terminate it, unless they are ignored.
+///
+/// Returns the thread if it still runs, threads that continue inside of the kernel handle the
+/// signals once they return from it.
+fn handle_signals(thread: Arc<IrqMutex<Thread>>) -> Arc<IrqMutex<Thread>> {
+    let process = thread.lock().process.clone();
+    let reason = loop {
+        let mut locked_process = process.lock();
+        if locked_process.kernel_process || thread.lock().kernel_context.is_some() {
+            return thread;
+        }
+        let signal = match locked_process.signals.take_deliverable() {
+            Some(signal) => signal,
+            None => return thread,
+        };
+        match locked_process.signals.action(signal) {
+            SignalAction::Handler { handler, restorer } => {
+                let pushed = push_signal_frame(
+                    &mut locked_process,
+                    &mut thread.lock(),
+                    signal,
+                    handler,
+                    restorer,
+                );
+                if let Err(frame_address) = pushed {
+                    break ExitReason::Fault(CpuFault::PageFault(frame_address));
+                }
+            }
+            SignalAction::Ignore => {}
+            SignalAction::Default if signal.is_ignored_by_default() => {}
+            SignalAction::Default => break ExitReason::Signaled(signal),
+        }
+    };
+
+    debug::log("Terminating process because of a signal");
+    // Nothing left on this stack has to be dropped
+    drop(thread);
+    // The page tables of the process get freed, so there is no going back to them
+    switch_to_kernel_memory();
+    kill_process(process, reason).expect("Failed to clean up the process");
+    switch_to_next_thread();
+}
 
-    remove_thread_from_process_queues(&borrowed_thread, thread.clone(), &mut borrowed_process);
+/// Frees the user mode mapping of the paging table, once no other CPU has it loaded.
+pub fn release_address_space(cr3: PhysAddr) -> Result<(), AddressNotAligned> {
+    if is_address_space_in_use(cr3) {
+        UNUSED_ADDRESS_SPACES.lock().push(cr3);
+        return Ok(());
+    }
+    unsafe { clear_user_mode_mapping(cr3) }
+}
+
+/// Frees the paging tables of exited programs that were in use on other CPUs.
+fn free_unused_address_spaces() {
+    let unused_address_spaces = core::mem::take(&mut *UNUSED_ADDRESS_SPACES.lock());
+    for cr3 in unused_address_spaces {
+        release_address_space(cr3).expect("Failed to clear the user mode mapping");
+    }
+}
+
+/// Blocks the running thread inside of the kernel until it's woken up, other threads run in the
+/// meantime. The thread has to be moved out of the ready state by `state`, unless it only yields.
+/// The caller must not hold any other lock of the process subsystem.
+///
+/// Returns the result the thread was woken up with.
+pub fn block_running_thread(state: ThreadState) -> u64 {
+    let thread = get_scheduler()
+        .running_thread()
+        .expect("There is no running thread to block");
+    if !matches!(state, ThreadState::Ready) {
+        // Threads that block before using up their time slice are interactive
+        thread.lock().promote();
+    }
+    Thread::change_state(&mut get_scheduler(), thread.clone(), state);
+    // The thread can continue on another CPU, which holds the kernel lock for itself then
+    let lock_depth = kernel_lock_depth();
+    unsafe { save_kernel_context() };
+    set_kernel_lock_depth(lock_depth);
+    let result = thread.lock().registers_state.rax;
+    result
+}
+
+/// Runs the next thread, the running thread has to be in a queue already if it should continue.
+pub fn switch_to_next_thread() -> ! {
+    get_scheduler().set_running_thread(None);
+    run_next_thread();
+}
+
+/// Saves the callee-saved registers on the kernel stack and runs the next thread.
+/// Returns when the running thread is switched to again.
+#[naked]
+unsafe extern "C" fn save_kernel_context() {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp", // The stack pointer the thread continues from
+        "sub rsp, 8",   // Aligning the stack for the call
+        "call block_running_thread_at",
+        options(noreturn)
+    );
+}
+
+/// Saves the stack pointer of the running thread so it can continue inside of the kernel later,
+/// then runs the next thread.
+#[no_mangle]
+extern "C" fn block_running_thread_at(stack_pointer: u64) -> ! {
+    let running_thread = get_scheduler().running_thread();
+    if let Some(thread) = running_thread {
+        thread.lock().kernel_context = Some(VirtAddr::new(stack_pointer));
+    }
+    switch_to_next_thread();
+}
+
+/// Continues a thread that blocked inside of the kernel, returning from `save_kernel_context`.
+#[naked]
+unsafe extern "C" fn resume_kernel_context(stack_pointer: u64) -> ! {
+    asm!(
+        "mov rsp, rdi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// Removes the thread from it's process, waking up the threads joining it. If this thread is the
+/// last one, the process is cleaned up.
+pub fn exit_thread(thread: Arc<IrqMutex<Thread>>, exit_code: u64) -> Result<(), AddressNotAligned> {
+    debug::log("Exiting thread");
+    let mut scheduler = get_scheduler();
+    let process = thread.lock().process.clone();
+    let (id, detached, stack, tls) = {
+        let mut locked_process = process.lock();
+        let mut locked_thread = thread.lock();
+        remove_thread_from_process_queues(
+            &mut scheduler,
+            &locked_thread,
+            &thread,
+            &mut locked_process,
+        );
+        locked_thread.terminate();
+        locked_thread.exit_code = Some(exit_code);
+        (
+            locked_thread.id,
+            locked_thread.detached,
+            locked_thread.stack.take(),
+            locked_thread.tls.take(),
+        )
+    };
+    if let Some((stack_start, stack_size)) = stack {
+        process.lock().unmap_memory(stack_start, stack_size);
+    }
+    if let Some(tls) = tls {
+        process.lock().unmap_memory(tls.start, tls.size);
+    }
 
     debug::log("Removed thread from process");
 
-    check_should_remove_process(borrowed_process, &borrowed_thread)?;
+    let joining_threads = get_waiting_threads(&process.lock(), WaitTarget::Thread(id));
+    if joining_threads.is_empty() && !detached {
+        // Kept until the thread is joined
+        process.lock().thread_exit_codes.insert(id, exit_code);
+    }
+    for joining_thread in joining_threads {
+        Thread::wake_up(&mut scheduler, joining_thread, exit_code);
+    }
+
+    let has_no_threads = process.lock().has_no_threads();
+    if has_no_threads {
+        finish_process(&mut scheduler, process, ExitReason::Exited(exit_code))?;
+    }
     Ok(())
 }
 
+/// Wakes up at most `count` threads of the process that wait for the futex at the address.
+///
+/// Returns the number of threads that were woken up.
+pub fn wake_futex(process: &Arc<IrqMutex<Process>>, address: VirtAddr, count: u64) -> u64 {
+    let mut scheduler = get_scheduler();
+    let waiting_threads = get_waiting_threads(&process.lock(), WaitTarget::Futex(address));
+    let mut woken = 0;
+    for waiting_thread in waiting_threads.into_iter().take(count as usize) {
+        Thread::wake_up(&mut scheduler, waiting_thread, 0);
+        woken += 1;
+    }
+    woken
+}
+
+/// Returns the threads of the process that wait for the target.
+fn get_waiting_threads(process: &Process, target: WaitTarget) -> Vec<Arc<IrqMutex<Thread>>> {
+    process
+        .waiting_threads
+        .iter()
+        .filter(|thread| matches!(thread.lock().state, ThreadState::Waiting(t) if t == target))
+        .cloned()
+        .collect()
+}
+
 /// Removes the thread from the respective process queue, depending on the thread state.
+///
+/// The thread and its process are locked by the caller.
 pub(crate) fn remove_thread_from_process_queues(
-    borrowed_thread: &Ref<Thread>,
-    thread: Rc<RefCell<Thread>>,
-    borrowed_process: &mut RefMut<Process>,
+    scheduler: &mut Scheduler,
+    locked_thread: &Thread,
+    thread: &Arc<IrqMutex<Thread>>,
+    locked_process: &mut Process,
 ) {
-    match borrowed_thread.state {
+    match locked_thread.state {
         ThreadState::Running => {
-            let scheduler = get_scheduler();
-            if let Some(current_thread) = scheduler.running_thread.clone() {
-                if Rc::ptr_eq(&thread, &current_thread) {
-                    scheduler.running_thread = None;
+            if let Some(current_thread) = scheduler.running_thread() {
+                if Arc::ptr_eq(thread, &current_thread) {
+                    scheduler.set_running_thread(None);
                 }
             }
         }
         ThreadState::NotStarted => {
-            let nst_pos = borrowed_process
+            let nst_pos = locked_process
                 .not_started_threads
                 .iter()
-                .position(|t| Rc::ptr_eq(t, &thread))
+                .position(|t| Arc::ptr_eq(t, thread))
                 .unwrap();
-            borrowed_process.not_started_threads.swap_remove(nst_pos);
+            locked_process.not_started_threads.swap_remove(nst_pos);
         }
         ThreadState::Ready => {
-            let nst_pos = borrowed_process
+            let nst_pos = locked_process
                 .ready_threads
                 .iter()
-                .position(|t| Rc::ptr_eq(t, &thread))
+                .position(|t| Arc::ptr_eq(t, thread))
                 .unwrap();
-            borrowed_process.ready_threads.swap_remove(nst_pos);
+            locked_process.ready_threads.swap_remove(nst_pos);
+            scheduler.dequeue_thread(thread);
         }
         ThreadState::Sleeping(_) => {
-            let nst_pos = borrowed_process
+            let nst_pos = locked_process
                 .sleeping_threads
                 .iter()
-                .position(|t| Rc::ptr_eq(t, &thread))
+                .position(|t| Arc::ptr_eq(t, thread))
+                .unwrap();
+            locked_process.sleeping_threads.swap_remove(nst_pos);
+        }
+        ThreadState::Waiting(_) => {
+            let nst_pos = locked_process
+                .waiting_threads
+                .iter()
+                .position(|t| Arc::ptr_eq(t, thread))
                 .unwrap();
-            borrowed_process.sleeping_threads.swap_remove(nst_pos);
+            locked_process.waiting_threads.swap_remove(nst_pos);
         }
         _ => {}
     }
 }
 
-/// Checks if the process has no threads and can be safely removed.
-fn check_should_remove_process(
-    borrowed_process: RefMut<Process>,
-    borrowed_thread: &Ref<Thread>,
+/// Cleans up the process that has no threads left.
+///
+/// The threads of the parent waiting for the process are woken up, if there are none the process
+/// is kept as a zombie until the parent waits for it, which is sent the child signal. Its
+/// children are adopted by init, which doesn't wait for them, so they aren't kept as zombies.
+/// Init becomes the foreground process again if the process was it.
+fn finish_process(
+    scheduler: &mut Scheduler,
+    process: Arc<IrqMutex<Process>>,
+    reason: ExitReason,
 ) -> Result<(), AddressNotAligned> {
-    let thread_vectors = [
-        &borrowed_process.not_started_threads,
-        &borrowed_process.ready_threads,
-        &borrowed_process.sleeping_threads,
-    ];
-    if thread_vectors.into_iter().all(|v| v.is_empty()) {
-        //Clean up the process
-        get_scheduler().remove_process(borrowed_thread.process.clone());
-        debug::log("Removed process from scheduler");
-        unsafe {
-            clear_user_mode_mapping(borrowed_process.cr3)?;
+    let id = process.lock().id;
+    scheduler.process_table_mut().remove(id);
+    reset_foreground_process(id);
+    debug::log("Removed process from the process table");
+
+    let parent_id = {
+        let mut locked_process = process.lock();
+        locked_process.exit_reason = Some(reason);
+        locked_process.thread_exit_codes.clear();
+        release_address_space(locked_process.cr3)?;
+        locked_process.parent_id
+    };
+
+    if let Some(parent) = parent_id.and_then(|parent_id| scheduler.process_table().get(parent_id)) {
+        let waiting_threads = get_waiting_threads(&parent.lock(), WaitTarget::Process(id));
+        if waiting_threads.is_empty() {
+            scheduler.process_table_mut().add_zombie(process);
+        }
+        for waiting_thread in waiting_threads {
+            Thread::wake_up(scheduler, waiting_thread, reason.status());
         }
+        send_signal(scheduler, &parent, Signal::Child);
     }
     Ok(())
 }
+
+/// Terminates all the threads of the process and cleans the process up.
+///
+/// The caller must not return to the process's address space if it was the active one.
+pub fn kill_process(
+    process: Arc<IrqMutex<Process>>,
+    reason: ExitReason,
+) -> Result<(), AddressNotAligned> {
+    let mut scheduler = get_scheduler();
+    if let Some(running_thread) = scheduler.running_thread() {
+        let is_running = Arc::ptr_eq(&running_thread.lock().process, &process);
+        if is_running {
+            running_thread.lock().terminate();
+            scheduler.set_running_thread(None);
+        }
+    }
+
+    // Dropping the queues also breaks the reference cycles between the process and its threads
+    let threads = {
+        let mut locked_process = process.lock();
+        [
+            core::mem::take(&mut locked_process.not_started_threads),
+            core::mem::take(&mut locked_process.ready_threads),
+            core::mem::take(&mut locked_process.sleeping_threads),
+            core::mem::take(&mut locked_process.waiting_threads),
+        ]
+    };
+    threads
+        .iter()
+        .flatten()
+        .for_each(|thread| thread.lock().terminate());
+    // The threads running on other CPUs are stopped once they notice
+    scheduler.preempt_terminated_threads();
+
+    debug::log("Killed process");
+    finish_process(&mut scheduler, process, reason)
+}
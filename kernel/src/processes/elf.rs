@@ -0,0 +1,207 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use x86_64::structures::paging::{PageSize, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::memory_area::MemoryPermissions;
+use super::tls::TlsTemplate;
+
+/// The magic bytes every ELF file starts with.
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+/// `EI_CLASS` value for 64-bit objects.
+const ELF_CLASS_64: u8 = 2;
+/// `EI_DATA` value for little endian objects.
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+/// The only ELF version there is.
+const ELF_VERSION_CURRENT: u32 = 1;
+/// `e_type` value for executable files.
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+/// `e_machine` value for AMD x86-64.
+const ELF_MACHINE_X86_64: u16 = 0x3E;
+
+/// `p_type` value for loadable segments.
+pub const PT_LOAD: u32 = 1;
+/// `p_type` value for the thread-local storage template.
+pub const PT_TLS: u32 = 7;
+/// `p_flags` bit for executable segments.
+pub const PF_X: u32 = 1;
+/// `p_flags` bit for writable segments.
+pub const PF_W: u32 = 2;
+
+/// The ELF64 file header.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ElfHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub program_header_offset: u64,
+    pub section_header_offset: u64,
+    pub flags: u32,
+    pub header_size: u16,
+    pub program_header_entry_size: u16,
+    pub program_header_count: u16,
+    pub section_header_entry_size: u16,
+    pub section_header_count: u16,
+    pub section_names_index: u16,
+}
+
+/// An ELF64 program header, describing one segment of the executable.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub virtual_address: u64,
+    pub physical_address: u64,
+    pub file_size: u64,
+    pub memory_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    /// Returns what the process is allowed to do with the segment.
+    pub fn permissions(&self) -> MemoryPermissions {
+        MemoryPermissions {
+            writable: self.flags & PF_W != 0,
+            executable: self.flags & PF_X != 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfLoadError {
+    /// The file is smaller than the headers it declares.
+    Truncated,
+    /// The file does not start with the ELF magic bytes.
+    InvalidMagic,
+    /// The file is not a little endian ELF64 file of the current version.
+    UnsupportedFormat,
+    /// The file is not an x86-64 executable.
+    UnsupportedType,
+    /// A loadable or the TLS segment is malformed or lies outside of the user address space.
+    InvalidSegment,
+    /// There was not enough memory to map the program.
+    OutOfMemory,
+}
+
+/// A validated ELF64 executable.
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    header: ElfHeader,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Validates the ELF header and the program header table of the file.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfLoadError> {
+        if data.len() < size_of::<ElfHeader>() {
+            return Err(ElfLoadError::Truncated);
+        }
+        let header = unsafe { read_unaligned(data.as_ptr() as *const ElfHeader) };
+
+        if header.ident[0..4] != ELF_MAGIC {
+            return Err(ElfLoadError::InvalidMagic);
+        }
+        if header.ident[4] != ELF_CLASS_64
+            || header.ident[5] != ELF_DATA_LITTLE_ENDIAN
+            || header.ident[6] as u32 != ELF_VERSION_CURRENT
+            || header.version != ELF_VERSION_CURRENT
+        {
+            return Err(ElfLoadError::UnsupportedFormat);
+        }
+        if header.elf_type != ELF_TYPE_EXECUTABLE || header.machine != ELF_MACHINE_X86_64 {
+            return Err(ElfLoadError::UnsupportedType);
+        }
+        if header.program_header_entry_size as usize != size_of::<ProgramHeader>() {
+            return Err(ElfLoadError::UnsupportedFormat);
+        }
+
+        let table_size = header.program_header_count as u64 * size_of::<ProgramHeader>() as u64;
+        match header.program_header_offset.checked_add(table_size) {
+            Some(table_end) if table_end <= data.len() as u64 => {}
+            _ => return Err(ElfLoadError::Truncated),
+        }
+
+        Ok(ElfFile { data, header })
+    }
+
+    /// Returns the address the first thread of the program should start at.
+    pub fn entry_point(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.header.entry)
+    }
+
+    /// Returns all the program headers of the file.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        let table = self.header.program_header_offset as usize;
+        (0..self.header.program_header_count as usize).map(move |index| unsafe {
+            read_unaligned(
+                self.data[table + index * size_of::<ProgramHeader>()..].as_ptr()
+                    as *const ProgramHeader,
+            )
+        })
+    }
+
+    /// Returns the loadable segments of the file, checking that their file contents are in bounds
+    /// and that they fit below `memory_end`.
+    pub fn load_segments(
+        &self,
+        memory_end: u64,
+    ) -> Result<impl Iterator<Item = ProgramHeader> + '_, ElfLoadError> {
+        for segment in self
+            .program_headers()
+            .filter(|header| header.segment_type == PT_LOAD)
+        {
+            let file_end = segment.offset.checked_add(segment.file_size);
+            let memory_segment_end = segment.virtual_address.checked_add(segment.memory_size);
+            match (file_end, memory_segment_end) {
+                (Some(file_end), Some(memory_segment_end))
+                    if segment.file_size <= segment.memory_size
+                        && file_end <= self.data.len() as u64
+                        && memory_segment_end <= memory_end => {}
+                _ => return Err(ElfLoadError::InvalidSegment),
+            }
+        }
+        Ok(self
+            .program_headers()
+            .filter(|header| header.segment_type == PT_LOAD))
+    }
+
+    /// Returns the template of the program's thread-local variables if it has any, checking that
+    /// its initialization image fits below `memory_end`.
+    pub fn tls_template(&self, memory_end: u64) -> Result<Option<TlsTemplate>, ElfLoadError> {
+        let segment = match self
+            .program_headers()
+            .find(|header| header.segment_type == PT_TLS)
+        {
+            Some(segment) => segment,
+            None => return Ok(None),
+        };
+        let align = segment.align.max(1);
+        let image_end = segment.virtual_address.checked_add(segment.file_size);
+        // Blocks are placed at the start of a page, so their alignment can't be any larger
+        let is_valid = segment.file_size <= segment.memory_size
+            && segment.memory_size <= memory_end
+            && image_end.map_or(false, |image_end| image_end <= memory_end)
+            && align.is_power_of_two()
+            && align <= Size4KiB::SIZE;
+        if !is_valid {
+            return Err(ElfLoadError::InvalidSegment);
+        }
+        Ok(Some(TlsTemplate {
+            address: VirtAddr::new(segment.virtual_address),
+            file_size: segment.file_size,
+            memory_size: segment.memory_size,
+            align,
+        }))
+    }
+
+    /// Returns the bytes of the segment that are stored in the file.
+    pub fn segment_data(&self, segment: &ProgramHeader) -> &'a [u8] {
+        let start = segment.offset as usize;
+        &self.data[start..start + segment.file_size as usize]
+    }
+}
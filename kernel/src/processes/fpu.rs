@@ -0,0 +1,112 @@
+use core::arch::asm;
+
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+/// The offset of the x87 control word in the FXSAVE area.
+const CONTROL_WORD_OFFSET: usize = 0;
+/// The offset of MXCSR, the SSE control and status register, in the FXSAVE area.
+const MXCSR_OFFSET: usize = 24;
+/// The offset of the first XMM register in the FXSAVE area, the others follow it.
+const XMM_OFFSET: usize = 160;
+/// The number of XMM registers in 64-bit mode.
+const XMM_COUNT: usize = 16;
+/// The x87 control word after `fninit`, every exception is masked.
+const DEFAULT_CONTROL_WORD: u16 = 0x037F;
+/// MXCSR after a reset, every exception is masked.
+const DEFAULT_MXCSR: u32 = 0x1F80;
+/// The bits of MXCSR every CPU supports, setting others makes `fxrstor` fault. The
+/// denormals-are-zero flag is left out as older CPUs don't have it.
+const MXCSR_MASK: u32 = 0xFFBF;
+
+/// The x87, MMX and SSE registers of a thread, in the layout `fxsave` stores them in.
+///
+/// They are saved when the thread is switched away from and only restored once it uses them
+/// again, as most threads never do.
+#[repr(C, align(16))]
+#[derive(Debug, Clone, Copy)]
+pub struct FpuState([u8; 512]);
+
+impl Default for FpuState {
+    /// The registers a program starts with, like after a reset.
+    fn default() -> Self {
+        let mut state = FpuState([0; 512]);
+        state.0[CONTROL_WORD_OFFSET..CONTROL_WORD_OFFSET + 2]
+            .copy_from_slice(&DEFAULT_CONTROL_WORD.to_ne_bytes());
+        state.set_mxcsr(DEFAULT_MXCSR);
+        state
+    }
+}
+
+impl FpuState {
+    /// Clears the bits a user-mode program could have set that `fxrstor` would fault on.
+    pub fn sanitize(&mut self) {
+        self.set_mxcsr(self.mxcsr() & MXCSR_MASK);
+    }
+
+    /// Returns MXCSR, the SSE control and status register.
+    pub fn mxcsr(&self) -> u32 {
+        let mut mxcsr = [0; 4];
+        mxcsr.copy_from_slice(&self.0[MXCSR_OFFSET..MXCSR_OFFSET + 4]);
+        u32::from_ne_bytes(mxcsr)
+    }
+
+    /// Sets MXCSR, the state has to be sanitized before it's loaded.
+    pub fn set_mxcsr(&mut self, mxcsr: u32) {
+        self.0[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&mxcsr.to_ne_bytes());
+    }
+
+    /// Returns the XMM register with the index, or `None` if there is no such register.
+    pub fn xmm(&self, index: usize) -> Option<u128> {
+        if index >= XMM_COUNT {
+            return None;
+        }
+        let offset = XMM_OFFSET + index * 16;
+        let mut xmm = [0; 16];
+        xmm.copy_from_slice(&self.0[offset..offset + 16]);
+        Some(u128::from_ne_bytes(xmm))
+    }
+
+    /// Stores the registers of the running CPU.
+    ///
+    /// # Safety
+    /// The FPU has to be usable, `CR0.TS` must be clear.
+    pub(crate) unsafe fn save(&mut self) {
+        asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack));
+    }
+
+    /// Loads the registers into the running CPU.
+    ///
+    /// # Safety
+    /// The FPU has to be usable, `CR0.TS` must be clear.
+    pub(crate) unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack));
+    }
+}
+
+/// Lets user mode use the x87 FPU and SSE on the running CPU, with their exceptions reported
+/// natively.
+///
+/// The registers start out unavailable, so the first thread using them raises a device not
+/// available exception, which loads its registers.
+pub(crate) fn enable() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(
+                Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR | Cr0Flags::TASK_SWITCHED,
+            );
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// Makes the next use of the registers on the running CPU raise a device not available
+/// exception.
+pub(crate) fn set_task_switched() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::TASK_SWITCHED)) };
+}
+
+/// Makes the registers usable on the running CPU.
+pub(crate) fn clear_task_switched() {
+    unsafe { asm!("clts", options(nomem, nostack)) };
+}
@@ -0,0 +1,156 @@
+use core::arch::asm;
+
+use alloc::vec::Vec;
+use internal_utils::{constants::KIB, FullFrameAllocator};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB},
+    VirtAddr,
+};
+
+use crate::{
+    init::get_kernel_information,
+    memory::{
+        layout::{KERNEL_STACKS_END, KERNEL_STACKS_START},
+        MEMORY_MAPPER,
+    },
+    smp::{is_kernel_stack_in_use, shoot_down_tlb},
+};
+
+/// The size of the kernel stack of a thread.
+pub const KERNEL_STACK_SIZE: u64 = 64 * KIB;
+/// The size of the memory each stack takes up, there is an unmapped guard page below it.
+const KERNEL_STACK_SLOT_SIZE: u64 = KERNEL_STACK_SIZE + Size4KiB::SIZE;
+
+lazy_static! {
+    static ref KERNEL_STACKS: Mutex<KernelStacks> = Mutex::new(KernelStacks {
+        free_slots: Vec::new(),
+        next_slot: 0,
+        released: Vec::new(),
+    });
+}
+
+/// The bookkeeping of the kernel stack region.
+struct KernelStacks {
+    /// The slots that were freed and can be reused.
+    free_slots: Vec<u64>,
+    /// The first slot that was never used.
+    next_slot: u64,
+    /// The stacks of terminated threads, waiting to be freed.
+    released: Vec<KernelStack>,
+}
+
+/// The stack a thread uses when it enters the kernel through a system call or an exception.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The position of the stack in the kernel stack region.
+    slot: u64,
+}
+
+impl KernelStack {
+    /// Maps a new kernel stack.
+    pub fn allocate() -> Option<KernelStack> {
+        let slot = {
+            let mut stacks = KERNEL_STACKS.lock();
+            match stacks.free_slots.pop() {
+                Some(slot) => slot,
+                None if KERNEL_STACKS_START + (stacks.next_slot + 1) * KERNEL_STACK_SLOT_SIZE
+                    <= KERNEL_STACKS_END =>
+                {
+                    stacks.next_slot += 1;
+                    stacks.next_slot - 1
+                }
+                None => return None,
+            }
+        };
+        let stack = KernelStack { slot };
+
+        let kernel_info = get_kernel_information();
+        let mut allocator = kernel_info.allocator.lock();
+        let mut mapper = MEMORY_MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in stack.pages() {
+            let frame: Option<PhysFrame<Size4KiB>> = allocator.allocate_frame();
+            let mapped = frame.and_then(|frame| {
+                unsafe { mapper.map_to(page, frame, flags, &mut *allocator) }.ok()
+            });
+            match mapped {
+                Some(flush) => flush.flush(),
+                None => {
+                    stack.free(mapper, &mut *allocator);
+                    return None;
+                }
+            }
+        }
+        Some(stack)
+    }
+
+    /// Returns the address right after the stack, where the stack pointer starts.
+    pub fn top(&self) -> VirtAddr {
+        VirtAddr::new(KERNEL_STACKS_START + (self.slot + 1) * KERNEL_STACK_SLOT_SIZE)
+    }
+
+    /// Checks if the address is inside of the stack.
+    fn contains(&self, address: VirtAddr) -> bool {
+        self.top() - KERNEL_STACK_SIZE <= address && address < self.top()
+    }
+
+    /// Returns the pages of the stack, without the guard page.
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        Page::range(
+            Page::containing_address(self.top() - KERNEL_STACK_SIZE),
+            Page::containing_address(self.top()),
+        )
+    }
+
+    /// Unmaps the mapped pages of the stack and makes its slot reusable.
+    fn free(self, mapper: &mut impl Mapper<Size4KiB>, allocator: &mut dyn FullFrameAllocator) {
+        for page in self.pages() {
+            if let Ok((frame, flush)) = mapper.unmap(page) {
+                flush.flush();
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        }
+        KERNEL_STACKS.lock().free_slots.push(self.slot);
+    }
+}
+
+/// Frees the stack of a terminated thread once it's not in use anymore.
+pub fn release_kernel_stack(stack: KernelStack) {
+    KERNEL_STACKS.lock().released.push(stack);
+}
+
+/// Frees the released stacks, except for the ones the CPUs are running on.
+pub fn free_released_kernel_stacks() {
+    let stack_pointer: u64;
+    unsafe {
+        asm!("mov {}, rsp", out(reg) stack_pointer);
+    }
+    let stack_pointer = VirtAddr::new(stack_pointer);
+
+    let (in_use, unused): (Vec<KernelStack>, Vec<KernelStack>) =
+        core::mem::take(&mut KERNEL_STACKS.lock().released)
+            .into_iter()
+            .partition(|stack| {
+                stack.contains(stack_pointer) || is_kernel_stack_in_use(stack.top())
+            });
+    KERNEL_STACKS.lock().released = in_use;
+    if unused.is_empty() {
+        return;
+    }
+
+    {
+        // The frame allocator is locked before the memory mapper, like when mapping memory
+        let kernel_info = get_kernel_information();
+        let mut allocator = kernel_info.allocator.lock();
+        let mut mapper = MEMORY_MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
+        for stack in unused {
+            stack.free(mapper, &mut *allocator);
+        }
+    }
+    // The stacks are mapped in every address space
+    shoot_down_tlb(None);
+}
@@ -0,0 +1,110 @@
+use x86_64::{
+    structures::{idt::PageFaultErrorCode, paging::PageTableFlags},
+    VirtAddr,
+};
+
+/// The access rights of a memory area, every area is readable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoryPermissions {
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl MemoryPermissions {
+    /// Returns the permissions that allow everything that either of them allows.
+    pub fn union(self, other: MemoryPermissions) -> MemoryPermissions {
+        MemoryPermissions {
+            writable: self.writable || other.writable,
+            executable: self.executable || other.executable,
+        }
+    }
+
+    /// Returns the flags a user-mode page with these permissions is mapped with.
+    pub fn page_table_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if self.writable {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !self.executable {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// Where the contents of a memory area come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryBacking {
+    /// Zeroed frames are mapped on first access.
+    Anonymous,
+    /// The area is a segment of the program's executable, mapped when the program was loaded.
+    Image,
+    /// The area is never mapped, so overflowing the stack below it faults.
+    Guard,
+}
+
+/// A reserved region of a process's address space.
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryArea {
+    /// The first address of the area, page aligned.
+    pub start: VirtAddr,
+    /// The size of the area in bytes, page aligned.
+    pub size: u64,
+    /// What the process is allowed to do with the area.
+    pub permissions: MemoryPermissions,
+    /// Where the contents of the area come from.
+    pub backing: MemoryBacking,
+}
+
+impl MemoryArea {
+    pub fn new(
+        start: VirtAddr,
+        size: u64,
+        permissions: MemoryPermissions,
+        backing: MemoryBacking,
+    ) -> Self {
+        MemoryArea {
+            start,
+            size,
+            permissions,
+            backing,
+        }
+    }
+
+    /// Returns the first address after the area.
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    /// Checks if the address is inside of the area.
+    pub fn contains(&self, address: VirtAddr) -> bool {
+        self.start <= address && address < self.end()
+    }
+
+    /// Checks if the area has any addresses in the given range.
+    pub fn overlaps(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        self.start < end && start < self.end()
+    }
+
+    /// Returns the part of the area that is inside of the given range.
+    pub fn intersection(&self, start: VirtAddr, end: VirtAddr) -> Option<MemoryArea> {
+        let start = start.max(self.start);
+        let end = end.min(self.end());
+        (start < end).then(|| MemoryArea {
+            start,
+            size: end - start,
+            ..*self
+        })
+    }
+
+    /// Checks if the access that caused the page fault is allowed in this area.
+    pub fn allows(&self, error_code: PageFaultErrorCode) -> bool {
+        if self.backing == MemoryBacking::Guard {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !self.permissions.writable {
+            return false;
+        }
+        !error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) || self.permissions.executable
+    }
+}
@@ -1,16 +1,47 @@
 use core::sync::atomic::fence;
 
+use internal_utils::constants::MIB;
 use x86_64::{
     structures::paging::{
-        page::AddressNotAligned, PageTable, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+        mapper::MapToError,
+        page::{AddressNotAligned, PageRangeInclusive},
+        Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PageTableIndex,
+        PhysFrame, Size2MiB, Size4KiB, Translate,
     },
-    PhysAddr,
+    PhysAddr, VirtAddr,
 };
 
-use crate::{debug, init::get_kernel_information};
+use crate::{
+    debug,
+    init::get_kernel_information,
+    memory::frame_allocator::{is_frame_shared, release_frame, share_frame},
+    smp::shoot_down_tlb,
+};
+
+/// The end of the memory a user-mode process can use, the kernel stack is mapped right after it.
+pub const USER_SPACE_END: u64 = 0x007F_8000_0000;
+/// The top of the stack of the first thread of a user-mode process.
+pub const USER_STACK_TOP: u64 = USER_SPACE_END;
+/// The size of the stack of the first thread of a user-mode process, mapped on demand.
+pub const USER_STACK_SIZE: u64 = 8 * MIB;
+/// The size of the stacks of the threads a user-mode process spawns, mapped on demand.
+pub const USER_THREAD_STACK_SIZE: u64 = 2 * MIB;
+/// The end of the region memory mapped by a user-mode process is placed in, a guard page below the stack.
+pub const USER_MMAP_END: u64 = USER_STACK_TOP - USER_STACK_SIZE - Size4KiB::SIZE;
+/// Marks a page that shares its frame with another process, it's mapped read-only and gets
+/// copied on the first write.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+/// The flags of the page tables mapping user memory, the pages decide what is allowed.
+const USER_TABLE_FLAGS: PageTableFlags = PageTableFlags::from_bits_truncate(
+    PageTableFlags::PRESENT.bits()
+        | PageTableFlags::WRITABLE.bits()
+        | PageTableFlags::USER_ACCESSIBLE.bits(),
+);
 
 /// Initializes and returns the level-4 page table that maps memory for a user-mode process.
-pub unsafe fn get_user_mode_mapping() -> Option<(PhysFrame, PhysAddr)> {
+///
+/// Only the kernel is mapped, the process's memory has to be mapped with `map_user_memory`.
+pub unsafe fn get_user_mode_mapping() -> Option<PhysFrame> {
     let kernel_info = get_kernel_information();
     let pmo = kernel_info.physical_memory_offset;
     let allocator = kernel_info.allocator;
@@ -20,49 +51,31 @@ pub unsafe fn get_user_mode_mapping() -> Option<(PhysFrame, PhysAddr)> {
     let mut allocator = allocator.lock();
     let level_4_frame: PhysFrame<Size4KiB> = allocator.allocate_frame()?;
     let level_3_frame: PhysFrame<Size4KiB> = allocator.allocate_frame()?;
-    let level_2_frame: PhysFrame<Size4KiB> = allocator.allocate_frame()?;
 
     let page_table_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
     let user_page_table_flags = page_table_flags | PageTableFlags::USER_ACCESSIBLE;
 
     let level_4_table_address = level_4_frame.start_address();
     let level_3_table_address = level_3_frame.start_address();
-    let level_2_table_address = level_2_frame.start_address();
     // Just take the mapping from the bootloader's page tables
     let (level_2_kernel_data_table_address, level_2_kernel_stack_table_address) =
         get_kernel_data_and_stack_level_2_table_addresses(pmo);
 
     let level_4_table = (level_4_table_address.as_u64() + pmo) as *mut PageTable;
     let level_4_table = level_4_table.as_mut().unwrap();
+    level_4_table.zero();
     // Mapping 0x0000_0000_0000 to level 3 table
     level_4_table[0].set_addr(level_3_table_address, user_page_table_flags);
 
     let level_3_table = (level_3_table_address.as_u64() + pmo) as *mut PageTable;
     let level_3_table = level_3_table.as_mut().unwrap();
-    // Mapping 0x0000_0000_0000 to level 2 table
-    level_3_table[0].set_addr(level_2_table_address, user_page_table_flags);
+    level_3_table.zero();
     // Mapping 0x007F_8000_0000 to kernel stack
     level_3_table[510].set_addr(level_2_kernel_stack_table_address, page_table_flags);
     // Mapping 0x007F_C000_0000 to kernel data
     level_3_table[511].set_addr(level_2_kernel_data_table_address, page_table_flags);
 
-    let level_2_table = (level_2_table_address.as_u64() + pmo) as *mut PageTable;
-    // Mapping level 2 entries to 2mb frames
-    let level_2_table = level_2_table.as_mut().unwrap();
-    level_2_table
-        .iter_mut()
-        .take(8) // We're mapping 16mb for now, e.g. 0x0100_0000
-        .for_each(|entry| {
-            let frame: PhysFrame<Size2MiB> = allocator
-                .allocate_frame()
-                .expect("Failed to allocate user process frame");
-            entry.set_addr(
-                frame.start_address(),
-                PageTableFlags::HUGE_PAGE | user_page_table_flags,
-            );
-        });
-
-    Some((level_4_frame, level_2_table[0].addr()))
+    Some(level_4_frame)
 }
 
 unsafe fn get_kernel_data_and_stack_level_2_table_addresses(pmo: u64) -> (PhysAddr, PhysAddr) {
@@ -76,6 +89,340 @@ unsafe fn get_kernel_data_and_stack_level_2_table_addresses(pmo: u64) -> (PhysAd
     (level3[511].addr(), level3[510].addr())
 }
 
+/// Returns a mapper for the page tables of a user-mode process.
+unsafe fn get_user_mapper(level_4_addr: PhysAddr) -> OffsetPageTable<'static> {
+    let pmo = get_kernel_information().physical_memory_offset;
+    let level_4_table = (level_4_addr.as_u64() + pmo) as *mut PageTable;
+    OffsetPageTable::new(level_4_table.as_mut().unwrap(), VirtAddr::new(pmo))
+}
+
+/// Maps zeroed frames to the given memory range of a user-mode process.
+///
+/// Pages in the range that are already mapped keep their frame and get the union of both flags.
+pub unsafe fn map_user_memory(
+    level_4_addr: PhysAddr,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let kernel_info = get_kernel_information();
+    let pmo = kernel_info.physical_memory_offset;
+    let mut allocator = kernel_info.allocator.lock();
+    let mut mapper = get_user_mapper(level_4_addr);
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    for page in get_page_range(start, size) {
+        if let Some(existing_flags) = get_user_page_flags(&mapper, page) {
+            let mut merged_flags = existing_flags | flags;
+            if !existing_flags.contains(PageTableFlags::NO_EXECUTE)
+                || !flags.contains(PageTableFlags::NO_EXECUTE)
+            {
+                merged_flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+            if existing_flags.contains(COPY_ON_WRITE) {
+                merged_flags.insert(PageTableFlags::WRITABLE);
+            }
+            let frame = mapper
+                .translate_page(page)
+                .expect("Failed to translate a mapped page");
+            mapper
+                .update_flags(page, shared_frame_flags(frame, merged_flags))
+                .expect("Failed to update the flags of a mapped page")
+                .ignore();
+            continue;
+        }
+        let frame: PhysFrame<Size4KiB> = allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_pointer = (frame.start_address().as_u64() + pmo) as *mut u8;
+        frame_pointer.write_bytes(0, Size4KiB::SIZE as usize);
+        mapper
+            .map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut *allocator)?
+            .ignore();
+    }
+    Ok(())
+}
+
+/// Removes the mapping of the given memory range of a user-mode process, freeing the frames
+/// no other process shares.
+///
+/// Pages in the range that are not mapped are skipped.
+pub unsafe fn unmap_user_memory(level_4_addr: PhysAddr, start: VirtAddr, size: u64) {
+    let kernel_info = get_kernel_information();
+    let mut allocator = kernel_info.allocator.lock();
+    let mut mapper = get_user_mapper(level_4_addr);
+
+    for page in get_page_range(start, size) {
+        if let Ok((frame, flush)) = mapper.unmap(page) {
+            flush.ignore();
+            if release_frame(frame) {
+                allocator.deallocate_frame(frame);
+            }
+        }
+    }
+    drop(allocator);
+    shoot_down_tlb(Some(level_4_addr));
+}
+
+/// Changes the flags of the mapped pages in the given memory range of a user-mode process.
+///
+/// Writable pages of frames other processes share become copy-on-write pages, which stay
+/// read-only until they are copied.
+pub unsafe fn update_user_memory_flags(
+    level_4_addr: PhysAddr,
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) {
+    let mut mapper = get_user_mapper(level_4_addr);
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+    for page in get_page_range(start, size) {
+        let frame = match mapper.translate_page(page) {
+            Ok(frame) => frame,
+            Err(_) => continue,
+        };
+        mapper
+            .update_flags(page, shared_frame_flags(frame, flags))
+            .expect("Failed to update the flags of a mapped page")
+            .ignore();
+    }
+    shoot_down_tlb(Some(level_4_addr));
+}
+
+/// Gives the page of a user-mode process its own writable frame if it's a copy-on-write page.
+///
+/// The frame is only copied if another process still shares it.
+/// Returns `false` if the page is not a copy-on-write page or there is no memory for the copy.
+pub unsafe fn copy_on_write(level_4_addr: PhysAddr, page: Page) -> bool {
+    let kernel_info = get_kernel_information();
+    let pmo = kernel_info.physical_memory_offset;
+    let mut allocator = kernel_info.allocator.lock();
+    let mut mapper = get_user_mapper(level_4_addr);
+
+    let flags = match get_user_page_flags(&mapper, page) {
+        Some(flags) if flags.contains(COPY_ON_WRITE) => flags,
+        _ => return false,
+    };
+    let flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+    let frame = mapper
+        .translate_page(page)
+        .expect("Failed to translate a mapped page");
+
+    if !is_frame_shared(frame) {
+        // The other processes have already copied or freed the page
+        mapper
+            .update_flags(page, flags)
+            .expect("Failed to update the flags of a mapped page")
+            .ignore();
+        drop(allocator);
+        shoot_down_tlb(Some(level_4_addr));
+        return true;
+    }
+
+    let copy: PhysFrame<Size4KiB> = match allocator.allocate_frame() {
+        Some(copy) => copy,
+        None => return false,
+    };
+    ((copy.start_address().as_u64() + pmo) as *mut u8).copy_from_nonoverlapping(
+        (frame.start_address().as_u64() + pmo) as *const u8,
+        Size4KiB::SIZE as usize,
+    );
+    mapper
+        .unmap(page)
+        .expect("Failed to unmap a mapped page")
+        .1
+        .ignore();
+    mapper
+        .map_to_with_table_flags(page, copy, flags, USER_TABLE_FLAGS, &mut *allocator)
+        .expect("Failed to map the copied page")
+        .ignore();
+    release_frame(frame);
+    drop(allocator);
+    shoot_down_tlb(Some(level_4_addr));
+    true
+}
+
+/// Returns the flags a page of the frame gets instead of the given ones. A frame other processes
+/// share must not be written to, so instead of being writable the page becomes a copy-on-write
+/// page.
+fn shared_frame_flags(frame: PhysFrame<Size4KiB>, flags: PageTableFlags) -> PageTableFlags {
+    let flags = flags - COPY_ON_WRITE;
+    if flags.contains(PageTableFlags::WRITABLE) && is_frame_shared(frame) {
+        (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE
+    } else {
+        flags
+    }
+}
+
+/// Returns the pages that contain the given memory range.
+fn get_page_range(start: VirtAddr, size: u64) -> PageRangeInclusive {
+    Page::range_inclusive(
+        Page::containing_address(start),
+        Page::containing_address(start + size.max(1) - 1u64),
+    )
+}
+
+/// Returns the flags of a mapped page of a user-mode process.
+fn get_user_page_flags(mapper: &OffsetPageTable, page: Page) -> Option<PageTableFlags> {
+    use x86_64::structures::paging::mapper::TranslateResult;
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// Copies the data to the memory of a user-mode process, the memory has to be mapped already.
+///
+/// Copy-on-write pages are copied before they are written to.
+/// Returns `None` if some of the memory is not mapped or outside of the user address space.
+pub unsafe fn write_user_memory(
+    level_4_addr: PhysAddr,
+    address: VirtAddr,
+    data: &[u8],
+) -> Option<()> {
+    if !is_user_memory(address, data.len() as u64) {
+        return None;
+    }
+    let pmo = get_kernel_information().physical_memory_offset;
+    let mapper = get_user_mapper(level_4_addr);
+
+    let mut written = 0usize;
+    while written < data.len() {
+        let current = address + written;
+        let page = Page::containing_address(current);
+        if get_user_page_flags(&mapper, page)?.contains(COPY_ON_WRITE)
+            && !copy_on_write(level_4_addr, page)
+        {
+            return None;
+        }
+        let physical_address = mapper.translate_addr(current)?;
+        // We can only copy until the end of the page as the next one can be anywhere
+        let page_remaining = (Size4KiB::SIZE - current.as_u64() % Size4KiB::SIZE)
+            .min((data.len() - written) as u64) as usize;
+        ((physical_address.as_u64() + pmo) as *mut u8)
+            .copy_from_nonoverlapping(data[written..].as_ptr(), page_remaining);
+        written += page_remaining;
+    }
+    Some(())
+}
+
+/// Copies the memory of a user-mode process to the buffer.
+///
+/// Returns `None` if some of the memory is not mapped or outside of the user address space.
+pub unsafe fn read_user_memory(
+    level_4_addr: PhysAddr,
+    address: VirtAddr,
+    buffer: &mut [u8],
+) -> Option<()> {
+    if !is_user_memory(address, buffer.len() as u64) {
+        return None;
+    }
+    let pmo = get_kernel_information().physical_memory_offset;
+    let mapper = get_user_mapper(level_4_addr);
+
+    let mut read = 0usize;
+    while read < buffer.len() {
+        let current = address + read;
+        let physical_address = mapper.translate_addr(current)?;
+        // We can only copy until the end of the page as the next one can be anywhere
+        let page_remaining = (Size4KiB::SIZE - current.as_u64() % Size4KiB::SIZE)
+            .min((buffer.len() - read) as u64) as usize;
+        buffer[read..].as_mut_ptr().copy_from_nonoverlapping(
+            (physical_address.as_u64() + pmo) as *const u8,
+            page_remaining,
+        );
+        read += page_remaining;
+    }
+    Some(())
+}
+
+/// Checks if the memory range is inside of the user address space.
+fn is_user_memory(address: VirtAddr, size: u64) -> bool {
+    address
+        .as_u64()
+        .checked_add(size)
+        .map_or(false, |end| end <= USER_SPACE_END)
+}
+
+/// Creates the level-4 page table for a copy of a user-mode process.
+///
+/// Every mapped page is shared with the copy, the writable ones become copy-on-write pages in
+/// both processes.
+pub unsafe fn fork_user_mode_mapping(level_4_addr: PhysAddr) -> Option<PhysFrame> {
+    let fork_level_4_frame = get_user_mode_mapping()?;
+    let shared = share_user_memory(level_4_addr, fork_level_4_frame.start_address());
+    // The writable pages of the source became copy-on-write pages
+    shoot_down_tlb(Some(level_4_addr));
+    if shared.is_err() {
+        clear_user_mode_mapping(fork_level_4_frame.start_address())
+            .expect("Failed to clear the user mode mapping");
+        return None;
+    }
+    Some(fork_level_4_frame)
+}
+
+/// Maps every page of the source process to the same frame in the target process.
+unsafe fn share_user_memory(
+    source_level_4_addr: PhysAddr,
+    target_level_4_addr: PhysAddr,
+) -> Result<(), MapToError<Size4KiB>> {
+    let kernel_info = get_kernel_information();
+    let pmo = kernel_info.physical_memory_offset;
+    let mut allocator = kernel_info.allocator.lock();
+    let mut target_mapper = get_user_mapper(target_level_4_addr);
+
+    let level_4_table = (source_level_4_addr.as_u64() + pmo) as *const PageTable;
+    let level_3_addr = level_4_table.as_ref().unwrap()[0].addr();
+    let level_3_table = ((level_3_addr.as_u64() + pmo) as *mut PageTable)
+        .as_mut()
+        .unwrap();
+
+    // The last two level 3 entries map the kernel, the target has them already
+    for (level_3_index, level_3_entry) in level_3_table.iter_mut().enumerate().take(510) {
+        if level_3_entry.is_unused() {
+            continue;
+        }
+        let level_2_table = ((level_3_entry.addr().as_u64() + pmo) as *mut PageTable)
+            .as_mut()
+            .unwrap();
+        for (level_2_index, level_2_entry) in level_2_table.iter_mut().enumerate() {
+            if level_2_entry.is_unused() {
+                continue;
+            }
+            // User memory is only ever mapped with 4K pages
+            if level_2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(MapToError::ParentEntryHugePage);
+            }
+            let level_1_table = ((level_2_entry.addr().as_u64() + pmo) as *mut PageTable)
+                .as_mut()
+                .unwrap();
+            for (level_1_index, entry) in level_1_table.iter_mut().enumerate() {
+                let frame = match entry.frame() {
+                    Ok(frame) => frame,
+                    Err(_) => continue,
+                };
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags = (flags - PageTableFlags::WRITABLE) | COPY_ON_WRITE;
+                    entry.set_flags(flags);
+                }
+                let page = Page::from_page_table_indices(
+                    PageTableIndex::new(0),
+                    PageTableIndex::new(level_3_index as u16),
+                    PageTableIndex::new(level_2_index as u16),
+                    PageTableIndex::new(level_1_index as u16),
+                );
+                target_mapper
+                    .map_to_with_table_flags(page, frame, flags, USER_TABLE_FLAGS, &mut *allocator)?
+                    .ignore();
+                share_frame(frame);
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Clears the memory and page-table mapping for a given level 4 page table (assuming user process).
 pub unsafe fn clear_user_mode_mapping(level_4_addr: PhysAddr) -> Result<(), AddressNotAligned> {
     let kernel_info = get_kernel_information();
@@ -94,28 +441,45 @@ pub unsafe fn clear_user_mode_mapping(level_4_addr: PhysAddr) -> Result<(), Addr
         let level_3_table = (level_3_addr.as_u64() + pmo) as *mut PageTable;
         level_3_table.as_mut().unwrap()
     };
-
-    let level_2_addr = level_3_table[0].addr();
-    let level_2_frame: PhysFrame<Size4KiB> = PhysFrame::from_start_address(level_2_addr)?;
-    let level_2_table = {
-        let level_2_table = (level_2_addr.as_u64() + pmo) as *mut PageTable;
-        level_2_table.as_mut().unwrap()
-    };
     fence(core::sync::atomic::Ordering::SeqCst);
-    // First we go through the memory allocations and free them
-    level_2_table
+
+    // The last two level 3 entries map the kernel, we must not free them
+    for level_3_entry in level_3_table
         .iter_mut()
+        .take(510)
         .filter(|entry| !entry.is_unused())
-        .for_each(|entry| {
-            if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
-                allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(entry.addr()));
-            } else {
-                allocator.deallocate_frame(entry.frame().unwrap());
+    {
+        let level_2_frame: PhysFrame<Size4KiB> =
+            PhysFrame::from_start_address(level_3_entry.addr())?;
+        let level_2_table = {
+            let level_2_table = (level_3_entry.addr().as_u64() + pmo) as *mut PageTable;
+            level_2_table.as_mut().unwrap()
+        };
+        // First we go through the memory allocations and free them
+        for level_2_entry in level_2_table.iter_mut().filter(|entry| !entry.is_unused()) {
+            if level_2_entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                allocator.deallocate_frame(PhysFrame::<Size2MiB>::containing_address(
+                    level_2_entry.addr(),
+                ));
+                continue;
             }
-        });
+            let level_1_frame: PhysFrame<Size4KiB> =
+                PhysFrame::from_start_address(level_2_entry.addr())?;
+            let level_1_table = {
+                let level_1_table = (level_2_entry.addr().as_u64() + pmo) as *mut PageTable;
+                level_1_table.as_mut().unwrap()
+            };
+            level_1_table
+                .iter_mut()
+                .filter_map(|entry| entry.frame().ok())
+                .filter(|frame| release_frame(*frame))
+                .for_each(|frame| allocator.deallocate_frame(frame));
+            allocator.deallocate_frame(level_1_frame);
+        }
+        allocator.deallocate_frame(level_2_frame);
+    }
 
     // Then we free the page tables themselves
-    allocator.deallocate_frame(level_2_frame);
     allocator.deallocate_frame(level_3_frame);
     allocator.deallocate_frame(level_4_frame);
 
@@ -1,19 +1,104 @@
-use core::cell::RefCell;
+use core::cmp::Ordering;
 
-use crate::processes::memory_mapper::get_user_mode_mapping;
-use crate::{debug, init::get_kernel_information};
-use alloc::rc::Rc;
+use crate::debug;
+use crate::memory::get_kernel_memory;
+use crate::processes::memory_mapper::{
+    clear_user_mode_mapping, copy_on_write, fork_user_mode_mapping, get_user_mode_mapping,
+    map_user_memory, read_user_memory, unmap_user_memory, update_user_memory_flags,
+    write_user_memory, USER_MMAP_END, USER_STACK_SIZE, USER_STACK_TOP,
+};
+use crate::sync::IrqMutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec;
 use internal_utils::get_current_tick;
-use x86_64::{PhysAddr, VirtAddr};
+use x86_64::{align_up, PhysAddr, VirtAddr};
 
 use alloc::vec::Vec;
+use x86_64::structures::idt::PageFaultErrorCode;
+use x86_64::structures::paging::{Page, PageSize, Size4KiB};
 
-use super::thread::{Thread, ThreadState};
+use super::dispatcher::release_address_space;
+use super::elf::{ElfFile, ElfLoadError};
+use super::memory_area::{MemoryArea, MemoryBacking, MemoryPermissions};
+use super::signal::{Signal, Signals};
+use super::thread::Thread;
+use super::tls::{TlsBlock, TlsTemplate};
+use super::RegistersState;
+
+/// A CPU exception a user-mode process can be terminated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFault {
+    DivideError,
+    InvalidOpcode,
+    StackSegmentFault,
+    GeneralProtectionFault,
+    PageFault(VirtAddr),
+    X87FloatingPoint,
+    AlignmentCheck,
+    SimdFloatingPoint,
+}
+
+impl CpuFault {
+    /// Returns the interrupt vector of the exception.
+    pub fn vector(&self) -> u64 {
+        match self {
+            CpuFault::DivideError => 0,
+            CpuFault::InvalidOpcode => 6,
+            CpuFault::StackSegmentFault => 12,
+            CpuFault::GeneralProtectionFault => 13,
+            CpuFault::PageFault(_) => 14,
+            CpuFault::X87FloatingPoint => 16,
+            CpuFault::AlignmentCheck => 17,
+            CpuFault::SimdFloatingPoint => 19,
+        }
+    }
+}
+
+/// The bit set in the exit status of processes that were terminated because of a CPU exception,
+/// the lower bits hold the vector of the exception.
+pub const EXIT_STATUS_FAULT: u64 = 1 << 63;
+/// The bit set in the exit status of processes that were terminated by a signal, the lower bits
+/// hold the number of the signal.
+///
+/// Exit codes are reported without these two bits, so the kind of exit can be told from the
+/// status and no status is ever `SYSCALL_ERROR`, which has both of them set.
+pub const EXIT_STATUS_SIGNAL: u64 = 1 << 62;
+
+/// The reason a process stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The last thread of the process exited with the exit code.
+    Exited(u64),
+    /// The process was terminated because it caused a CPU exception.
+    Fault(CpuFault),
+    /// The process was terminated by a signal it didn't handle.
+    Signaled(Signal),
+}
+
+impl ExitReason {
+    /// Returns the exit status waiting threads get.
+    ///
+    /// It's the exit code without its two highest bits, `EXIT_STATUS_FAULT` together with the
+    /// vector of the exception, or `EXIT_STATUS_SIGNAL` together with the number of the signal.
+    pub fn status(&self) -> u64 {
+        match self {
+            ExitReason::Exited(code) => code & !(EXIT_STATUS_FAULT | EXIT_STATUS_SIGNAL),
+            ExitReason::Fault(fault) => EXIT_STATUS_FAULT | fault.vector(),
+            ExitReason::Signaled(signal) => EXIT_STATUS_SIGNAL | *signal as u64,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Process {
     /// The process's ID.
     pub id: u64,
+    /// The ID of the process that forked this one, or of init once that one exited.
+    pub parent_id: Option<u64>,
+    /// Set once init adopted the process, init doesn't know to wait for it so it's reaped as
+    /// soon as it exits.
+    pub adopted: bool,
     /// The page table the process is using.
     pub cr3: PhysAddr,
     /// Total ticks the process has been running for.
@@ -25,11 +110,29 @@ pub struct Process {
     /// Is the process a kernel process (should it run in ring 0 or 3?).
     pub kernel_process: bool,
     /// The threads of the process that have not started yet.
-    pub not_started_threads: Vec<Rc<RefCell<Thread>>>,
+    pub not_started_threads: Vec<Arc<IrqMutex<Thread>>>,
     /// The threads of the process that are eligible to run.
-    pub ready_threads: Vec<Rc<RefCell<Thread>>>,
+    pub ready_threads: Vec<Arc<IrqMutex<Thread>>>,
     /// The threads of the process that are sleeping.
-    pub sleeping_threads: Vec<Rc<RefCell<Thread>>>,
+    pub sleeping_threads: Vec<Arc<IrqMutex<Thread>>>,
+    /// The threads of the process that are waiting for another thread or process to exit.
+    pub waiting_threads: Vec<Arc<IrqMutex<Thread>>>,
+    /// The exit codes of the threads that exited and haven't been joined yet, detached threads
+    /// don't leave one.
+    pub thread_exit_codes: BTreeMap<u64, u64>,
+    /// The reserved memory of the process.
+    pub memory_areas: Vec<MemoryArea>,
+    /// The start of the process's heap, right after the program's segments.
+    pub heap_start: VirtAddr,
+    /// The current end of the process's heap.
+    pub program_break: VirtAddr,
+    /// Why the process stopped running, `None` while it's still alive.
+    pub exit_reason: Option<ExitReason>,
+    /// The signals sent to the process and how it handles them.
+    pub signals: Signals,
+    /// The template the thread-local storage of new threads is initialized from, `None` if the
+    /// program has no thread-local variables.
+    pub tls_template: Option<TlsTemplate>,
 }
 
 impl Process {
@@ -39,69 +142,579 @@ impl Process {
         self.total_ticks * 100 / ticks_maximum
     }
 
-    /// Creates a new process from a function pointer.
+    /// Creates a process that runs in ring 0 with the kernel's paging table.
+    pub fn new_kernel(id: u64) -> Self {
+        Process {
+            id,
+            parent_id: None,
+            adopted: false,
+            cr3: get_kernel_memory(),
+            total_ticks: 0,
+            start_tick: get_current_tick(),
+            last_tick: 0,
+            kernel_process: true,
+            not_started_threads: Vec::new(),
+            ready_threads: Vec::new(),
+            sleeping_threads: Vec::new(),
+            waiting_threads: Vec::new(),
+            thread_exit_codes: BTreeMap::new(),
+            memory_areas: Vec::new(),
+            heap_start: VirtAddr::zero(),
+            program_break: VirtAddr::zero(),
+            exit_reason: None,
+            signals: Signals::default(),
+            tls_template: None,
+        }
+    }
+
+    /// Creates a new process from an ELF64 executable, mapping each of its loadable segments and
+    /// reserving the stack and the thread-local storage of its first thread.
     ///
-    /// # Safety
-    /// This function is unsafe as it copies the first 1024 bytes from the function pointer.
-    // TODO: Loading the process from e.g. an ELF file
-    // We have to look up the structure of an ELF file and prepare the user memory mapping according to it.
-    // Then we can load the program and it's data to proper places and create a process out of it.
-    pub unsafe fn from_extern(function: extern "C" fn(), id: u64) -> Self {
-        let function_pointer = function as *const () as *const u8;
-        let kernel_info = get_kernel_information();
-        unsafe {
-            let (user_page_map, user_physical_address) =
-                get_user_mode_mapping().expect("Error while creating user mode mapping");
-
-            let user_mode_code_address = 0x1000u64;
-
-            let virtual_address = VirtAddr::new(
-                user_physical_address.as_u64()
-                    + user_mode_code_address
-                    + kernel_info.physical_memory_offset,
-            )
-            .as_mut_ptr::<u8>();
-            debug::log("Loading program");
-
-            virtual_address.copy_from_nonoverlapping(function_pointer, 1024);
-
-            Process {
-                id,
-                cr3: user_page_map.start_address(),
-                total_ticks: 0,
-                start_tick: get_current_tick(),
-                last_tick: 0,
-                kernel_process: false,
-                not_started_threads: Vec::new(),
-                ready_threads: Vec::new(),
-                sleeping_threads: Vec::new(),
+    /// Returns the process together with the entry point of the program and the thread-local
+    /// storage of the first thread, if the program has thread-local variables.
+    pub fn from_elf(
+        elf: &[u8],
+        id: u64,
+    ) -> Result<(Self, VirtAddr, Option<TlsBlock>), ElfLoadError> {
+        let elf = ElfFile::parse(elf)?;
+        // Validating everything before allocating anything, so we don't have to clean up
+        let segments = elf.load_segments(USER_MMAP_END)?;
+        let tls_template = elf.tls_template(USER_MMAP_END)?;
+
+        let user_page_map = unsafe { get_user_mode_mapping() }.ok_or(ElfLoadError::OutOfMemory)?;
+        let mut process = Process {
+            id,
+            parent_id: None,
+            adopted: false,
+            cr3: user_page_map.start_address(),
+            total_ticks: 0,
+            start_tick: get_current_tick(),
+            last_tick: 0,
+            kernel_process: false,
+            not_started_threads: Vec::new(),
+            ready_threads: Vec::new(),
+            sleeping_threads: Vec::new(),
+            waiting_threads: Vec::new(),
+            thread_exit_codes: BTreeMap::new(),
+            memory_areas: vec![MemoryArea::new(
+                VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+                USER_STACK_SIZE,
+                MemoryPermissions {
+                    writable: true,
+                    executable: false,
+                },
+                MemoryBacking::Anonymous,
+            )],
+            heap_start: VirtAddr::zero(),
+            program_break: VirtAddr::zero(),
+            exit_reason: None,
+            signals: Signals::default(),
+            tls_template,
+        };
+
+        debug::log("Loading program");
+        let cr3 = process.cr3;
+        for segment in segments {
+            let start = VirtAddr::new(segment.virtual_address);
+            let end = start + segment.memory_size;
+            let loaded = unsafe {
+                // The pages are zeroed on allocation, so whatever is not in the file (.bss) stays zero.
+                map_user_memory(
+                    cr3,
+                    start,
+                    segment.memory_size,
+                    segment.permissions().page_table_flags(),
+                )
+                .ok()
+                .and_then(|_| write_user_memory(cr3, start, elf.segment_data(&segment)))
+            };
+            if loaded.is_none() {
+                unsafe {
+                    clear_user_mode_mapping(cr3).expect("Failed to clear the user mode mapping");
+                }
+                return Err(ElfLoadError::OutOfMemory);
             }
+            process.add_image_area(start, end, segment.permissions());
+            process.heap_start = process.heap_start.max(end.align_up(Size4KiB::SIZE));
         }
-    }
+        process.program_break = process.heap_start;
 
-    /// Updates the sleeping threads, waking them up if they are sleeping for too long.
-    pub fn update_sleeping_threads(this: Rc<RefCell<Process>>) {
-        let mut process = this.borrow_mut();
-        if process.sleeping_threads.is_empty() {
-            return;
-        }
-        let mut drained = Vec::new();
-        process.sleeping_threads.retain(|thread| {
-            let mut borrowed_thread = thread.borrow_mut();
-            match borrowed_thread.state {
-                ThreadState::Sleeping(ref mut sleep_ticks) => {
-                    if *sleep_ticks > 0 {
-                        *sleep_ticks -= 1;
-                        true
-                    } else {
-                        borrowed_thread.state = ThreadState::Ready;
-                        drained.push(thread.clone());
-                        false
+        // The template is in the loaded segments, so this can only run out of memory
+        let tls = match tls_template {
+            Some(template) => match process.map_tls(template) {
+                Some(tls) => Some(tls),
+                None => {
+                    unsafe {
+                        clear_user_mode_mapping(cr3)
+                            .expect("Failed to clear the user mode mapping");
                     }
+                    return Err(ElfLoadError::OutOfMemory);
                 }
-                _ => unreachable!(),
+            },
+            None => None,
+        };
+
+        Ok((process, elf.entry_point(), tls))
+    }
+
+    /// Replaces the program of the process with an ELF64 executable, freeing the memory of the
+    /// current one. The current program is kept if the executable can't be loaded.
+    ///
+    /// The arguments and environment variables are placed on the new stack.
+    /// Returns the registers a thread has to start with to run the new program, and the
+    /// thread-local storage it uses.
+    pub fn exec(
+        &mut self,
+        elf: &[u8],
+        arguments: &[Vec<u8>],
+        environment: &[Vec<u8>],
+    ) -> Result<(RegistersState, Option<TlsBlock>), ElfLoadError> {
+        let (image, entry_point, tls) = Process::from_elf(elf, self.id)?;
+        let stack_pointer = match image.push_arguments(arguments, environment) {
+            Some(stack_pointer) => stack_pointer,
+            None => {
+                unsafe {
+                    clear_user_mode_mapping(image.cr3)
+                        .expect("Failed to clear the user mode mapping");
+                }
+                return Err(ElfLoadError::OutOfMemory);
+            }
+        };
+
+        // Other threads of the process can still be running on other CPUs
+        release_address_space(self.cr3).expect("Failed to clear the user mode mapping");
+        self.cr3 = image.cr3;
+        self.memory_areas = image.memory_areas;
+        self.heap_start = image.heap_start;
+        self.program_break = image.program_break;
+        self.tls_template = image.tls_template;
+        self.signals.reset_handlers();
+
+        let mut registers_state = RegistersState::new(entry_point, 0x200, stack_pointer);
+        registers_state.rdi = arguments.len() as u64;
+        registers_state.rsi = (stack_pointer + 8u64).as_u64();
+        registers_state.rdx = (stack_pointer + (arguments.len() as u64 + 2) * 8).as_u64();
+        Ok((registers_state, tls))
+    }
+
+    /// Writes the arguments and environment variables to the top of the stack.
+    ///
+    /// The stack pointer points to the argument count, followed by the null-terminated arrays of
+    /// the arguments and the environment variables, the strings are after them.
+    fn push_arguments(&self, arguments: &[Vec<u8>], environment: &[Vec<u8>]) -> Option<VirtAddr> {
+        let pointers_size = (arguments.len() + environment.len() + 3) * 8;
+        let strings_size = arguments
+            .iter()
+            .chain(environment)
+            .map(|string| string.len() + 1)
+            .sum::<usize>();
+        let size = align_up((pointers_size + strings_size) as u64, 16);
+        if size > USER_STACK_SIZE {
+            return None;
+        }
+        let stack_pointer = VirtAddr::new(USER_STACK_TOP - size);
+        let strings_start = stack_pointer + pointers_size;
+
+        let mut pointers = Vec::with_capacity(pointers_size);
+        let mut strings = Vec::with_capacity(strings_size);
+        pointers.extend_from_slice(&(arguments.len() as u64).to_ne_bytes());
+        for list in [arguments, environment] {
+            for string in list {
+                let address = strings_start + strings.len();
+                pointers.extend_from_slice(&address.as_u64().to_ne_bytes());
+                strings.extend_from_slice(string);
+                strings.push(0);
             }
+            pointers.extend_from_slice(&0u64.to_ne_bytes());
+        }
+
+        let flags = MemoryPermissions {
+            writable: true,
+            executable: false,
+        }
+        .page_table_flags();
+        unsafe {
+            map_user_memory(self.cr3, stack_pointer, size, flags).ok()?;
+            write_user_memory(self.cr3, stack_pointer, &pointers)?;
+            write_user_memory(self.cr3, strings_start, &strings)?;
+        }
+        Some(stack_pointer)
+    }
+
+    /// Copies memory of the process, all of it has to be mapped.
+    pub fn read_memory(&self, address: VirtAddr, size: u64) -> Option<Vec<u8>> {
+        let mut buffer = vec![0; size as usize];
+        unsafe { read_user_memory(self.cr3, address, &mut buffer) }?;
+        Some(buffer)
+    }
+
+    /// Copies the data to the memory of the process, mapping the pages that weren't accessed yet.
+    ///
+    /// Returns `None` if some of the memory isn't in a writable memory area.
+    pub fn write_memory(&self, address: VirtAddr, data: &[u8]) -> Option<()> {
+        let end = address.as_u64().checked_add(data.len() as u64)?;
+        let mut current = address.align_down(Size4KiB::SIZE);
+        while current.as_u64() < end {
+            let area = self
+                .memory_areas
+                .iter()
+                .find(|area| area.contains(current) && area.permissions.writable)?;
+            // Pages that are mapped already keep their frames
+            let flags = area.permissions.page_table_flags();
+            unsafe { map_user_memory(self.cr3, current, Size4KiB::SIZE, flags) }.ok()?;
+            current += Size4KiB::SIZE;
+        }
+        unsafe { write_user_memory(self.cr3, address, data) }
+    }
+
+    /// Copies a null-terminated string from the memory of the process, without the terminator.
+    ///
+    /// Returns `None` if the string is longer than the maximum size.
+    pub fn read_string(&self, address: VirtAddr, max_size: u64) -> Option<Vec<u8>> {
+        let mut string = Vec::new();
+        let mut current = address;
+        loop {
+            // Reading page by page, as the string can end right before an unmapped page
+            let chunk_size = Size4KiB::SIZE - current.as_u64() % Size4KiB::SIZE;
+            let chunk = self.read_memory(current, chunk_size)?;
+            if let Some(length) = chunk.iter().position(|byte| *byte == 0) {
+                string.extend_from_slice(&chunk[..length]);
+                return (string.len() as u64 <= max_size).then(|| string);
+            }
+            string.extend_from_slice(&chunk);
+            if string.len() as u64 > max_size {
+                return None;
+            }
+            current += chunk_size;
+        }
+    }
+
+    /// Creates a copy of the process with the given ID and without threads.
+    ///
+    /// The memory is shared until either of the processes writes to it.
+    pub fn fork(&self, id: u64) -> Option<Process> {
+        if self.kernel_process {
+            return None;
+        }
+        let user_page_map = unsafe { fork_user_mode_mapping(self.cr3) }?;
+        Some(Process {
+            id,
+            parent_id: Some(self.id),
+            adopted: false,
+            cr3: user_page_map.start_address(),
+            total_ticks: 0,
+            start_tick: get_current_tick(),
+            last_tick: 0,
+            kernel_process: false,
+            not_started_threads: Vec::new(),
+            ready_threads: Vec::new(),
+            sleeping_threads: Vec::new(),
+            waiting_threads: Vec::new(),
+            thread_exit_codes: BTreeMap::new(),
+            memory_areas: self.memory_areas.clone(),
+            heap_start: self.heap_start,
+            program_break: self.program_break,
+            exit_reason: None,
+            signals: self.signals.fork(),
+            tls_template: self.tls_template,
+        })
+    }
+
+    /// Registers a segment of the executable as a memory area, merging it with the segments
+    /// it shares pages with.
+    fn add_image_area(&mut self, start: VirtAddr, end: VirtAddr, permissions: MemoryPermissions) {
+        let start = start.align_down(Size4KiB::SIZE);
+        let end = end.align_up(Size4KiB::SIZE);
+        let permissions = self
+            .remove_memory_areas(start, end)
+            .iter()
+            .fold(permissions, |permissions, area| {
+                permissions.union(area.permissions)
+            });
+        self.memory_areas.push(MemoryArea::new(
+            start,
+            end - start,
+            permissions,
+            MemoryBacking::Image,
+        ));
+    }
+
+    /// Maps a zeroed frame to the page containing the address, if it's in one of the process's
+    /// memory areas and the access is allowed there. Writes to copy-on-write pages get a copy
+    /// of the frame.
+    ///
+    /// Returns `false` if the page fault can't be resolved.
+    pub fn handle_page_fault(&self, address: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+        let area = match self.memory_areas.iter().find(|area| area.contains(address)) {
+            Some(area) if area.allows(error_code) => area,
+            _ => return false,
+        };
+        let page = Page::<Size4KiB>::containing_address(address);
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+                && unsafe { copy_on_write(self.cr3, page) };
+        }
+        let flags = area.permissions.page_table_flags();
+        unsafe { map_user_memory(self.cr3, page.start_address(), page.size(), flags) }.is_ok()
+    }
+
+    /// Checks if no memory area of the process has addresses in the given range.
+    fn is_memory_free(&self, start: VirtAddr, end: VirtAddr) -> bool {
+        !self
+            .memory_areas
+            .iter()
+            .any(|area| area.overlaps(start, end))
+    }
+
+    /// Finds the highest free range of the given size in the memory map region.
+    fn find_free_memory(&self, size: u64) -> Option<VirtAddr> {
+        if size > USER_MMAP_END {
+            return None;
+        }
+        let mut areas: Vec<&MemoryArea> = self.memory_areas.iter().collect();
+        areas.sort_unstable_by_key(|area| core::cmp::Reverse(area.start));
+
+        let lowest_address = self.program_break.align_up(Size4KiB::SIZE).as_u64();
+        let mut end = USER_MMAP_END;
+        for area in areas {
+            if area.start.as_u64() >= end {
+                continue;
+            }
+            if area.end().as_u64() + size <= end {
+                break;
+            }
+            end = area.start.as_u64();
+        }
+        let start = end.checked_sub(size)?;
+        (start >= lowest_address).then(|| VirtAddr::new(start))
+    }
+
+    /// Removes the given range from the process's memory areas, splitting the ones that are
+    /// partially in it.
+    ///
+    /// Returns the removed parts of the areas.
+    fn remove_memory_areas(&mut self, start: VirtAddr, end: VirtAddr) -> Vec<MemoryArea> {
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for area in self.memory_areas.drain(..) {
+            if !area.overlaps(start, end) {
+                kept.push(area);
+                continue;
+            }
+            if let Some(before) = area.intersection(area.start, start) {
+                kept.push(before);
+            }
+            if let Some(after) = area.intersection(end, area.end()) {
+                kept.push(after);
+            }
+            removed.extend(area.intersection(start, end));
+        }
+        self.memory_areas = kept;
+        removed
+    }
+
+    /// Reserves a new anonymous memory area of the given size, mapped on first access.
+    ///
+    /// The area is placed at the hint if that range is free, anywhere else otherwise.
+    pub fn map_memory(
+        &mut self,
+        hint: Option<VirtAddr>,
+        size: u64,
+        permissions: MemoryPermissions,
+    ) -> Option<VirtAddr> {
+        if size == 0 || size > USER_MMAP_END {
+            return None;
+        }
+        let size = align_up(size, Size4KiB::SIZE);
+        let start = hint
+            .map(|hint| hint.align_down(Size4KiB::SIZE))
+            .filter(|hint| {
+                let end = hint.as_u64().checked_add(size);
+                hint.as_u64() >= self.program_break.align_up(Size4KiB::SIZE).as_u64()
+                    && end.map_or(false, |end| end <= USER_MMAP_END)
+                    && self.is_memory_free(*hint, *hint + size)
+            })
+            .or_else(|| self.find_free_memory(size))?;
+
+        self.memory_areas.push(MemoryArea::new(
+            start,
+            size,
+            permissions,
+            MemoryBacking::Anonymous,
+        ));
+        Some(start)
+    }
+
+    /// Reserves a stack of the given size for a new thread, with a guard page below it.
+    /// The stack is mapped on first access.
+    ///
+    /// Returns the start and the size of the reserved memory, including the guard page.
+    pub fn map_stack(&mut self, size: u64) -> Option<(VirtAddr, u64)> {
+        let size = align_up(size, Size4KiB::SIZE);
+        let start = self.find_free_memory(size + Size4KiB::SIZE)?;
+        self.memory_areas.push(MemoryArea::new(
+            start,
+            Size4KiB::SIZE,
+            MemoryPermissions::default(),
+            MemoryBacking::Guard,
+        ));
+        self.memory_areas.push(MemoryArea::new(
+            start + Size4KiB::SIZE,
+            size,
+            MemoryPermissions {
+                writable: true,
+                executable: false,
+            },
+            MemoryBacking::Anonymous,
+        ));
+        Some((start, size + Size4KiB::SIZE))
+    }
+
+    /// Reserves the thread-local storage of a new thread, copying the template into it. The
+    /// thread pointer after the thread-local variables points to itself.
+    ///
+    /// Returns `None` if the template isn't mapped or there's no memory left.
+    pub fn map_tls(&mut self, template: TlsTemplate) -> Option<TlsBlock> {
+        let image = self.read_memory(template.address, template.file_size)?;
+        let size = template.size();
+        let start = self.map_memory(
+            None,
+            size,
+            MemoryPermissions {
+                writable: true,
+                executable: false,
+            },
+        )?;
+        let thread_pointer = template.thread_pointer(start);
+        // The rest of the variables are zero in the fresh memory
+        let written = self.write_memory(start, &image).and_then(|_| {
+            self.write_memory(thread_pointer, &thread_pointer.as_u64().to_ne_bytes())
         });
-        process.ready_threads.extend(drained);
+        if written.is_none() {
+            self.unmap_memory(start, size);
+            return None;
+        }
+        Some(TlsBlock {
+            start,
+            size,
+            thread_pointer,
+        })
+    }
+
+    /// Removes the given memory range from the process and frees its frames.
+    pub fn unmap_memory(&mut self, start: VirtAddr, size: u64) -> Option<()> {
+        if !start.is_aligned(Size4KiB::SIZE) || size == 0 {
+            return None;
+        }
+        let end = page_range_end(start, size)?;
+        for area in self.remove_memory_areas(start, end) {
+            unsafe { unmap_user_memory(self.cr3, area.start, area.size) };
+        }
+        Some(())
+    }
+
+    /// Changes the permissions of the given memory range, all of it has to be reserved.
+    pub fn protect_memory(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        permissions: MemoryPermissions,
+    ) -> Option<()> {
+        if !start.is_aligned(Size4KiB::SIZE) || size == 0 {
+            return None;
+        }
+        let end = page_range_end(start, size)?;
+        let mut areas = self.remove_memory_areas(start, end);
+        let reserved_size = areas.iter().map(|area| area.size).sum::<u64>();
+        if reserved_size != end - start {
+            self.memory_areas.append(&mut areas);
+            return None;
+        }
+        for area in areas.iter_mut() {
+            area.permissions = permissions;
+            unsafe {
+                update_user_memory_flags(
+                    self.cr3,
+                    area.start,
+                    area.size,
+                    permissions.page_table_flags(),
+                );
+            }
+        }
+        self.memory_areas.append(&mut areas);
+        Some(())
+    }
+
+    /// Moves the end of the process's heap, returning the new end.
+    ///
+    /// If the heap can't be moved there, the current end is returned.
+    pub fn set_program_break(&mut self, program_break: VirtAddr) -> VirtAddr {
+        if program_break < self.heap_start || program_break.as_u64() > USER_MMAP_END {
+            return self.program_break;
+        }
+        let current_end = self.program_break.align_up(Size4KiB::SIZE);
+        let new_end = program_break.align_up(Size4KiB::SIZE);
+        match new_end.cmp(&current_end) {
+            Ordering::Greater => {
+                if new_end.as_u64() > USER_MMAP_END || !self.is_memory_free(current_end, new_end) {
+                    return self.program_break;
+                }
+                self.memory_areas.push(MemoryArea::new(
+                    current_end,
+                    new_end - current_end,
+                    MemoryPermissions {
+                        writable: true,
+                        executable: false,
+                    },
+                    MemoryBacking::Anonymous,
+                ));
+            }
+            Ordering::Less => {
+                for area in self.remove_memory_areas(new_end, current_end) {
+                    unsafe { unmap_user_memory(self.cr3, area.start, area.size) };
+                }
+            }
+            Ordering::Equal => {}
+        }
+        self.program_break = program_break;
+        program_break
+    }
+
+    /// Checks if the process has no threads left.
+    pub fn has_no_threads(&self) -> bool {
+        [
+            &self.not_started_threads,
+            &self.ready_threads,
+            &self.sleeping_threads,
+            &self.waiting_threads,
+        ]
+        .into_iter()
+        .all(|threads| threads.is_empty())
     }
+
+    /// Returns the thread of the process with the given ID, if it's still running.
+    pub fn find_thread(&self, id: u64) -> Option<Arc<IrqMutex<Thread>>> {
+        [
+            &self.not_started_threads,
+            &self.ready_threads,
+            &self.sleeping_threads,
+            &self.waiting_threads,
+        ]
+        .into_iter()
+        .flatten()
+        .find(|thread| thread.lock().id == id)
+        .cloned()
+    }
+}
+
+/// Returns the end of the last page the range is in, or `None` if the range doesn't fit in the
+/// address space.
+fn page_range_end(start: VirtAddr, size: u64) -> Option<VirtAddr> {
+    let end = start
+        .as_u64()
+        .checked_add(size)?
+        .checked_add(Size4KiB::SIZE - 1)?;
+    VirtAddr::try_new(end)
+        .ok()
+        .map(|end| end.align_down(Size4KiB::SIZE))
 }
@@ -0,0 +1,96 @@
+use alloc::{collections::BTreeMap, sync::Arc};
+
+use super::process::Process;
+use crate::sync::IrqMutex;
+
+/// The ID of the first process, the processes whose parent exited are adopted by it.
+pub const INIT_PROCESS_ID: u64 = 1;
+/// The ID of the processes the idle threads run in, they aren't in the process table.
+pub const IDLE_PROCESS_ID: u64 = 0;
+
+/// The processes by their IDs, it gives out the IDs of new processes and threads.
+///
+/// IDs are never reused, so a process or thread can't be mistaken for one that exited.
+#[derive(Default)]
+pub struct ProcessTable {
+    /// The processes that are running.
+    processes: BTreeMap<u64, Arc<IrqMutex<Process>>>,
+    /// The processes that exited, kept until their parent waits for them.
+    zombies: BTreeMap<u64, Arc<IrqMutex<Process>>>,
+    /// The ID the last process got.
+    last_process_id: u64,
+    /// The ID the last thread got.
+    last_thread_id: u64,
+}
+
+impl ProcessTable {
+    /// Returns an ID that no process had before, the first one is `INIT_PROCESS_ID`.
+    pub fn allocate_process_id(&mut self) -> u64 {
+        self.last_process_id += 1;
+        self.last_process_id
+    }
+
+    /// Returns an ID that no thread had before.
+    pub fn allocate_thread_id(&mut self) -> u64 {
+        self.last_thread_id += 1;
+        self.last_thread_id
+    }
+
+    /// Adds the process with an ID from `allocate_process_id` to the table.
+    pub fn insert(&mut self, process: Process) -> Arc<IrqMutex<Process>> {
+        let id = process.id;
+        let arc = Arc::new(IrqMutex::new(process));
+        self.processes.insert(id, arc.clone());
+        arc
+    }
+
+    /// Returns the running process with the ID.
+    pub fn get(&self, id: u64) -> Option<Arc<IrqMutex<Process>>> {
+        self.processes.get(&id).cloned()
+    }
+
+    /// Returns the running processes, ordered by their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<IrqMutex<Process>>> {
+        self.processes.values()
+    }
+
+    /// Removes the exited process with the ID, its children are adopted by init.
+    ///
+    /// Init doesn't know about the children it adopts, so they are reaped instead of becoming
+    /// its zombies. Its exited children are dropped right away.
+    pub fn remove(&mut self, id: u64) -> Option<Arc<IrqMutex<Process>>> {
+        let process = self.processes.remove(&id)?;
+        let adoptive_parent_id = (id != INIT_PROCESS_ID).then(|| INIT_PROCESS_ID);
+        for orphan in self.processes.values() {
+            let mut orphan = orphan.lock();
+            if orphan.parent_id == Some(id) {
+                orphan.parent_id = adoptive_parent_id;
+                orphan.adopted = true;
+            }
+        }
+        self.zombies
+            .retain(|_, zombie| zombie.lock().parent_id != Some(id));
+        Some(process)
+    }
+
+    /// Keeps the exited process until its parent waits for it, processes init adopted are
+    /// dropped instead.
+    pub fn add_zombie(&mut self, process: Arc<IrqMutex<Process>>) {
+        let (id, adopted) = {
+            let process = process.lock();
+            (process.id, process.adopted)
+        };
+        if !adopted {
+            self.zombies.insert(id, process);
+        }
+    }
+
+    /// Removes the exited child with the ID of the parent with the given ID.
+    pub fn take_zombie(&mut self, id: u64, parent_id: u64) -> Option<Arc<IrqMutex<Process>>> {
+        let is_child = self.zombies.get(&id)?.lock().parent_id == Some(parent_id);
+        if !is_child {
+            return None;
+        }
+        self.zombies.remove(&id)
+    }
+}
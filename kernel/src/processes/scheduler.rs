@@ -1,100 +1,693 @@
-use core::cell::RefCell;
+use core::cmp::Ordering;
+use core::sync::atomic::{self, AtomicU64};
 
-use alloc::{collections::VecDeque, rc::Rc};
+use alloc::{
+    collections::BinaryHeap,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use lazy_static::lazy_static;
+use x86_64::VirtAddr;
 
-use super::{process::Process, thread::Thread, RegistersState};
+use super::{
+    elf::ElfLoadError,
+    fpu::{self, FpuState},
+    memory_mapper::{clear_user_mode_mapping, USER_STACK_TOP, USER_THREAD_STACK_SIZE},
+    process::Process,
+    process_table::{ProcessTable, IDLE_PROCESS_ID},
+    signal::has_deliverable_signal,
+    thread::{Thread, ThreadState},
+    RegistersState,
+};
+use crate::hlt_loop;
 use crate::processes::dispatcher::switch_to_thread;
+use crate::smp::{cpu_count, current_cpu_index, send_reschedule};
+use crate::sync::{IrqMutex, IrqMutexGuard};
+use crate::time::{timer_period_nanos, uptime_millis};
 
-static mut SCHEDULER: Option<Scheduler> = None;
+/// The milliseconds after which every thread is moved back to the level of its priority.
+const PRIORITY_BOOST_INTERVAL_MILLIS: u64 = 1000;
+/// The time slice of the threads on the first level the kernel starts with, in milliseconds.
+pub const DEFAULT_QUANTUM_MILLIS: u64 = 10;
 
-pub fn get_scheduler() -> &'static mut Scheduler {
-    unsafe { SCHEDULER.get_or_insert_default() }
+/// The time slice of the threads on the first level in nanoseconds, it doubles on each level.
+static QUANTUM_NANOS: AtomicU64 = AtomicU64::new(DEFAULT_QUANTUM_MILLIS * 1_000_000);
+
+lazy_static! {
+    static ref SCHEDULER: IrqMutex<Scheduler> = IrqMutex::new(Scheduler::default());
+}
+
+/// Locks the scheduler, it's unlocked once the guard is dropped.
+///
+/// The locks of the process subsystem are taken in this order, and none is taken again while
+/// it's held:
+/// 1. the kernel lock
+/// 2. the scheduler
+/// 3. a process
+/// 4. one thread
+///
+/// So the scheduler can't be locked while a process or thread is, functions that need it in
+/// between take it as a parameter. Nothing may be locked when switching to another thread.
+///
+/// Memory is mapped while any of them is held, so the frame allocator and then the memory mapper
+/// are taken after them, the memory mapper is never held while locking the frame allocator.
+pub fn get_scheduler() -> IrqMutexGuard<'static, Scheduler> {
+    SCHEDULER.lock()
 }
 
 /// Runs the scheduler, giving it control of the CPU.
 ///
-/// Will return only if there are no threads at all to run.
-pub fn run_processes() -> Option<()> {
-    switch_to_thread(get_scheduler().schedule()?);
+/// The idle thread runs whenever no other thread is ready. The application processors start
+/// running threads once the kernel lock is released.
+pub fn run_processes() -> ! {
+    run_next_thread();
+}
+
+/// Adds the process to the process table, its ID has to be allocated by the table.
+pub fn add_process(process: Process) -> Arc<IrqMutex<Process>> {
+    get_scheduler().process_table_mut().insert(process)
+}
+
+/// Loads an ELF64 executable as a new process without a parent and readies its first thread at
+/// the entry point. The first process becomes init.
+pub fn add_elf_process(elf: &[u8]) -> Result<Arc<IrqMutex<Thread>>, ElfLoadError> {
+    let id = get_scheduler().process_table_mut().allocate_process_id();
+    let (process, entry_point, tls) = Process::from_elf(elf, id)?;
+    let mut scheduler = get_scheduler();
+    let process_table = scheduler.process_table_mut();
+    let process = process_table.insert(process);
+    let thread_id = process_table.allocate_thread_id();
+    let thread = unsafe {
+        Thread::new_native(
+            thread_id,
+            entry_point.as_u64(),
+            USER_STACK_TOP,
+            process.clone(),
+        )
+    };
+    let thread = match thread {
+        Some(thread) => thread,
+        None => {
+            process_table.remove(id);
+            unsafe { clear_user_mode_mapping(process.lock().cr3) }
+                .expect("Failed to clear the user mode mapping");
+            return Err(ElfLoadError::OutOfMemory);
+        }
+    };
+    thread.lock().set_tls(tls);
+    Thread::change_state(&mut scheduler, thread.clone(), ThreadState::Ready);
+    Ok(thread)
+}
+
+/// Creates a copy of the thread's process, with a copy of the thread as its only thread.
+///
+/// The copied thread continues from the thread's saved registers, with 0 in RAX.
+pub fn fork_process(thread: Arc<IrqMutex<Thread>>) -> Option<Arc<IrqMutex<Process>>> {
+    let mut scheduler = get_scheduler();
+    // The copy gets the FPU registers the thread has loaded
+    scheduler.save_fpu_state();
+    let id = scheduler.process_table_mut().allocate_process_id();
+    let (parent, mut registers_state, fpu_state, fs_base, stack, tls, priority) = {
+        let thread = thread.lock();
+        let parent = thread.process.clone();
+        (
+            parent,
+            thread.registers_state,
+            thread.fpu_state,
+            thread.fs_base,
+            thread.stack,
+            thread.tls,
+            thread.priority,
+        )
+    };
+    let process = parent.lock().fork(id)?;
+    let process_table = scheduler.process_table_mut();
+    let process = process_table.insert(process);
+
+    registers_state.rax = 0;
+    let forked_thread = unsafe {
+        Thread::new_native(
+            process_table.allocate_thread_id(),
+            registers_state.rip.as_u64(),
+            registers_state.rsp.as_u64(),
+            process.clone(),
+        )
+    };
+    let forked_thread = match forked_thread {
+        Some(forked_thread) => forked_thread,
+        None => {
+            process_table.remove(id);
+            unsafe { clear_user_mode_mapping(process.lock().cr3) }
+                .expect("Failed to clear the user mode mapping");
+            return None;
+        }
+    };
+    {
+        let mut forked_thread = forked_thread.lock();
+        forked_thread.registers_state = registers_state;
+        forked_thread.fpu_state = fpu_state;
+        forked_thread.fs_base = fs_base;
+        forked_thread.stack = stack;
+        forked_thread.tls = tls;
+        forked_thread.set_priority(priority);
+    }
+    Thread::change_state(&mut scheduler, forked_thread, ThreadState::Ready);
+    Some(process)
+}
+
+/// Creates a new thread in the process with its own stack, it starts at the entry point with the
+/// arguments in RDI and RSI.
+///
+/// The entry point is called with a null return address, so it must not return.
+pub fn spawn_thread(
+    process: Arc<IrqMutex<Process>>,
+    entry_point: VirtAddr,
+    arguments: [u64; 2],
+) -> Option<Arc<IrqMutex<Thread>>> {
+    let mut scheduler = get_scheduler();
+    if process.lock().kernel_process {
+        return None;
+    }
+    let (stack_start, stack_size) = process.lock().map_stack(USER_THREAD_STACK_SIZE)?;
+    let tls_template = process.lock().tls_template;
+    let tls = match tls_template {
+        Some(template) => match process.lock().map_tls(template) {
+            Some(tls) => Some(tls),
+            None => {
+                process.lock().unmap_memory(stack_start, stack_size);
+                return None;
+            }
+        },
+        None => None,
+    };
+    // Leaving room for the return address, as if the entry point was called
+    let stack_pointer = stack_start + stack_size - 8u64;
+    let id = scheduler.process_table_mut().allocate_thread_id();
+    let thread = unsafe {
+        Thread::new_native(
+            id,
+            entry_point.as_u64(),
+            stack_pointer.as_u64(),
+            process.clone(),
+        )
+    };
+    let thread = match thread {
+        Some(thread) => thread,
+        None => {
+            let mut process = process.lock();
+            process.unmap_memory(stack_start, stack_size);
+            if let Some(tls) = tls {
+                process.unmap_memory(tls.start, tls.size);
+            }
+            return None;
+        }
+    };
+    {
+        let mut thread = thread.lock();
+        thread.registers_state.rdi = arguments[0];
+        thread.registers_state.rsi = arguments[1];
+        thread.stack = Some((stack_start, stack_size));
+        thread.set_tls(tls);
+    }
+    Thread::change_state(&mut scheduler, thread.clone(), ThreadState::Ready);
+    Some(thread)
+}
+
+/// Replaces the program of the thread's process with an ELF64 executable, the thread starts
+/// running it at the entry point when it's switched to next. The other threads of the process
+/// are terminated.
+///
+/// The process is left untouched if the executable can't be loaded.
+pub fn exec_process(
+    thread: Arc<IrqMutex<Thread>>,
+    elf: &[u8],
+    arguments: &[Vec<u8>],
+    environment: &[Vec<u8>],
+) -> Result<(), ElfLoadError> {
+    let process = thread.lock().process.clone();
+    let (registers_state, tls) = {
+        let mut process = process.lock();
+        let process = &mut *process;
+        let (registers_state, tls) = process.exec(elf, arguments, environment)?;
+
+        let queues = [
+            &mut process.not_started_threads,
+            &mut process.ready_threads,
+            &mut process.sleeping_threads,
+            &mut process.waiting_threads,
+        ];
+        for queue in queues {
+            queue.retain(|other_thread| {
+                let is_caller = Arc::ptr_eq(other_thread, &thread);
+                if !is_caller {
+                    other_thread.lock().terminate();
+                }
+                is_caller
+            });
+        }
+        (registers_state, tls)
+    };
+    {
+        let mut scheduler = get_scheduler();
+        scheduler.preempt_terminated_threads();
+        // The loaded FPU registers belong to the old program
+        scheduler.save_fpu_state();
+    }
+    let mut thread = thread.lock();
+    thread.registers_state = registers_state;
+    thread.fpu_state = FpuState::default();
+    // The stack was in the old address space
+    thread.stack = None;
+    thread.set_tls(tls);
+    Ok(())
+}
+
+/// Switches to the thread the running CPU should run next, or to its idle thread if none is
+/// ready.
+pub fn run_next_thread() -> ! {
+    let next_thread = {
+        let mut scheduler = get_scheduler();
+        scheduler
+            .schedule()
+            .unwrap_or_else(|| scheduler.idle_thread())
+    };
+    switch_to_thread(next_thread);
+}
+
+/// The code the idle thread runs, it waits for interrupts until another thread becomes ready.
+extern "C" fn idle() -> ! {
+    hlt_loop();
 }
 
-pub fn add_process(process: Process) -> Rc<RefCell<Process>> {
-    get_scheduler().add_process(process)
+/// A sleeping thread in the timer queue.
+struct SleepingThread {
+    /// The uptime in milliseconds the thread wakes up at.
+    deadline: u64,
+    /// The thread, which doesn't keep it alive once it was terminated.
+    thread: Weak<IrqMutex<Thread>>,
 }
 
-pub fn run_next_thread() -> Option<()> {
-    let next_thread = get_scheduler().schedule();
-    if let Some(thread) = next_thread {
-        crate::processes::dispatcher::switch_to_thread(thread);
-    } else {
-        Some(())
+impl PartialEq for SleepingThread {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for SleepingThread {}
+
+impl PartialOrd for SleepingThread {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for SleepingThread {
+    /// The earlier deadline is the greater one, so it's on top of the heap.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+/// The threads of a CPU.
+#[derive(Default)]
+struct RunQueue {
+    /// The thread the CPU is running.
+    running_thread: Option<Arc<IrqMutex<Thread>>>,
+    /// The thread that runs while no other thread is ready, created when it's first needed.
+    idle_thread: Option<Arc<IrqMutex<Thread>>>,
+    /// The ready threads of the CPU, the running thread stays in it while it's ready.
+    ready_threads: Vec<Arc<IrqMutex<Thread>>>,
+    /// The thread whose FPU registers the CPU has loaded, they are saved to it when the CPU
+    /// switches threads.
+    fpu_owner: Option<Arc<IrqMutex<Thread>>>,
+}
+
+impl RunQueue {
+    /// Checks if the CPU runs its idle thread, or no thread yet.
+    fn is_idle(&self) -> bool {
+        match (&self.running_thread, &self.idle_thread) {
+            (Some(running_thread), Some(idle_thread)) => Arc::ptr_eq(running_thread, idle_thread),
+            (running_thread, _) => running_thread.is_none(),
+        }
     }
 }
 
 #[derive(Default)]
 pub struct Scheduler {
-    /// The currently running process.
-    pub running_thread: Option<Rc<RefCell<Thread>>>,
-    /// The list of processes that are registered.
-    processes: VecDeque<Rc<RefCell<Process>>>,
+    /// The run queue of each CPU.
+    run_queues: Vec<RunQueue>,
+    /// The processes, running and exited.
+    process_table: ProcessTable,
+    /// The sleeping threads, the one to wake up first on top.
+    sleeping_threads: BinaryHeap<SleepingThread>,
+    /// The uptime the threads were last moved back to the levels of their priorities at.
+    last_boost_millis: u64,
 }
 
 impl Scheduler {
-    /// Adds a process to the scheduling queue so it will be ran.
-    pub fn add_process(&mut self, process: Process) -> Rc<RefCell<Process>> {
-        let rc = Rc::new(RefCell::new(process));
-        self.processes.push_back(rc.clone());
-        rc
+    /// Returns the run queue of the CPU.
+    fn run_queue(&mut self, cpu: usize) -> &mut RunQueue {
+        if self.run_queues.len() <= cpu {
+            self.run_queues.resize_with(cpu + 1, RunQueue::default);
+        }
+        &mut self.run_queues[cpu]
     }
 
-    /// Removes the process from the queue.
-    pub fn remove_process(&mut self, process: Rc<RefCell<Process>>) {
-        self.processes.retain(|p| !Rc::ptr_eq(p, &process));
+    /// Returns the thread the running CPU is running.
+    pub fn running_thread(&self) -> Option<Arc<IrqMutex<Thread>>> {
+        self.run_queues
+            .get(current_cpu_index())
+            .and_then(|run_queue| run_queue.running_thread.clone())
     }
 
-    /// Manages scheduler operations on a timer tick
-    pub fn timer_tick(&self, registers_state: RegistersState, tick: u64) {
-        if let Some(thread) = self.running_thread.clone() {
-            let mut thread_mut = thread.borrow_mut();
+    /// Sets the thread the running CPU is running, `None` while it switches threads.
+    pub fn set_running_thread(&mut self, thread: Option<Arc<IrqMutex<Thread>>>) {
+        self.run_queue(current_cpu_index()).running_thread = thread;
+    }
 
-            thread_mut.registers_state = registers_state;
-            thread_mut.total_ticks += tick - thread_mut.last_tick;
-            thread_mut.last_tick = tick;
-            let mut process = thread_mut.process.borrow_mut();
+    /// Checks if a CPU is running the thread.
+    fn is_running(&self, thread: &Arc<IrqMutex<Thread>>) -> bool {
+        self.run_queues.iter().any(|run_queue| {
+            run_queue
+                .running_thread
+                .as_ref()
+                .map_or(false, |running_thread| Arc::ptr_eq(running_thread, thread))
+        })
+    }
+
+    /// Adds the ready thread to the run queue of its CPU, or of the CPU with the fewest ready
+    /// threads if it wasn't ready before. The CPU is interrupted if it's idle.
+    pub fn enqueue_thread(&mut self, thread: Arc<IrqMutex<Thread>>) {
+        let cpu = thread.lock().cpu.unwrap_or_else(|| {
+            (0..cpu_count())
+                .min_by_key(|&cpu| {
+                    self.run_queues
+                        .get(cpu)
+                        .map_or(0, |run_queue| run_queue.ready_threads.len())
+                })
+                .unwrap_or(0)
+        });
+        thread.lock().cpu = Some(cpu);
+        let run_queue = self.run_queue(cpu);
+        run_queue.ready_threads.push(thread);
+        if run_queue.is_idle() && cpu != current_cpu_index() {
+            send_reschedule(cpu);
+        }
+    }
+
+    /// Removes the thread from the run queue it's in.
+    pub fn dequeue_thread(&mut self, thread: &Arc<IrqMutex<Thread>>) {
+        for run_queue in &mut self.run_queues {
+            run_queue
+                .ready_threads
+                .retain(|ready_thread| !Arc::ptr_eq(ready_thread, thread));
+        }
+    }
+
+    /// Makes the other CPUs whose running thread was terminated switch to another thread.
+    pub fn preempt_terminated_threads(&self) {
+        for (cpu, run_queue) in self.run_queues.iter().enumerate() {
+            let is_terminated = run_queue.running_thread.as_ref().map_or(false, |thread| {
+                matches!(thread.lock().state, ThreadState::Terminated)
+            });
+            if is_terminated && cpu != current_cpu_index() {
+                send_reschedule(cpu);
+            }
+        }
+    }
+
+    /// Makes the other CPUs running a thread of the process switch threads, so the thread
+    /// handles the signals of the process.
+    pub fn preempt_process(&self, process: &Arc<IrqMutex<Process>>) {
+        for (cpu, run_queue) in self.run_queues.iter().enumerate() {
+            let is_in_process = run_queue
+                .running_thread
+                .as_ref()
+                .map_or(false, |thread| Arc::ptr_eq(&thread.lock().process, process));
+            if is_in_process && cpu != current_cpu_index() {
+                send_reschedule(cpu);
+            }
+        }
+    }
+
+    /// Saves the FPU registers the running CPU has loaded to their thread, and makes the next
+    /// thread using them raise a device not available exception.
+    ///
+    /// It has to be called before switching threads, or changing the FPU state of the running
+    /// thread.
+    pub fn save_fpu_state(&mut self) {
+        if let Some(owner) = self.run_queue(current_cpu_index()).fpu_owner.take() {
+            // The registers are only usable while they have an owner
+            unsafe { owner.lock().fpu_state.save() };
+        }
+        fpu::set_task_switched();
+    }
+
+    /// Loads the FPU registers of the running thread after it raised a device not available
+    /// exception, they stay loaded until the CPU switches threads.
+    ///
+    /// Returns `false` if no thread is running.
+    pub fn load_fpu_state(&mut self) -> bool {
+        let thread = match self.running_thread() {
+            Some(thread) => thread,
+            None => return false,
+        };
+        fpu::clear_task_switched();
+        unsafe { thread.lock().fpu_state.restore() };
+        self.run_queue(current_cpu_index()).fpu_owner = Some(thread);
+        true
+    }
+
+    /// Returns the table of the processes.
+    pub fn process_table(&self) -> &ProcessTable {
+        &self.process_table
+    }
+
+    /// Returns the table of the processes, to add or remove processes.
+    pub fn process_table_mut(&mut self) -> &mut ProcessTable {
+        &mut self.process_table
+    }
+
+    /// Registers the sleeping thread to be woken up at the deadline, an uptime in milliseconds.
+    pub fn add_sleeping_thread(&mut self, deadline: u64, thread: Arc<IrqMutex<Thread>>) {
+        self.sleeping_threads.push(SleepingThread {
+            deadline,
+            thread: Arc::downgrade(&thread),
+        });
+    }
+
+    /// Readies the sleeping threads whose deadline has passed.
+    ///
+    /// Threads that stopped sleeping or were dropped in the meantime are skipped.
+    fn wake_up_sleeping_threads(&mut self, now: u64) {
+        while let Some(sleeping_thread) = self.sleeping_threads.peek() {
+            if sleeping_thread.deadline > now {
+                break;
+            }
+            let SleepingThread { deadline, thread } = self.sleeping_threads.pop().unwrap();
+            let thread = match thread.upgrade() {
+                Some(thread) => thread,
+                None => continue,
+            };
+            let is_sleeping = matches!(
+                thread.lock().state,
+                ThreadState::Sleeping(thread_deadline) if thread_deadline == deadline
+            );
+            if is_sleeping {
+                Thread::change_state(self, thread, ThreadState::Ready);
+            }
+        }
+    }
+
+    /// Returns the thread of the running CPU that runs while no other thread is ready.
+    ///
+    /// It's a kernel thread halting with interrupts enabled, so the timer can wake up sleeping
+    /// threads. It's never in a run queue, so it's never scheduled otherwise.
+    pub fn idle_thread(&mut self) -> Arc<IrqMutex<Thread>> {
+        let cpu = current_cpu_index();
+        if let Some(thread) = &self.run_queue(cpu).idle_thread {
+            return thread.clone();
+        }
+        let process = Arc::new(IrqMutex::new(Process::new_kernel(IDLE_PROCESS_ID)));
+        let id = self.process_table.allocate_thread_id();
+        let thread = unsafe { Thread::new_native(id, idle as usize as u64, 0, process) }
+            .expect("Failed to allocate the kernel stack of the idle thread");
+        {
+            let mut thread = thread.lock();
+            // The thread only ever runs in ring 0, so it can use its kernel stack
+            let stack_top = thread.kernel_stack.as_ref().unwrap().top();
+            thread.registers_state.rsp = stack_top;
+            thread.cpu = Some(cpu);
+        }
+        self.run_queue(cpu).idle_thread = Some(thread.clone());
+        thread
+    }
+
+    /// Manages scheduler operations on a timer tick.
+    ///
+    /// Returns if the running thread should be preempted, because it used up its time slice, a
+    /// thread on a lower level is ready or its process has a signal to handle.
+    pub fn timer_tick(&mut self, registers_state: RegistersState, tick: u64) -> bool {
+        let now = uptime_millis();
+        self.wake_up_sleeping_threads(now);
+        if now - self.last_boost_millis >= PRIORITY_BOOST_INTERVAL_MILLIS {
+            self.last_boost_millis = now;
+            self.boost_threads();
+        }
+
+        // Nothing is running yet while the kernel initializes
+        let thread = match self.running_thread() {
+            Some(thread) => thread,
+            None => return false,
+        };
+        if matches!(thread.lock().state, ThreadState::Terminated) {
+            return true;
+        }
+        let cpu = current_cpu_index();
+        let is_idle = self.run_queue(cpu).is_idle();
+        let process = thread.lock().process.clone();
+        let has_signal = {
+            let mut process = process.lock();
             process.total_ticks += tick - process.last_tick;
             process.last_tick = tick;
+            process.signals.has_deliverable()
+        };
+        let level = {
+            let mut thread_mut = thread.lock();
+            let thread_mut = &mut *thread_mut;
+            thread_mut.save_registers(registers_state);
+            thread_mut.total_ticks += tick - thread_mut.last_tick;
+            thread_mut.quantum_ticks += 1;
+            thread_mut.last_tick = tick;
+
+            if thread_mut.quantum_ticks >= time_slice(thread_mut.level) {
+                thread_mut.demote();
+                return true;
+            }
+            thread_mut.level
+        };
+        if has_signal {
+            return true;
+        }
+        // An idle CPU looks for threads it can take from the other CPUs too
+        match self.find_thread_to_run(cpu) {
+            Some(next_thread) => is_idle || next_thread.lock().level < level,
+            None => is_idle,
         }
     }
 
-    /// Returns the thread that should be ran next.
-    pub fn schedule(&mut self) -> Option<Rc<RefCell<Thread>>> {
-        if self.processes.is_empty() {
-            return None;
+    /// Handles a reschedule IPI, another CPU readied a thread on this one, terminated its
+    /// running thread or sent a signal to its process.
+    ///
+    /// Returns if the running thread should be preempted.
+    pub fn reschedule(&mut self, registers_state: RegistersState) -> bool {
+        let thread = match self.running_thread() {
+            Some(thread) => thread,
+            None => return false,
+        };
+        if matches!(thread.lock().state, ThreadState::Terminated) {
+            return true;
+        }
+        thread.lock().save_registers(registers_state);
+        let cpu = current_cpu_index();
+        if self.run_queue(cpu).is_idle() || has_deliverable_signal(&thread) {
+            return true;
         }
-        // We're taking the first process in the queue that returns a runnable thread
-        let processes = &self.processes;
-        let process_index = processes.iter().position(|process| {
-            Process::update_sleeping_threads(process.clone());
-            !process.borrow().ready_threads.is_empty()
-        })?;
-        let process = self.processes.remove(process_index)?;
-        let thread = Scheduler::get_thread_to_run(process.clone())?;
-        // Putting the process at the back of the queue
-        self.processes.push_back(process);
+        let level = thread.lock().level;
+        self.find_thread_to_run(cpu)
+            .map_or(false, |next_thread| next_thread.lock().level < level)
+    }
+
+    /// Returns the thread the running CPU should run next, taking one from another CPU if it
+    /// has none ready.
+    pub fn schedule(&mut self) -> Option<Arc<IrqMutex<Thread>>> {
+        let cpu = current_cpu_index();
+        // Threads of killed processes are terminated without being dequeued
+        self.run_queue(cpu)
+            .ready_threads
+            .retain(|thread| !matches!(thread.lock().state, ThreadState::Terminated));
+        self.find_thread_to_run(cpu)
+            .or_else(|| self.steal_thread(cpu))
+    }
+
+    /// Returns the ready thread of the CPU on the lowest level, the one that waited the longest
+    /// if there are more of them.
+    fn find_thread_to_run(&self, cpu: usize) -> Option<Arc<IrqMutex<Thread>>> {
+        // The order is read up front, so only one thread is locked at a time
+        self.run_queues
+            .get(cpu)?
+            .ready_threads
+            .iter()
+            .filter_map(|thread| {
+                let locked_thread = thread.lock();
+                let is_terminated = matches!(locked_thread.state, ThreadState::Terminated);
+                (!is_terminated).then(|| ((locked_thread.level, locked_thread.last_tick), thread))
+            })
+            .min_by_key(|(order, _)| *order)
+            .map(|(_, thread)| thread.clone())
+    }
+
+    /// Checks if another CPU can take the thread from its run queue.
+    fn can_steal(&self, thread: &Arc<IrqMutex<Thread>>) -> bool {
+        !matches!(thread.lock().state, ThreadState::Terminated) && !self.is_running(thread)
+    }
 
+    /// Moves a ready thread that isn't running from the CPU with the most of them to the run
+    /// queue of the CPU.
+    fn steal_thread(&mut self, cpu: usize) -> Option<Arc<IrqMutex<Thread>>> {
+        let (victim, _) = self
+            .run_queues
+            .iter()
+            .enumerate()
+            .filter(|(other_cpu, _)| *other_cpu != cpu)
+            .map(|(other_cpu, run_queue)| {
+                let stealable = run_queue
+                    .ready_threads
+                    .iter()
+                    .filter(|thread| self.can_steal(thread))
+                    .count();
+                (other_cpu, stealable)
+            })
+            .filter(|(_, stealable)| *stealable > 0)
+            .max_by_key(|(_, stealable)| *stealable)?;
+        let position = self.run_queues[victim]
+            .ready_threads
+            .iter()
+            .position(|thread| self.can_steal(thread))?;
+        let thread = self.run_queues[victim].ready_threads.remove(position);
+        thread.lock().cpu = Some(cpu);
+        self.run_queue(cpu).ready_threads.push(thread.clone());
         Some(thread)
     }
 
-    /// Returns the thread from the process that should be ran next.
-    fn get_thread_to_run(process: Rc<RefCell<Process>>) -> Option<Rc<RefCell<Thread>>> {
-        let mut process_borrowed = process.borrow_mut();
-        // Taking the first thread in the chosen process
-        if process_borrowed.ready_threads.is_empty() {
-            return None;
+    /// Moves every thread back to the level of its priority, so threads that were demoted
+    /// don't starve.
+    fn boost_threads(&self) {
+        for process in self.process_table.iter() {
+            let process = process.lock();
+            let queues = [
+                &process.not_started_threads,
+                &process.ready_threads,
+                &process.sleeping_threads,
+                &process.waiting_threads,
+            ];
+            for thread in queues.into_iter().flatten() {
+                let mut thread = thread.lock();
+                let priority = thread.priority;
+                thread.set_priority(priority);
+            }
         }
-        let thread = process_borrowed.ready_threads.remove(0);
-        // Putting the thread at the back of the thread-queue
-        process_borrowed.ready_threads.push(thread.clone());
-        Some(thread)
     }
 }
+
+/// Sets the time slice of the threads on the first level, the threads on each level below get
+/// twice as long.
+pub fn set_quantum_millis(millis: u64) {
+    QUANTUM_NANOS.store(millis.max(1) * 1_000_000, atomic::Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts a thread on the level runs for before it's demoted.
+fn time_slice(level: u8) -> u64 {
+    let quantum_ticks = QUANTUM_NANOS.load(atomic::Ordering::Relaxed) / timer_period_nanos();
+    quantum_ticks.max(1) << level
+}
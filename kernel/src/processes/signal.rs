@@ -0,0 +1,398 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::fpu::FpuState;
+use super::process::Process;
+use super::process_table::INIT_PROCESS_ID;
+use super::thread::Thread;
+use super::{get_scheduler, RegistersState, Scheduler};
+use crate::smp::with_kernel_lock;
+use crate::sync::IrqMutex;
+use crate::syscalls::system_call::SYSCALL_ERROR;
+
+/// The raw handler that restores the default action of a signal.
+pub const SIGNAL_DEFAULT: u64 = 0;
+/// The raw handler that makes a process ignore a signal.
+pub const SIGNAL_IGNORE: u64 = 1;
+/// The bytes below the stack pointer the interrupted code can use without moving it, the
+/// signal frame is placed below them.
+const RED_ZONE_SIZE: u64 = 128;
+/// The flags of RFLAGS a signal handler can change in its frame.
+const USER_FLAGS: u64 = 0xDD5;
+/// The trap and direction flags, cleared when a handler is called like the ABI expects.
+const HANDLER_CLEARED_FLAGS: u64 = 0x500;
+/// The number of signals there can be, their numbers are below it.
+const SIGNAL_COUNT: usize = 32;
+
+/// The process `interrupt_foreground_process` sends its signal to.
+static FOREGROUND_PROCESS_ID: AtomicU64 = AtomicU64::new(INIT_PROCESS_ID);
+
+/// A signal a process can be sent, numbered like on Linux.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Hangup = 1,
+    /// Sent to the foreground process when Ctrl+C is pressed.
+    Interrupt = 2,
+    Quit = 3,
+    Abort = 6,
+    /// Terminates the process, it can't be handled, ignored or blocked.
+    Kill = 9,
+    User1 = 10,
+    User2 = 12,
+    Pipe = 13,
+    Alarm = 14,
+    Terminate = 15,
+    /// Sent to the parent when a process exits, ignored by default.
+    Child = 17,
+}
+
+impl Signal {
+    /// Returns the bit of the signal in a signal mask.
+    pub fn mask(self) -> u64 {
+        1 << self as u64
+    }
+
+    /// Checks if the signal does nothing unless the process handles it.
+    pub fn is_ignored_by_default(self) -> bool {
+        self == Signal::Child
+    }
+
+    /// Checks if the process can change what the signal does.
+    pub fn can_be_caught(self) -> bool {
+        self != Signal::Kill
+    }
+}
+
+impl TryFrom<u64> for Signal {
+    type Error = ();
+
+    fn try_from(number: u64) -> Result<Self, Self::Error> {
+        match number {
+            1 => Ok(Signal::Hangup),
+            2 => Ok(Signal::Interrupt),
+            3 => Ok(Signal::Quit),
+            6 => Ok(Signal::Abort),
+            9 => Ok(Signal::Kill),
+            10 => Ok(Signal::User1),
+            12 => Ok(Signal::User2),
+            13 => Ok(Signal::Pipe),
+            14 => Ok(Signal::Alarm),
+            15 => Ok(Signal::Terminate),
+            17 => Ok(Signal::Child),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What a process does when it gets a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalAction {
+    /// The process is terminated, unless the signal is ignored by default.
+    Default,
+    /// The signal is discarded.
+    Ignore,
+    /// The handler is called with the signal number, it returns to the restorer, which has to
+    /// make the `sigreturn` system call.
+    Handler {
+        handler: VirtAddr,
+        restorer: VirtAddr,
+    },
+}
+
+impl SignalAction {
+    /// Creates the action from the raw handler and restorer of a system call.
+    pub fn from_raw(handler: u64, restorer: u64) -> Option<SignalAction> {
+        match handler {
+            SIGNAL_DEFAULT => Some(SignalAction::Default),
+            SIGNAL_IGNORE => Some(SignalAction::Ignore),
+            _ => Some(SignalAction::Handler {
+                handler: VirtAddr::try_new(handler).ok()?,
+                restorer: VirtAddr::try_new(restorer).ok()?,
+            }),
+        }
+    }
+
+    /// Returns the raw handler of the action, as system calls return it.
+    pub fn raw_handler(&self) -> u64 {
+        match self {
+            SignalAction::Default => SIGNAL_DEFAULT,
+            SignalAction::Ignore => SIGNAL_IGNORE,
+            SignalAction::Handler { handler, .. } => handler.as_u64(),
+        }
+    }
+}
+
+/// The signals of a process.
+#[derive(Debug, Clone)]
+pub struct Signals {
+    /// The signals that were sent and not handled yet.
+    pending: u64,
+    /// The signals that stay pending until they are unblocked.
+    blocked: u64,
+    /// What the process does with each signal, by number.
+    actions: [SignalAction; SIGNAL_COUNT],
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Signals {
+            pending: 0,
+            blocked: 0,
+            actions: [SignalAction::Default; SIGNAL_COUNT],
+        }
+    }
+}
+
+impl Signals {
+    /// Returns what the process does with the signal.
+    pub fn action(&self, signal: Signal) -> SignalAction {
+        self.actions[signal as usize]
+    }
+
+    /// Changes what the process does with the signal, pending signals that are ignored now are
+    /// discarded.
+    ///
+    /// Returns the previous action, or `None` if the signal can't be caught.
+    pub fn set_action(&mut self, signal: Signal, action: SignalAction) -> Option<SignalAction> {
+        if !signal.can_be_caught() {
+            return None;
+        }
+        let previous = core::mem::replace(&mut self.actions[signal as usize], action);
+        if self.is_ignored(signal) {
+            self.pending &= !signal.mask();
+        }
+        Some(previous)
+    }
+
+    /// Returns the mask of the blocked signals.
+    pub fn blocked(&self) -> u64 {
+        self.blocked
+    }
+
+    /// Blocks the signals in the mask, the ones that can't be caught are never blocked.
+    pub fn set_blocked(&mut self, mask: u64) {
+        self.blocked = mask & !Signal::Kill.mask();
+    }
+
+    /// Checks if the process doesn't do anything when it gets the signal.
+    pub fn is_ignored(&self, signal: Signal) -> bool {
+        match self.action(signal) {
+            SignalAction::Default => signal.is_ignored_by_default(),
+            SignalAction::Ignore => true,
+            SignalAction::Handler { .. } => false,
+        }
+    }
+
+    /// Checks if the signal terminates the process, because it neither handles nor ignores it.
+    pub fn terminates(&self, signal: Signal) -> bool {
+        matches!(self.action(signal), SignalAction::Default) && !signal.is_ignored_by_default()
+    }
+
+    /// Marks the signal as pending, unless the process ignores it.
+    ///
+    /// Returns if it can be delivered right away.
+    pub fn raise(&mut self, signal: Signal) -> bool {
+        if self.is_ignored(signal) {
+            return false;
+        }
+        self.pending |= signal.mask();
+        self.blocked & signal.mask() == 0
+    }
+
+    /// Checks if a pending signal isn't blocked.
+    pub fn has_deliverable(&self) -> bool {
+        self.pending & !self.blocked != 0
+    }
+
+    /// Removes the pending signal with the lowest number that isn't blocked.
+    pub fn take_deliverable(&mut self) -> Option<Signal> {
+        let deliverable = self.pending & !self.blocked;
+        if deliverable == 0 {
+            return None;
+        }
+        let number = deliverable.trailing_zeros() as u64;
+        self.pending &= !(1 << number);
+        Signal::try_from(number).ok()
+    }
+
+    /// Returns the signals of a forked copy of the process, which has no pending signals.
+    pub fn fork(&self) -> Signals {
+        Signals {
+            pending: 0,
+            ..self.clone()
+        }
+    }
+
+    /// Restores the default action of the handled signals, as their handlers are in the program
+    /// that was replaced.
+    pub fn reset_handlers(&mut self) {
+        for action in self.actions.iter_mut() {
+            if let SignalAction::Handler { .. } = action {
+                *action = SignalAction::Default;
+            }
+        }
+    }
+}
+
+/// What a handler finds on its stack above the return address, `sigreturn` restores the
+/// interrupted code from it.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    /// The registers of the code the handler interrupted.
+    pub registers_state: RegistersState,
+    /// The FPU and SSE registers of the code the handler interrupted.
+    pub fpu_state: FpuState,
+    /// The blocked signals before the handler was called.
+    pub blocked: u64,
+    /// The signal that is handled.
+    pub signal: u64,
+}
+
+/// Checks if the signal can be sent to the process. Init has to keep running to adopt orphans,
+/// so it's only sent the signals it handles or ignores.
+pub fn can_send_signal(process: &Process, signal: Signal) -> bool {
+    process.id != INIT_PROCESS_ID || !process.signals.terminates(signal)
+}
+
+/// Sends the signal to the process, unless it's ignored or can't be sent to it.
+///
+/// A thread of the process that is blocked in a system call is woken up so it can handle the
+/// signal, the system call fails. The CPUs running threads of the process are interrupted.
+pub fn send_signal(scheduler: &mut Scheduler, process: &Arc<IrqMutex<Process>>, signal: Signal) {
+    let blocked_thread = {
+        let mut process = process.lock();
+        // Kernel processes don't return to user mode, where signals are handled
+        if process.kernel_process
+            || !can_send_signal(&process, signal)
+            || !process.signals.raise(signal)
+        {
+            return;
+        }
+        process
+            .sleeping_threads
+            .iter()
+            .chain(process.waiting_threads.iter())
+            .next()
+            .cloned()
+    };
+    if let Some(thread) = blocked_thread {
+        Thread::wake_up(scheduler, thread, SYSCALL_ERROR);
+    }
+    scheduler.preempt_process(process);
+}
+
+/// Checks if the process of the thread has a signal it can handle now.
+pub fn has_deliverable_signal(thread: &Arc<IrqMutex<Thread>>) -> bool {
+    let process = thread.lock().process.clone();
+    let has_deliverable = process.lock().signals.has_deliverable();
+    has_deliverable
+}
+
+/// Makes the thread call the handler of the signal once it runs again, by putting the frame on
+/// its stack. The signal is blocked while it's handled, and the handler starts with clean FPU
+/// registers.
+///
+/// The FPU registers of the thread must have been saved. Returns the address the frame was
+/// supposed to be at if the stack isn't writable.
+pub(crate) fn push_signal_frame(
+    process: &mut Process,
+    thread: &mut Thread,
+    signal: Signal,
+    handler: VirtAddr,
+    restorer: VirtAddr,
+) -> Result<(), VirtAddr> {
+    let registers_state = &mut thread.registers_state;
+    let frame = SignalFrame {
+        registers_state: *registers_state,
+        fpu_state: thread.fpu_state,
+        blocked: process.signals.blocked(),
+        signal: signal as u64,
+    };
+    let frame_size = size_of::<SignalFrame>() as u64;
+    // The handler is entered as if it was called, right below a 16 bytes aligned frame
+    let frame_address = registers_state
+        .rsp
+        .as_u64()
+        .checked_sub(RED_ZONE_SIZE + frame_size)
+        .map(|address| VirtAddr::new_truncate(address).align_down(16u64))
+        .filter(|address| address.as_u64() >= 8)
+        .ok_or(registers_state.rsp)?;
+    let stack_pointer = frame_address - 8u64;
+
+    let mut data = Vec::with_capacity(8 + frame_size as usize);
+    data.extend_from_slice(&restorer.as_u64().to_ne_bytes());
+    data.extend_from_slice(unsafe {
+        core::slice::from_raw_parts(
+            &frame as *const SignalFrame as *const u8,
+            frame_size as usize,
+        )
+    });
+    process
+        .write_memory(stack_pointer, &data)
+        .ok_or(frame_address)?;
+
+    let blocked = process.signals.blocked() | signal.mask();
+    process.signals.set_blocked(blocked);
+    registers_state.rip = handler;
+    registers_state.rsp = stack_pointer;
+    registers_state.rdi = signal as u64;
+    registers_state.rflags &= !HANDLER_CLEARED_FLAGS;
+    thread.fpu_state = FpuState::default();
+    Ok(())
+}
+
+/// Reads the signal frame at the stack pointer of a handler that returned, restoring the
+/// registers of the interrupted code and the blocked signals from it.
+///
+/// The FPU registers of the thread must have been saved. Returns `None` if the frame isn't
+/// valid, the thread is left unchanged then.
+pub fn pop_signal_frame(process: &mut Process, thread: &mut Thread) -> Option<()> {
+    let stack_pointer = thread.registers_state.rsp;
+    let frame = process.read_memory(stack_pointer, size_of::<SignalFrame>() as u64)?;
+    let frame = unsafe { read_unaligned(frame.as_ptr() as *const SignalFrame) };
+    let mut registers_state = frame.registers_state;
+    // The handler could have changed anything in the frame
+    registers_state.rip = VirtAddr::try_new(registers_state.rip.as_u64()).ok()?;
+    registers_state.rsp = VirtAddr::try_new(registers_state.rsp.as_u64()).ok()?;
+    registers_state.rflags &= USER_FLAGS;
+    let mut fpu_state = frame.fpu_state;
+    fpu_state.sanitize();
+    process.signals.set_blocked(frame.blocked);
+    thread.registers_state = registers_state;
+    thread.fpu_state = fpu_state;
+    Some(())
+}
+
+/// Sets the process Ctrl+C interrupts.
+pub fn set_foreground_process(id: u64) {
+    FOREGROUND_PROCESS_ID.store(id, Ordering::Relaxed);
+}
+
+/// Makes init the foreground process again if the exited process with the ID was it.
+pub(crate) fn reset_foreground_process(id: u64) {
+    let _ = FOREGROUND_PROCESS_ID.compare_exchange(
+        id,
+        INIT_PROCESS_ID,
+        Ordering::Relaxed,
+        Ordering::Relaxed,
+    );
+}
+
+/// Sends the interrupt signal to the foreground process, if it's still running.
+pub fn interrupt_foreground_process() {
+    with_kernel_lock(|| {
+        let mut scheduler = get_scheduler();
+        let id = FOREGROUND_PROCESS_ID.load(Ordering::Relaxed);
+        let process = scheduler.process_table().get(id);
+        if let Some(process) = process {
+            send_signal(&mut scheduler, &process, Signal::Interrupt);
+        }
+    });
+}
@@ -1,39 +1,84 @@
-use core::cell::RefCell;
-
-use alloc::rc::Rc;
+use alloc::sync::Arc;
 use internal_utils::get_current_tick;
 use x86_64::VirtAddr;
 
 use super::process::Process;
+use super::Scheduler;
+use crate::sync::IrqMutex;
 
 use super::dispatcher::remove_thread_from_process_queues;
+use super::fpu::FpuState;
+use super::kernel_stack::{release_kernel_stack, KernelStack};
+use super::tls::{user_fs_base, TlsBlock};
 use super::RegistersState;
 
+/// The number of scheduling levels, a thread's priority and level are below it.
+pub const PRIORITY_LEVELS: u8 = 8;
+
 #[derive(Debug, Clone)]
 pub enum ThreadState {
     NotStarted,
     Ready,
     Running,
+    /// Sleeping until the uptime in milliseconds.
     Sleeping(u64),
+    Waiting(WaitTarget),
     Terminated,
 }
 
+/// What a waiting thread waits for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitTarget {
+    /// The thread of the same process with the ID to exit.
+    Thread(u64),
+    /// The child process with the ID to exit.
+    Process(u64),
+    /// The futex at the address to be woken up by a thread of the same process.
+    Futex(VirtAddr),
+}
+
 #[derive(Debug)]
 pub struct Thread {
-    /// The thread's ID (in-process).
+    /// The thread's ID, unique among the threads of all processes.
     pub id: u64,
     /// The thread's current state.
     pub state: ThreadState,
     /// The state of the registers.
     pub registers_state: RegistersState,
+    /// The state of the FPU and SSE registers, out of date while the thread's CPU has them
+    /// loaded.
+    pub fpu_state: FpuState,
+    /// The base of the FS segment, user mode finds its thread-local variables through it.
+    pub fs_base: VirtAddr,
     /// Total ticks the thread has been running for.
     pub total_ticks: u64,
     /// The tick the thread has been created on.
     pub start_tick: u64,
     /// The tick the thread has been last ran on.
     pub last_tick: u64,
+    /// The highest scheduling level the thread can be at, 0 is the most important.
+    pub priority: u8,
+    /// The scheduling level the thread is at, threads on lower levels run first.
+    pub level: u8,
+    /// The timer interrupts the thread has been running for on its current level.
+    pub quantum_ticks: u64,
     /// The process the thread is running for.
-    pub process: Rc<RefCell<Process>>,
+    pub process: Arc<IrqMutex<Process>>,
+    /// The CPU whose run queue the thread is in while it's ready, `None` until it's first ready.
+    pub cpu: Option<usize>,
+    /// The code the thread exited with, `None` while it's still running.
+    pub exit_code: Option<u64>,
+    /// Set once the thread doesn't have to be joined, its exit code isn't kept then.
+    pub detached: bool,
+    /// The stack the thread uses in the kernel, `None` once the thread terminated.
+    pub kernel_stack: Option<KernelStack>,
+    /// The kernel stack pointer the thread continues from if it blocked inside of the kernel.
+    pub kernel_context: Option<VirtAddr>,
+    /// The start and the size of the stack reserved for the thread, including its guard page.
+    /// It's freed when the thread exits.
+    pub stack: Option<(VirtAddr, u64)>,
+    /// The thread-local storage reserved for the thread, freed when it exits.
+    pub tls: Option<TlsBlock>,
 }
 
 impl Thread {
@@ -43,59 +88,132 @@ impl Thread {
         self.total_ticks * 100 / ticks_maximum
     }
 
-    pub fn change_state(thread: Rc<RefCell<Thread>>, state: ThreadState) {
+    /// Stores the registers the thread entered the kernel with, together with its FS base if user
+    /// mode could have changed it.
+    pub fn save_registers(&mut self, registers_state: RegistersState) {
+        self.registers_state = registers_state;
+        if let Some(fs_base) = user_fs_base() {
+            self.fs_base = fs_base;
+        }
+    }
+
+    /// Gives the thread its thread-local storage, pointing its FS base to the thread pointer.
+    pub fn set_tls(&mut self, tls: Option<TlsBlock>) {
+        self.fs_base = tls.map_or(VirtAddr::zero(), |tls| tls.thread_pointer);
+        self.tls = tls;
+    }
+
+    /// Moves the thread to the queue of the state in its process and in the scheduler.
+    ///
+    /// The process and the thread must not be locked by the caller.
+    pub fn change_state(
+        scheduler: &mut Scheduler,
+        thread: Arc<IrqMutex<Thread>>,
+        state: ThreadState,
+    ) {
+        let process = thread.lock().process.clone();
         {
-            let borrowed_thread = thread.borrow();
-            let mut borrowed_process = borrowed_thread.process.borrow_mut();
+            let mut locked_process = process.lock();
+            let mut locked_thread = thread.lock();
             remove_thread_from_process_queues(
-                &borrowed_thread,
-                thread.clone(),
-                &mut borrowed_process,
+                scheduler,
+                &locked_thread,
+                &thread,
+                &mut locked_process,
             );
-        }
-        let mut borrowed_thread = thread.borrow_mut();
-        borrowed_thread.state = state;
-        {
-            let mut borrowed_process = borrowed_thread.process.borrow_mut();
-            match borrowed_thread.state {
-                ThreadState::NotStarted => borrowed_process.not_started_threads.push(thread.clone()),
-                ThreadState::Ready => borrowed_process.ready_threads.push(thread.clone()),
+            locked_thread.state = state.clone();
+            match state {
+                ThreadState::NotStarted => locked_process.not_started_threads.push(thread.clone()),
+                ThreadState::Ready => locked_process.ready_threads.push(thread.clone()),
                 ThreadState::Running => panic!("Trying to change a thread to running state - use dispatcher::switch_to_thread() instead"),
-                ThreadState::Sleeping(_) => borrowed_process.sleeping_threads.push(thread.clone()),
+                ThreadState::Sleeping(deadline) => {
+                    locked_process.sleeping_threads.push(thread.clone());
+                    scheduler.add_sleeping_thread(deadline, thread.clone());
+                }
+                ThreadState::Waiting(_) => locked_process.waiting_threads.push(thread.clone()),
                 ThreadState::Terminated => {}
             }
         }
+        if matches!(state, ThreadState::Ready) {
+            scheduler.enqueue_thread(thread);
+        }
     }
 
-    /// Creates a new thread with the given starting address and stack pointer.
+    /// Sets the priority of the thread, moving it to the level of the priority.
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+        self.level = priority;
+        self.quantum_ticks = 0;
+    }
+
+    /// Moves the thread one level up because it blocked before using up its time slice.
+    pub fn promote(&mut self) {
+        self.level = self.level.saturating_sub(1).max(self.priority);
+        self.quantum_ticks = 0;
+    }
+
+    /// Moves the thread one level down because it used up its time slice.
+    pub fn demote(&mut self) {
+        self.level = (self.level + 1).min(PRIORITY_LEVELS - 1);
+        self.quantum_ticks = 0;
+    }
+
+    /// Marks the thread as terminated and frees its kernel stack once it's not in use.
+    pub fn terminate(&mut self) {
+        self.state = ThreadState::Terminated;
+        if let Some(kernel_stack) = self.kernel_stack.take() {
+            release_kernel_stack(kernel_stack);
+        }
+    }
+
+    /// Readies the waiting thread, it continues with the result as the return value of the system
+    /// call it was waiting in. The result is stored in the thread's RAX.
+    pub fn wake_up(scheduler: &mut Scheduler, thread: Arc<IrqMutex<Thread>>, result: u64) {
+        thread.lock().registers_state.rax = result;
+        Thread::change_state(scheduler, thread, ThreadState::Ready);
+    }
+
+    /// Creates a new thread with the given ID from the process table, starting address and stack
+    /// pointer.
+    ///
+    /// Returns `None` if there is no memory left for its kernel stack.
     ///
     /// # Safety
     /// This function is unsafe as it does not enforce pointing the instruction and stack pointers to valid addresses.
     pub unsafe fn new_native(
+        id: u64,
         address: u64,
         stack_pointer: u64,
-        process: Rc<RefCell<Process>>,
-    ) -> Rc<RefCell<Self>> {
+        process: Arc<IrqMutex<Process>>,
+    ) -> Option<Arc<IrqMutex<Self>>> {
+        let kernel_stack = KernelStack::allocate()?;
         let thread = Thread {
-            id: {
-                let process = process.borrow();
-                process.not_started_threads.len()
-                    + process.ready_threads.len()
-                    + process.sleeping_threads.len()
-            } as u64,
+            id,
             state: ThreadState::NotStarted,
             total_ticks: 0,
             start_tick: get_current_tick(),
             last_tick: 0,
+            priority: 0,
+            level: 0,
+            quantum_ticks: 0,
             process: process.clone(),
+            cpu: None,
+            exit_code: None,
+            detached: false,
+            kernel_stack: Some(kernel_stack),
+            kernel_context: None,
+            stack: None,
+            tls: None,
             registers_state: RegistersState::new(
                 VirtAddr::new(address),
                 0x200,
                 VirtAddr::new(stack_pointer),
             ),
+            fpu_state: FpuState::default(),
+            fs_base: VirtAddr::zero(),
         };
-        let rc = Rc::new(RefCell::new(thread));
-        process.borrow_mut().not_started_threads.push(rc.clone());
-        rc
+        let arc = Arc::new(IrqMutex::new(thread));
+        process.lock().not_started_threads.push(arc.clone());
+        Some(arc)
     }
 }
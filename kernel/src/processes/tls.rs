@@ -0,0 +1,84 @@
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use x86_64::instructions::segmentation::{Segment64, FS};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::FsBase;
+use x86_64::{align_up, VirtAddr};
+
+/// The bit of EBX in CPUID leaf 7 that is set if the CPU has the FS and GS base instructions.
+const CPUID_FSGSBASE: u32 = 1;
+/// The size of the thread control block right at the thread pointer, it only holds the thread
+/// pointer itself so `fs:0` can be used to find it.
+pub const TCB_SIZE: u64 = 8;
+
+/// Set once `wrfsbase` and the other FS and GS base instructions are enabled.
+static FSGSBASE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The initialization image of the thread-local variables of a program, from its `PT_TLS`
+/// segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsTemplate {
+    /// Where the initialized variables are in the memory of the program.
+    pub address: VirtAddr,
+    /// The size of the initialized variables, the rest of a thread's block is zeroed.
+    pub file_size: u64,
+    /// The size of all the thread-local variables.
+    pub memory_size: u64,
+    /// The alignment of the block, a power of two of at most a page.
+    pub align: u64,
+}
+
+impl TlsTemplate {
+    /// Returns the offset of a thread's block below its thread pointer, which is aligned like
+    /// the block.
+    pub fn block_offset(&self) -> u64 {
+        align_up(self.memory_size, self.align)
+    }
+
+    /// Returns the size of a thread's block together with its thread control block.
+    pub fn size(&self) -> u64 {
+        self.block_offset() + TCB_SIZE
+    }
+
+    /// Returns the thread pointer of the block starting at the address.
+    pub fn thread_pointer(&self, start: VirtAddr) -> VirtAddr {
+        start + self.block_offset()
+    }
+}
+
+/// The thread-local storage reserved for a thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TlsBlock {
+    /// The start of the reserved memory.
+    pub start: VirtAddr,
+    /// The size of the reserved memory.
+    pub size: u64,
+    /// The address the FS base of the thread points to, right after its thread-local variables.
+    pub thread_pointer: VirtAddr,
+}
+
+/// Enables the FS and GS base instructions on the running CPU if it has them, so the FS base
+/// can be set without writing the model-specific register.
+pub(crate) fn enable() {
+    let has_fsgsbase = unsafe { __cpuid_count(7, 0) }.ebx & CPUID_FSGSBASE != 0;
+    if has_fsgsbase {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::FSGSBASE)) };
+        FSGSBASE_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Sets the FS base of the running CPU, which is where `fs:0` points to.
+pub fn set_fs_base(base: VirtAddr) {
+    if FSGSBASE_ENABLED.load(Ordering::Relaxed) {
+        unsafe { FS::write_base(base) };
+    } else {
+        FsBase::write(base);
+    }
+}
+
+/// Returns the FS base of the running CPU if user mode can change it without a system call,
+/// which it can once the FS and GS base instructions are enabled.
+pub(crate) fn user_fs_base() -> Option<VirtAddr> {
+    FSGSBASE_ENABLED.load(Ordering::Relaxed).then(FS::read_base)
+}
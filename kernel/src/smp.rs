@@ -0,0 +1,325 @@
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use alloc::vec::Vec;
+use x86_64::instructions::tlb;
+use x86_64::registers::model_specific::KernelGsBase;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::acpi::madt::Madt;
+use crate::debug;
+use crate::init::get_kernel_information;
+use crate::interrupts::{self, apic, InterruptIndex};
+use crate::memory::get_kernel_memory;
+use crate::processes::fpu;
+use crate::processes::kernel_stack::KernelStack;
+use crate::processes::tls;
+use crate::processes::{run_next_thread, RegistersState};
+use crate::syscalls::system_call::setup_syscalls;
+use crate::time::monotonic_nanos;
+
+mod kernel_lock;
+mod trampoline;
+pub(crate) use kernel_lock::{kernel_lock_depth, set_kernel_lock_depth, with_kernel_lock};
+pub use kernel_lock::{lock_kernel, release_kernel_lock, unlock_kernel};
+use trampoline::Trampoline;
+
+/// The most CPUs the kernel runs on, the other processors are left halted.
+pub const MAX_CPUS: usize = 16;
+/// The index of the CPU the kernel was booted on.
+pub const BOOT_CPU: usize = 0;
+/// The end of the memory real mode can reach, application processors start in it.
+pub(crate) const REAL_MODE_MEMORY_END: u64 = 0x10_0000;
+/// How long to wait after the INIT IPI before sending the startup IPI.
+const INIT_DELAY_MICROS: u64 = 10_000;
+/// How long to wait for a processor after the first startup IPI before sending the second one.
+const STARTUP_DELAY_MICROS: u64 = 200;
+/// How long to wait for a processor after the second startup IPI before giving up on it.
+const STARTUP_TIMEOUT_MICROS: u64 = 100_000;
+/// The size of the stack threads are returned to from.
+const RETURN_STACK_SIZE: usize = 1024;
+
+/// A CPU the kernel runs on.
+///
+/// The CPUs are in the kernel's image, so they are mapped in every address space. The kernel GS
+/// base of a processor points to its CPU.
+#[repr(C)]
+pub(crate) struct Cpu {
+    /// The top of the stack `_syscall` switches to, the kernel stack of the running thread.
+    /// `_syscall` reads it through the GS segment, so it has to be the first field.
+    syscall_stack_top: AtomicU64,
+    index: usize,
+    apic_id: u8,
+    online: AtomicBool,
+    /// The paging table of the thread the CPU runs.
+    address_space: AtomicU64,
+    /// Set while another CPU waits for this one to flush its TLB.
+    tlb_flush_requested: AtomicBool,
+    /// The stacks of an application processor: the one it starts on, followed by the double
+    /// fault, NMI and timer interrupt stacks.
+    stacks: Vec<KernelStack>,
+    return_stack: ReturnStack,
+}
+
+/// The stack `switch_to_thread` returns to a thread from, the registers of the thread are on
+/// its top.
+///
+/// The kernel stack `switch_to_thread` was called on can be taken over by another CPU once the
+/// kernel lock is released, this one only ever belongs to its CPU.
+#[repr(C, align(16))]
+struct ReturnStack {
+    stack: [u8; RETURN_STACK_SIZE],
+    registers_state: MaybeUninit<RegistersState>,
+}
+
+impl Cpu {
+    // Only used to initialize `CPUS`
+    #[allow(clippy::declare_interior_mutable_const)]
+    const OFFLINE: Cpu = Cpu {
+        syscall_stack_top: AtomicU64::new(0),
+        index: 0,
+        apic_id: 0,
+        online: AtomicBool::new(false),
+        address_space: AtomicU64::new(0),
+        tlb_flush_requested: AtomicBool::new(false),
+        stacks: Vec::new(),
+        return_stack: ReturnStack {
+            stack: [0; RETURN_STACK_SIZE],
+            registers_state: MaybeUninit::uninit(),
+        },
+    };
+}
+
+static mut CPUS: [Cpu; MAX_CPUS] = [Cpu::OFFLINE; MAX_CPUS];
+/// The number of CPUs that are online, they have the indices below it.
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+
+/// Returns the CPU the code runs on, the boot processor's before it's set up.
+fn current_cpu() -> &'static mut Cpu {
+    let address = KernelGsBase::read();
+    unsafe {
+        if address.is_null() {
+            &mut CPUS[BOOT_CPU]
+        } else {
+            &mut *address.as_mut_ptr::<Cpu>()
+        }
+    }
+}
+
+/// Returns the online CPUs other than the running one.
+fn other_cpus() -> impl Iterator<Item = &'static Cpu> {
+    let current = current_cpu_index();
+    unsafe { CPUS[..cpu_count()].iter() }.filter(move |cpu| cpu.index != current)
+}
+
+/// Returns the index of the CPU the code runs on.
+pub fn current_cpu_index() -> usize {
+    current_cpu().index
+}
+
+/// Returns the number of CPUs the kernel runs on.
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Points the kernel GS base of the boot processor to its CPU, has to run before anything uses
+/// per-CPU data.
+pub(crate) fn init_boot_cpu() {
+    let cpu = unsafe { &mut CPUS[BOOT_CPU] };
+    cpu.online.store(true, Ordering::Relaxed);
+    KernelGsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+/// Starts the application processors the MADT lists, through the trampoline in the frame.
+///
+/// They wait for the kernel lock, so they only start running threads once the boot processor
+/// switches to the first one.
+pub(crate) fn init(trampoline_frame: Option<PhysFrame>) {
+    let frame = match trampoline_frame {
+        Some(frame) => frame,
+        None => {
+            debug::log("No memory for the trampoline, running on one CPU");
+            return;
+        }
+    };
+    let madt = match Madt::parse() {
+        Some(madt) if interrupts::is_apic_enabled() => madt,
+        _ => {
+            unsafe {
+                get_kernel_information()
+                    .allocator
+                    .lock()
+                    .deallocate_frame(frame)
+            };
+            debug::log("No APIC, running on one CPU");
+            return;
+        }
+    };
+    let trampoline = match Trampoline::install(frame) {
+        Some(trampoline) => trampoline,
+        None => {
+            debug::log("Failed to install the trampoline, running on one CPU");
+            return;
+        }
+    };
+
+    let boot_cpu = unsafe { &mut CPUS[BOOT_CPU] };
+    boot_cpu.apic_id = apic::local_apic_id();
+    boot_cpu
+        .address_space
+        .store(get_kernel_memory().as_u64(), Ordering::Relaxed);
+    for &apic_id in &madt.local_apic_ids {
+        let index = cpu_count();
+        if index == MAX_CPUS {
+            break;
+        }
+        if apic_id == boot_cpu.apic_id {
+            continue;
+        }
+        if start_application_processor(&trampoline, index, apic_id) {
+            CPU_COUNT.store(index + 1, Ordering::Release);
+        } else {
+            debug::log("An application processor didn't start");
+        }
+    }
+    trampoline.remove();
+    debug::log("Application processors started");
+}
+
+/// Starts the processor through the trampoline with the INIT-SIPI-SIPI sequence, it becomes the
+/// CPU with the index.
+///
+/// Returns if it came online in time.
+fn start_application_processor(trampoline: &Trampoline, index: usize, apic_id: u8) -> bool {
+    let cpu = unsafe { &mut CPUS[index] };
+    let stacks: Option<Vec<KernelStack>> = (0..4).map(|_| KernelStack::allocate()).collect();
+    cpu.stacks = match stacks {
+        Some(stacks) => stacks,
+        None => return false,
+    };
+    cpu.index = index;
+    cpu.apic_id = apic_id;
+    cpu.address_space
+        .store(get_kernel_memory().as_u64(), Ordering::Relaxed);
+    trampoline.prepare(cpu.stacks[0].top(), application_processor_main, index);
+
+    apic::send_init_ipi(apic_id);
+    wait_until(INIT_DELAY_MICROS, || false);
+    apic::send_startup_ipi(apic_id, trampoline.vector());
+    if wait_until(STARTUP_DELAY_MICROS, || cpu.online.load(Ordering::Acquire)) {
+        return true;
+    }
+    apic::send_startup_ipi(apic_id, trampoline.vector());
+    wait_until(STARTUP_TIMEOUT_MICROS, || {
+        cpu.online.load(Ordering::Acquire)
+    })
+}
+
+/// Waits until the condition holds, at most for the microseconds.
+///
+/// Returns if the condition holds.
+fn wait_until(micros: u64, condition: impl Fn() -> bool) -> bool {
+    let deadline = monotonic_nanos() + micros * 1000;
+    while !condition() {
+        if monotonic_nanos() >= deadline {
+            return false;
+        }
+        spin_loop();
+    }
+    true
+}
+
+/// Where application processors continue from the trampoline, with interrupts disabled.
+extern "C" fn application_processor_main(index: usize) -> ! {
+    let cpu = unsafe { &mut CPUS[index] };
+    KernelGsBase::write(VirtAddr::from_ptr(cpu));
+    interrupts::init_application_processor(
+        index,
+        cpu.stacks[0].top(),
+        [
+            cpu.stacks[1].top(),
+            cpu.stacks[2].top(),
+            cpu.stacks[3].top(),
+        ],
+    );
+    setup_syscalls();
+    fpu::enable();
+    tls::enable();
+    cpu.online.store(true, Ordering::Release);
+
+    // The boot processor holds the lock until it switches to the first thread
+    lock_kernel();
+    run_next_thread();
+}
+
+/// Sets the stack system calls on the running CPU are handled on.
+pub(crate) fn set_syscall_stack(stack_top: VirtAddr) {
+    current_cpu()
+        .syscall_stack_top
+        .store(stack_top.as_u64(), Ordering::Relaxed);
+}
+
+/// Checks if another CPU handles system calls on the kernel stack with the top.
+pub(crate) fn is_kernel_stack_in_use(stack_top: VirtAddr) -> bool {
+    other_cpus().any(|cpu| cpu.syscall_stack_top.load(Ordering::Relaxed) == stack_top.as_u64())
+}
+
+/// Records the paging table the running CPU runs its thread with.
+pub(crate) fn set_address_space(cr3: PhysAddr) {
+    current_cpu()
+        .address_space
+        .store(cr3.as_u64(), Ordering::Relaxed);
+}
+
+/// Checks if another CPU runs a thread with the paging table.
+pub(crate) fn is_address_space_in_use(cr3: PhysAddr) -> bool {
+    other_cpus().any(|cpu| cpu.address_space.load(Ordering::Relaxed) == cr3.as_u64())
+}
+
+/// Puts the registers on top of the running CPU's return stack, and returns their address.
+pub(crate) fn push_return_registers(registers_state: RegistersState) -> *const RegistersState {
+    current_cpu()
+        .return_stack
+        .registers_state
+        .write(registers_state)
+}
+
+/// Makes the CPU look for another thread to run, because a thread became ready on it or its
+/// running thread was terminated.
+pub(crate) fn send_reschedule(cpu: usize) {
+    if let Some(cpu) = other_cpus().find(|other_cpu| other_cpu.index == cpu) {
+        apic::send_ipi(cpu.apic_id, InterruptIndex::Reschedule);
+    }
+}
+
+/// Makes the other CPUs that run a thread with the paging table, or all of them for the
+/// kernel's mappings, flush their TLBs. Returns once they did.
+///
+/// The TLB of the running CPU is flushed when it switches paging tables.
+pub(crate) fn shoot_down_tlb(address_space: Option<PhysAddr>) {
+    let is_target = |cpu: &&Cpu| {
+        address_space.map_or(true, |address_space| {
+            cpu.address_space.load(Ordering::Relaxed) == address_space.as_u64()
+        })
+    };
+    for cpu in other_cpus().filter(is_target) {
+        cpu.tlb_flush_requested.store(true, Ordering::Release);
+        apic::send_ipi(cpu.apic_id, InterruptIndex::TlbShootdown);
+    }
+    for cpu in other_cpus().filter(is_target) {
+        while cpu.tlb_flush_requested.load(Ordering::Acquire) {
+            spin_loop();
+        }
+    }
+}
+
+/// Flushes the TLB of the running CPU if another CPU waits for it to.
+pub(crate) fn flush_tlb_if_requested() {
+    let cpu = current_cpu();
+    if cpu.tlb_flush_requested.load(Ordering::Acquire) {
+        tlb::flush_all();
+        cpu.tlb_flush_requested.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,67 @@
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{current_cpu_index, flush_tlb_if_requested};
+
+/// The value of the owner while no CPU holds the kernel lock.
+const NO_OWNER: usize = usize::MAX;
+
+/// The CPU holding the kernel lock, only one CPU at a time uses the scheduler and the processes.
+static OWNER: AtomicUsize = AtomicUsize::new(NO_OWNER);
+/// How many times the owner locked the kernel without unlocking it.
+static DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// Locks the kernel for the running CPU, waiting until no other CPU holds the lock.
+///
+/// A CPU can lock the kernel again while it holds the lock, interrupts handled in the kernel do
+/// that. It's unlocked once `unlock_kernel` was called as many times.
+pub fn lock_kernel() {
+    let cpu = current_cpu_index();
+    if OWNER.load(Ordering::Acquire) != cpu {
+        while OWNER
+            .compare_exchange_weak(NO_OWNER, cpu, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // The owner can be waiting for this CPU to flush its TLB
+            flush_tlb_if_requested();
+            spin_loop();
+        }
+    }
+    DEPTH.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Undoes one `lock_kernel` of the running CPU, `_syscall` calls it right before returning.
+#[no_mangle]
+pub extern "C" fn unlock_kernel() {
+    if DEPTH.fetch_sub(1, Ordering::Relaxed) == 1 {
+        OWNER.store(NO_OWNER, Ordering::Release);
+    }
+}
+
+/// Releases the kernel lock no matter how many times the running CPU locked it.
+///
+/// `switch_to_thread` calls it once it doesn't use the stack it was called on anymore.
+#[no_mangle]
+pub extern "C" fn release_kernel_lock() {
+    DEPTH.store(0, Ordering::Relaxed);
+    OWNER.store(NO_OWNER, Ordering::Release);
+}
+
+/// Returns how many times the running CPU locked the kernel.
+pub(crate) fn kernel_lock_depth() -> usize {
+    DEPTH.load(Ordering::Relaxed)
+}
+
+/// Sets how many times the running CPU locked the kernel, for a thread that continues inside
+/// of the kernel on the CPU that holds the lock now.
+pub(crate) fn set_kernel_lock_depth(depth: usize) {
+    DEPTH.store(depth, Ordering::Relaxed);
+}
+
+/// Performs an action while holding the kernel lock.
+pub(crate) fn with_kernel_lock<V>(action: impl FnOnce() -> V) -> V {
+    lock_kernel();
+    let result = action();
+    unlock_kernel();
+    result
+}
@@ -0,0 +1,229 @@
+use core::arch::global_asm;
+use core::ptr::copy_nonoverlapping;
+
+use x86_64::registers::control::{Cr0, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{
+    mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+use crate::init::get_kernel_information;
+use crate::memory::{get_kernel_memory, MEMORY_MAPPER};
+
+// An application processor starts in real mode at the start of the page of its startup IPI.
+// The trampoline gets it to long mode with the kernel's paging table, then calls the entry point
+// with the CPU's index on the stack prepared for it. The page is identity mapped, so the code
+// keeps running once paging is enabled.
+//
+// The trampoline runs wherever it's copied to, so it patches the addresses of its GDT and of the
+// code it jumps to itself. EBX holds the physical address of the trampoline throughout.
+global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_data",
+    ".global ap_trampoline_end",
+    ".set TRAMPOLINE_GDT, trampoline_gdt - ap_trampoline_start",
+    ".set TRAMPOLINE_GDT_POINTER, trampoline_gdt_pointer - ap_trampoline_start",
+    ".set TRAMPOLINE_PROTECTED_MODE, trampoline_protected_mode - ap_trampoline_start",
+    ".set TRAMPOLINE_PROTECTED_MODE_POINTER, trampoline_protected_mode_pointer - ap_trampoline_start",
+    ".set TRAMPOLINE_LONG_MODE, trampoline_long_mode - ap_trampoline_start",
+    ".set TRAMPOLINE_LONG_MODE_POINTER, trampoline_long_mode_pointer - ap_trampoline_start",
+    ".set TRAMPOLINE_CR0, trampoline_cr0 - ap_trampoline_start",
+    ".set TRAMPOLINE_CR3, trampoline_cr3 - ap_trampoline_start",
+    ".set TRAMPOLINE_CR4, trampoline_cr4 - ap_trampoline_start",
+    ".set TRAMPOLINE_EFER, trampoline_efer - ap_trampoline_start",
+    ".set TRAMPOLINE_STACK_TOP, trampoline_stack_top - ap_trampoline_start",
+    ".set TRAMPOLINE_ENTRY_POINT, trampoline_entry_point - ap_trampoline_start",
+    ".set TRAMPOLINE_CPU, trampoline_cpu - ap_trampoline_start",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "xor ebx, ebx",
+    "mov bx, ax",
+    "shl ebx, 4",
+    "lea eax, [ebx + TRAMPOLINE_GDT]",
+    "mov dword ptr [TRAMPOLINE_GDT_POINTER + 2], eax",
+    "lea eax, [ebx + TRAMPOLINE_PROTECTED_MODE]",
+    "mov dword ptr [TRAMPOLINE_PROTECTED_MODE_POINTER], eax",
+    "lea eax, [ebx + TRAMPOLINE_LONG_MODE]",
+    "mov dword ptr [TRAMPOLINE_LONG_MODE_POINTER], eax",
+    "lgdt [TRAMPOLINE_GDT_POINTER]",
+    "mov eax, cr0",
+    "or eax, 1",
+    "mov cr0, eax",
+    // The operand size prefix makes the far pointer 32 bits long
+    ".byte 0x66",
+    "ljmp dword ptr [TRAMPOLINE_PROTECTED_MODE_POINTER]",
+    ".code32",
+    "trampoline_protected_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    // Long mode is entered with the control registers of the boot processor
+    "mov eax, [ebx + TRAMPOLINE_CR4]",
+    "mov cr4, eax",
+    "mov eax, [ebx + TRAMPOLINE_CR3]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, [ebx + TRAMPOLINE_EFER]",
+    "xor edx, edx",
+    "wrmsr",
+    "mov eax, [ebx + TRAMPOLINE_CR0]",
+    "mov cr0, eax",
+    "ljmp fword ptr [ebx + TRAMPOLINE_LONG_MODE_POINTER]",
+    ".code64",
+    "trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov ebx, ebx",
+    "mov rsp, [rbx + TRAMPOLINE_STACK_TOP]",
+    "mov rdi, [rbx + TRAMPOLINE_CPU]",
+    "call [rbx + TRAMPOLINE_ENTRY_POINT]",
+    "ud2",
+    ".align 8",
+    "trampoline_gdt:",
+    ".quad 0",
+    ".quad 0x00CF9A000000FFFF", // 32-bit code
+    ".quad 0x00CF92000000FFFF", // data
+    ".quad 0x00AF9A000000FFFF", // 64-bit code
+    "trampoline_gdt_pointer:",
+    ".word trampoline_gdt_pointer - trampoline_gdt - 1",
+    ".long 0",
+    "trampoline_protected_mode_pointer:",
+    ".long 0",
+    ".word 0x08",
+    "trampoline_long_mode_pointer:",
+    ".long 0",
+    ".word 0x18",
+    // The layout of `TrampolineData`
+    ".align 8",
+    "ap_trampoline_data:",
+    "trampoline_cr0: .quad 0",
+    "trampoline_cr3: .quad 0",
+    "trampoline_cr4: .quad 0",
+    "trampoline_efer: .quad 0",
+    "trampoline_stack_top: .quad 0",
+    "trampoline_entry_point: .quad 0",
+    "trampoline_cpu: .quad 0",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// The values an application processor starts with, at the end of the trampoline.
+#[repr(C)]
+struct TrampolineData {
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack_top: u64,
+    entry_point: u64,
+    cpu: u64,
+}
+
+/// The trampoline copied to a frame application processors can start in.
+pub(super) struct Trampoline {
+    frame: PhysFrame<Size4KiB>,
+    /// If the identity mapping of the frame was added for the trampoline.
+    mapped: bool,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to the frame and identity maps it in the kernel's paging table.
+    ///
+    /// The frame has to be below 1 MiB, the kernel's paging table below 4 GiB and active.
+    pub(super) fn install(frame: PhysFrame<Size4KiB>) -> Option<Trampoline> {
+        // Protected mode can only load a 32-bit CR3
+        if get_kernel_memory().as_u64() > u32::MAX as u64 {
+            return None;
+        }
+        let kernel_info = get_kernel_information();
+        let page =
+            Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+        let mapped = {
+            let mut allocator = kernel_info.allocator.lock();
+            let mut mapper = MEMORY_MAPPER.lock();
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            match unsafe { mapper.as_mut()?.map_to(page, frame, flags, &mut *allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(MapToError::PageAlreadyMapped(mapped_frame)) if mapped_frame == frame => false,
+                Err(_) => return None,
+            }
+        };
+
+        unsafe {
+            let start = &ap_trampoline_start as *const u8;
+            let size = &ap_trampoline_end as *const u8 as usize - start as usize;
+            let target =
+                (kernel_info.physical_memory_offset + frame.start_address().as_u64()) as *mut u8;
+            copy_nonoverlapping(start, target, size);
+        }
+        Some(Trampoline { frame, mapped })
+    }
+
+    /// Returns the vector of the startup IPI that starts a processor in the trampoline.
+    pub(super) fn vector(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Sets up the trampoline for the next application processor, it calls the entry point with
+    /// the CPU's index on the stack.
+    pub(super) fn prepare(
+        &self,
+        stack_top: VirtAddr,
+        entry_point: extern "C" fn(usize) -> !,
+        cpu: usize,
+    ) {
+        let data = TrampolineData {
+            cr0: Cr0::read_raw(),
+            cr3: get_kernel_memory().as_u64(),
+            // PCIDs can only be enabled in long mode
+            cr4: (Cr4::read() - Cr4Flags::PCID).bits(),
+            // Long mode becomes active once paging is enabled
+            efer: (Efer::read() - EferFlags::LONG_MODE_ACTIVE).bits(),
+            stack_top: stack_top.as_u64(),
+            entry_point: entry_point as usize as u64,
+            cpu: cpu as u64,
+        };
+        unsafe {
+            let offset = &ap_trampoline_data as *const u8 as usize
+                - &ap_trampoline_start as *const u8 as usize;
+            let address = self.frame.start_address() + offset;
+            let physical_memory_offset = get_kernel_information().physical_memory_offset;
+            ((physical_memory_offset + address.as_u64()) as *mut TrampolineData)
+                .write_volatile(data);
+        }
+    }
+
+    /// Removes the identity mapping of the trampoline and frees its frame.
+    pub(super) fn remove(self) {
+        let kernel_info = get_kernel_information();
+        if self.mapped {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                self.frame.start_address().as_u64(),
+            ));
+            if let Some(mapper) = MEMORY_MAPPER.lock().as_mut() {
+                if let Ok((_, flush)) = mapper.unmap(page) {
+                    flush.flush();
+                }
+            }
+        }
+        unsafe { kernel_info.allocator.lock().deallocate_frame(self.frame) };
+    }
+}
@@ -0,0 +1,76 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+/// A spinlock that disables interrupts on the running CPU while it's held.
+///
+/// Interrupt handlers can lock it too, they can't interrupt the code holding it on the same
+/// CPU, and another CPU only waits until it's unlocked. Locking it again on the CPU holding it
+/// deadlocks, so the order the locks are taken in has to be kept, see `processes`.
+pub struct IrqMutex<T> {
+    inner: Mutex<T>,
+}
+
+/// The guard of a locked `IrqMutex`, interrupts are enabled again once it's dropped if they
+/// were enabled before it was locked.
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqMutex {
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// Disables interrupts and waits until the value is unlocked.
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_were_enabled,
+        }
+    }
+}
+
+impl<T: Default> Default for IrqMutex<T> {
+    fn default() -> Self {
+        IrqMutex::new(T::default())
+    }
+}
+
+impl<T: core::fmt::Debug> core::fmt::Debug for IrqMutex<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl<'a, T> Deref for IrqMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqMutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Unlocked before interrupts are enabled, so a handler never finds it locked by the
+        // code it interrupted
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
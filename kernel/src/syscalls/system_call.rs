@@ -1,20 +1,32 @@
-use alloc::rc::Rc;
+use alloc::sync::Arc;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::VirtAddr;
 
-use crate::processes::thread::Thread;
+use crate::processes::dispatcher::{switch_to_next_thread, switch_to_thread};
+use crate::processes::signal::has_deliverable_signal;
+use crate::processes::thread::{Thread, ThreadState};
+use crate::processes::RegistersState;
+use crate::smp::lock_kernel;
+use crate::sync::IrqMutex;
 use crate::{debug, memory::with_kernel_memory, processes::get_scheduler};
 
 use crate::interrupts::gdt::GDT;
 use core::arch::asm;
-use core::cell::RefCell;
 use internal_utils::{mov_all, push_all};
 
-pub type SysCallHandlerFunc = extern "C" fn(u64, u64, Rc<RefCell<Thread>>) -> u64;
+/// The value system calls return when they fail.
+pub const SYSCALL_ERROR: u64 = u64::MAX;
+
+pub type SysCallHandlerFunc = extern "C" fn(u64, u64, u64, Arc<IrqMutex<Thread>>) -> u64;
 
 /// A system call handler that panics.
-extern "C" fn fail_syscall(_arg1: u64, _arg2: u64, calling_thread: Rc<RefCell<Thread>>) -> u64 {
+extern "C" fn fail_syscall(
+    _arg1: u64,
+    _arg2: u64,
+    _arg3: u64,
+    calling_thread: Arc<IrqMutex<Thread>>,
+) -> u64 {
     panic!("NO SYSCALL DEFINED");
 }
 
@@ -43,6 +55,8 @@ pub(crate) fn setup_syscalls() {
     let new_efer_flags = {
         let mut flags = Efer::read();
         flags.set(EferFlags::SYSTEM_CALL_EXTENSIONS, true);
+        // User-mode pages are mapped without execute permissions unless they contain code.
+        flags.set(EferFlags::NO_EXECUTE_ENABLE, true);
         flags
     };
     unsafe {
@@ -59,14 +73,15 @@ pub fn register_syscall(syscall_number: u16, handler: SysCallHandlerFunc) {
 
 /// Handles a system call.
 /// On entry to this function:
+/// - the system call name and arguments are stored in RDI, RSI, RDX and R8
 /// - the instruction pointer is stored in RCX
 /// - the flags are stored in R11
 /// - the stack pointer is still targeting the user mode stack
 ///
 /// To properly handle this, we need to:
 /// 1. save the user mode stack pointer
-/// 2. set the syscall stack pointer
-/// 3. save all the registers we need to preserve on the stack
+/// 2. set the syscall stack pointer, the first field of the CPU the kernel GS base points to
+/// 3. save all the registers we need to preserve on the stack, in the layout of `RegistersState`
 /// 4. do our thing with the values we got from the user
 /// 5. restore the registers from the stack
 /// 6. restore the user mode stack pointer
@@ -76,30 +91,40 @@ pub fn register_syscall(syscall_number: u16, handler: SysCallHandlerFunc) {
 unsafe extern "C" fn _syscall() -> ! {
     asm!(
         "cli",
+        "swapgs",
         "mov r10, rsp",
-        "mov rsp, 0x007F80014000", // User stack saved in R10, start of kernel stack loaded
+        "mov rsp, gs:[0]", // User stack saved in R10, kernel stack loaded
+        "swapgs",
+        "push 0",   // stack segment, set on return
+        "push r10", // process stack pointer
+        "push r11", // rflags
+        "push 0",   // code segment, set on return
+        "push rcx", // instruction address to return to
         push_all!(),
-        "mov r9, rsp",
-        "push r9",
+        "mov r12, rsp",   // The register state, R12 is preserved by the calls
+        "  mov rcx, r8",  // The third argument, RCX is already saved
+        "  mov r8, r12",  // The register state
         "  call handler", // Return value is in RAX
         "  push rax",
         "    call get_code_selector",
         "    push rax",
         "      call get_data_selector",
-        "    pop rbx",
-        "    mov rcx, rax", // struct is in RBX+RCX now
-        "  pop rax",        // We get the syscall value back
-        "pop r9",           // We get the register state value back
+        "      push rax",
+        "        call unlock_kernel", // Nothing is used after this but the stack
+        "      pop rcx",
+        "    pop rbx", // struct is in RBX+RCX now
+        "  pop rax",   // We get the syscall value back
+        "mov r9, r12", // We get the register state back
         // Preparing iretq
-        "push rcx",           // data selector
-        "push [r9 + 40]",     // process stack pointer
-        "mov r11, [r9 + 32]", // rflags
+        "push rcx",            // data selector
+        "push [r9 + 144]",     // process stack pointer
+        "mov r11, [r9 + 136]", // rflags
         "or r11, 0x200",
         "and r11, 0xffffffffffffbfff",
-        "push r11",       // rflags
-        "push rbx",       // code selector
-        "push [r9 + 96]", // instruction address to return to
-        "push rax",       // We want to keep the RAX
+        "push r11",        // rflags
+        "push rbx",        // code selector
+        "push [r9 + 120]", // instruction address to return to
+        "push rax",        // We want to keep the RAX
         mov_all!(),
         "pop rax",
         "iretq",
@@ -108,11 +133,43 @@ unsafe extern "C" fn _syscall() -> ! {
 }
 
 #[no_mangle]
-extern "C" fn handler(name: u64, arg1: u64, arg2: u64) -> u64 {
+extern "C" fn handler(
+    name: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    registers_state: *const RegistersState,
+) -> u64 {
+    let registers_state = unsafe { *registers_state };
+    // Unlocked by `_syscall` right before returning
+    lock_kernel();
     // This block executes after saving the user state and before returning back
     with_kernel_memory(|| {
-        let thread = get_scheduler().running_thread.clone().unwrap();
-        SYSCALLS.lock()[name as u16 as usize](arg1, arg2, thread)
+        // Another CPU can terminate the thread while it's entering the kernel
+        let running_thread = get_scheduler()
+            .running_thread()
+            .filter(|thread| !matches!(thread.lock().state, ThreadState::Terminated));
+        let thread = match running_thread {
+            Some(thread) => thread,
+            None => switch_to_next_thread(),
+        };
+        // The thread continues from here if the system call switches to another thread
+        thread.lock().save_registers(registers_state);
+        // The lock is released first, system calls that switch to another thread never return
+        let syscall = SYSCALLS.lock()[name as u16 as usize];
+        let result = syscall(arg1, arg2, arg3, thread);
+
+        // Signals sent during the system call are handled before returning to user mode
+        let running_thread = get_scheduler().running_thread();
+        let interrupted_thread = running_thread.filter(|thread| {
+            let is_terminated = matches!(thread.lock().state, ThreadState::Terminated);
+            !is_terminated && has_deliverable_signal(thread)
+        });
+        if let Some(thread) = interrupted_thread {
+            thread.lock().registers_state.rax = result;
+            switch_to_thread(thread);
+        }
+        result
     })
 }
 
@@ -121,10 +178,9 @@ extern "C" fn handler(name: u64, arg1: u64, arg2: u64) -> u64 {
 #[no_mangle]
 extern "C" fn get_code_selector() -> u64 {
     with_kernel_memory(|| {
-        let thread = get_scheduler().running_thread.clone().unwrap();
-        let thread = thread.as_ref().borrow();
-        let process = thread.process.as_ref().borrow();
-        if process.kernel_process {
+        let thread = get_scheduler().running_thread().unwrap();
+        let process = thread.lock().process.clone();
+        if process.lock().kernel_process {
             (GDT.1.kernel_code_selector.index() * 8) as u64
         } else {
             ((GDT.1.user_code_selector.index() * 8) | 3) as u64
@@ -135,10 +191,9 @@ extern "C" fn get_code_selector() -> u64 {
 #[no_mangle]
 extern "C" fn get_data_selector() -> u64 {
     with_kernel_memory(|| {
-        let thread = get_scheduler().running_thread.clone().unwrap();
-        let thread = thread.as_ref().borrow();
-        let process = thread.process.as_ref().borrow();
-        if process.kernel_process {
+        let thread = get_scheduler().running_thread().unwrap();
+        let process = thread.lock().process.clone();
+        if process.lock().kernel_process {
             (GDT.1.kernel_data_selector.index() * 8) as u64
         } else {
             ((GDT.1.user_data_selector.index() * 8) | 3) as u64
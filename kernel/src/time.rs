@@ -0,0 +1,206 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use internal_utils::get_current_tick;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+use x86_64::structures::paging::{Page, PhysFrame};
+use x86_64::VirtAddr;
+
+use crate::acpi::hpet::Hpet;
+use crate::interrupts;
+use crate::memory::layout::HPET_ADDRESS;
+use crate::memory::map_device_memory;
+
+/// The frequency of the oscillator driving the PIT, in Hz.
+pub const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+/// The largest divisor the PIT counts with, it's the power-on default.
+const PIT_MAX_DIVISOR: u64 = 65_536;
+/// The frequency of the timer interrupts the kernel sets up at boot, in Hz.
+pub const DEFAULT_TIMER_FREQUENCY: u64 = 1000;
+/// How long the TSC is measured for against the HPET or PIT, in milliseconds.
+const CALIBRATION_MILLIS: u64 = 50;
+/// The longest period of the HPET's main counter the specification allows, 100 ns.
+const HPET_MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+
+const HPET_REGISTER_CAPABILITIES: u64 = 0x00;
+const HPET_REGISTER_CONFIGURATION: u64 = 0x10;
+const HPET_REGISTER_MAIN_COUNTER: u64 = 0xF0;
+/// Capabilities bit of HPETs with a 64-bit main counter.
+const HPET_COUNTER_64_BIT: u64 = 1 << 13;
+/// Configuration bit starting the main counter.
+const HPET_ENABLED: u64 = 1;
+
+/// The number of timer interrupts since boot.
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+/// The time between two timer interrupts in nanoseconds, the PIT's power-on default at first.
+static TIMER_PERIOD_NANOS: AtomicU64 =
+    AtomicU64::new(PIT_MAX_DIVISOR * 1_000_000_000 / PIT_BASE_FREQUENCY);
+/// The frequency of the TSC in Hz, 0 until it's calibrated.
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// The TSC value monotonic time is counted from.
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+/// The Unix time in nanoseconds at monotonic time 0, 0 until a clock driver sets it.
+static WALL_CLOCK_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a timer interrupt, called once per interrupt by the timer handler.
+pub(crate) fn tick() {
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the number of timer interrupts since boot.
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
+}
+
+/// Returns the time between two timer interrupts in nanoseconds.
+pub fn timer_period_nanos() -> u64 {
+    TIMER_PERIOD_NANOS.load(Ordering::Relaxed)
+}
+
+/// Makes the timer interrupt at about the frequency in Hz, through the local APIC timer if the
+/// APIC is used and through the PIT's channel 0 otherwise.
+///
+/// The PIT can't go below about 19 Hz, the frequency is rounded to what the timer can do.
+pub fn set_timer_frequency(frequency: u64) {
+    let frequency = frequency.clamp(1, PIT_BASE_FREQUENCY);
+    if interrupts::is_apic_enabled() {
+        let period_nanos = 1_000_000_000 / frequency;
+        TIMER_PERIOD_NANOS.store(period_nanos, Ordering::Relaxed);
+        interrupts::set_apic_timer_period(period_nanos);
+        return;
+    }
+    let divisor = (PIT_BASE_FREQUENCY / frequency).clamp(1, PIT_MAX_DIVISOR);
+    TIMER_PERIOD_NANOS.store(
+        divisor * 1_000_000_000 / PIT_BASE_FREQUENCY,
+        Ordering::Relaxed,
+    );
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    without_interrupts(|| unsafe {
+        // Channel 0, low and high byte, square wave generator, binary
+        command.write(0b0011_0110);
+        // A divisor of 65536 is written as 0
+        channel_0.write(divisor as u8);
+        channel_0.write((divisor >> 8) as u8);
+    });
+}
+
+/// Measures the frequency of the TSC by counting its cycles over a known interval, timed by the
+/// HPET if the ACPI tables describe one and by the PIT's channel 2 otherwise. Monotonic time
+/// starts at 0 afterwards.
+///
+/// Has to run with interrupts disabled, so nothing stretches the measured interval.
+pub(crate) fn calibrate_tsc() {
+    let (start, end) = measure_tsc_with_hpet().unwrap_or_else(measure_tsc_with_pit);
+    TSC_FREQUENCY.store((end - start) * 1000 / CALIBRATION_MILLIS, Ordering::Relaxed);
+    TSC_START.store(end, Ordering::Relaxed);
+}
+
+/// Reads the TSC before and after the HPET's main counter advances by the calibration interval.
+///
+/// Returns `None` if there is no usable HPET.
+fn measure_tsc_with_hpet() -> Option<(u64, u64)> {
+    let hpet = Hpet::parse()?;
+    let address = VirtAddr::new(HPET_ADDRESS);
+    map_device_memory(
+        Page::containing_address(address),
+        PhysFrame::containing_address(hpet.base_address),
+    )?;
+    // The registers don't have to be at the start of the page
+    let registers = address + (hpet.base_address.as_u64() & 0xFFF);
+    let read = |offset: u64| unsafe { read_volatile((registers + offset).as_ptr::<u64>()) };
+    let write = |offset: u64, value: u64| unsafe {
+        write_volatile((registers + offset).as_mut_ptr(), value)
+    };
+
+    let capabilities = read(HPET_REGISTER_CAPABILITIES);
+    let period_femtos = capabilities >> 32;
+    if period_femtos == 0 || period_femtos > HPET_MAX_PERIOD_FEMTOS {
+        return None;
+    }
+    let count = CALIBRATION_MILLIS * 1_000_000_000_000 / period_femtos;
+    let counter_mask = if capabilities & HPET_COUNTER_64_BIT != 0 {
+        u64::MAX
+    } else {
+        u32::MAX as u64
+    };
+
+    let configuration = read(HPET_REGISTER_CONFIGURATION);
+    write(HPET_REGISTER_CONFIGURATION, configuration | HPET_ENABLED);
+    let counter_start = read(HPET_REGISTER_MAIN_COUNTER);
+    let start = get_current_tick();
+    while read(HPET_REGISTER_MAIN_COUNTER).wrapping_sub(counter_start) & counter_mask < count {}
+    let end = get_current_tick();
+    write(HPET_REGISTER_CONFIGURATION, configuration);
+    Some((start, end))
+}
+
+/// Reads the TSC before and after the PIT's channel 2 counts down the calibration interval.
+fn measure_tsc_with_pit() -> (u64, u64) {
+    let count = PIT_BASE_FREQUENCY * CALIBRATION_MILLIS / 1000;
+    let mut gate: Port<u8> = Port::new(0x61);
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_2: Port<u8> = Port::new(0x42);
+    unsafe {
+        // Disabling the speaker and the gate of channel 2, so it doesn't start counting yet
+        let gate_value = gate.read() & !0b11;
+        gate.write(gate_value);
+        // Channel 2, low and high byte, interrupt on terminal count, binary
+        command.write(0b1011_0000);
+        channel_2.write(count as u8);
+        channel_2.write((count >> 8) as u8);
+
+        gate.write(gate_value | 1);
+        let start = get_current_tick();
+        // The output of channel 2 goes high once the count reaches 0
+        while gate.read() & 0b10_0000 == 0 {}
+        let end = get_current_tick();
+        gate.write(gate_value);
+        (start, end)
+    }
+}
+
+/// Returns the frequency of the TSC in Hz, 0 if it's not calibrated.
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Converts a number of TSC cycles, like the tick counts of threads, to nanoseconds.
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    match tsc_frequency() {
+        0 => 0,
+        frequency => (cycles as u128 * 1_000_000_000 / frequency as u128) as u64,
+    }
+}
+
+/// Returns the time since the TSC was calibrated in nanoseconds, it never goes backwards.
+///
+/// Before calibration the time is counted in timer interrupts since boot.
+pub fn monotonic_nanos() -> u64 {
+    if tsc_frequency() == 0 {
+        return timer_ticks() * timer_period_nanos();
+    }
+    cycles_to_nanos(get_current_tick().saturating_sub(TSC_START.load(Ordering::Relaxed)))
+}
+
+/// Returns the monotonic time in milliseconds.
+pub fn uptime_millis() -> u64 {
+    monotonic_nanos() / 1_000_000
+}
+
+/// Sets the wall-clock time to the Unix time in nanoseconds, it advances with the monotonic
+/// clock afterwards.
+pub fn set_wall_clock(unix_nanos: u64) {
+    WALL_CLOCK_OFFSET.store(
+        unix_nanos.saturating_sub(monotonic_nanos()),
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns the Unix time in nanoseconds, `None` if no clock driver set the wall-clock time.
+pub fn wall_clock_nanos() -> Option<u64> {
+    match WALL_CLOCK_OFFSET.load(Ordering::Relaxed) {
+        0 => None,
+        offset => Some(offset + monotonic_nanos()),
+    }
+}
@@ -1,18 +1,51 @@
 #![no_std] // no standard library
 #![no_main]
 #![allow(incomplete_features)]
-#![feature(generic_const_exprs, core_intrinsics, alloc_error_handler)]
+#![feature(
+    generic_const_exprs,
+    core_intrinsics,
+    alloc_error_handler,
+    asm_const,
+    naked_functions
+)]
 
 use core::arch::asm;
 
 use crate::syscall_name::SysCallName;
 extern crate alloc;
 
+pub mod memory_utils;
+pub mod process_utils;
+pub mod signal_utils;
+pub mod sync_utils;
 pub mod syscall_name;
+pub mod system_utils;
 pub mod thread_utils;
+pub mod time_utils;
+pub mod tls_utils;
 
 pub fn __initialize_syscalls() {
     use kernel::syscalls::system_call::register_syscall;
+    register_syscall(
+        SysCallName::ProcessFork as u16,
+        process_utils::handler_process_fork,
+    );
+    register_syscall(
+        SysCallName::ProcessExec as u16,
+        process_utils::handler_process_exec,
+    );
+    register_syscall(
+        SysCallName::ProcessWait as u16,
+        process_utils::handler_process_wait,
+    );
+    register_syscall(
+        SysCallName::ProcessKill as u16,
+        process_utils::handler_process_kill,
+    );
+    register_syscall(
+        SysCallName::ProcessSetForeground as u16,
+        process_utils::handler_process_set_foreground,
+    );
     register_syscall(
         SysCallName::ThreadExit as u16,
         thread_utils::handler_thread_exit,
@@ -25,10 +58,79 @@ pub fn __initialize_syscalls() {
         SysCallName::ThreadSleep as u16,
         thread_utils::handler_thread_sleep,
     );
+    register_syscall(
+        SysCallName::ThreadJoin as u16,
+        thread_utils::handler_thread_join,
+    );
+    register_syscall(
+        SysCallName::ThreadSpawn as u16,
+        thread_utils::handler_thread_spawn,
+    );
+    register_syscall(
+        SysCallName::ThreadSetPriority as u16,
+        thread_utils::handler_thread_set_priority,
+    );
+    register_syscall(
+        SysCallName::ThreadDetach as u16,
+        thread_utils::handler_thread_detach,
+    );
+    register_syscall(
+        SysCallName::ThreadSetTls as u16,
+        tls_utils::handler_thread_set_tls,
+    );
+    register_syscall(
+        SysCallName::MemoryMap as u16,
+        memory_utils::handler_memory_map,
+    );
+    register_syscall(
+        SysCallName::MemoryUnmap as u16,
+        memory_utils::handler_memory_unmap,
+    );
+    register_syscall(
+        SysCallName::MemoryProtect as u16,
+        memory_utils::handler_memory_protect,
+    );
+    register_syscall(
+        SysCallName::ProgramBreak as u16,
+        memory_utils::handler_program_break,
+    );
+    register_syscall(
+        SysCallName::ClockGetTime as u16,
+        time_utils::handler_clock_get_time,
+    );
+    register_syscall(SysCallName::Time as u16, time_utils::handler_time);
+    register_syscall(
+        SysCallName::SystemShutdown as u16,
+        system_utils::handler_system_shutdown,
+    );
+    register_syscall(
+        SysCallName::SystemReboot as u16,
+        system_utils::handler_system_reboot,
+    );
+    register_syscall(
+        SysCallName::SignalAction as u16,
+        signal_utils::handler_signal_action,
+    );
+    register_syscall(
+        SysCallName::SignalMask as u16,
+        signal_utils::handler_signal_mask,
+    );
+    register_syscall(
+        SysCallName::SignalReturn as u16,
+        signal_utils::handler_signal_return,
+    );
+    register_syscall(
+        SysCallName::FutexWait as u16,
+        sync_utils::handler_futex_wait,
+    );
+    register_syscall(
+        SysCallName::FutexWake as u16,
+        sync_utils::handler_futex_wake,
+    );
 }
 
 #[inline(always)]
-pub(crate) fn syscall(name: SysCallName, arg1: u64, arg2: u64) -> u64 {
+pub(crate) fn syscall(name: SysCallName, arg1: u64, arg2: u64, arg3: u64) -> u64 {
     unsafe {
         let result: u64;
         asm!(
@@ -38,6 +140,7 @@ pub(crate) fn syscall(name: SysCallName, arg1: u64, arg2: u64) -> u64 {
             in("rdi")(name as u64),
             in("rsi")(arg1),
             in("rdx")(arg2),
+            in("r8")(arg3),
             out("rax")(result)
         );
         result
@@ -0,0 +1,120 @@
+use alloc::sync::Arc;
+use bitflags::bitflags;
+use kernel::processes::memory_area::MemoryPermissions;
+use kernel::processes::thread::Thread;
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use x86_64::VirtAddr;
+
+use crate::syscall_name::SysCallName;
+
+bitflags! {
+    /// What a process is allowed to do with its memory.
+    pub struct Protection: u64 {
+        /// Memory is always readable, the flag exists for familiarity.
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXECUTE = 1 << 2;
+    }
+}
+
+impl From<Protection> for MemoryPermissions {
+    fn from(protection: Protection) -> Self {
+        MemoryPermissions {
+            writable: protection.contains(Protection::WRITE),
+            executable: protection.contains(Protection::EXECUTE),
+        }
+    }
+}
+
+pub(crate) extern "C" fn handler_memory_map(
+    hint: u64,
+    size: u64,
+    protection: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    let hint = VirtAddr::try_new(hint).ok().filter(|hint| !hint.is_null());
+    let protection = Protection::from_bits_truncate(protection);
+    process
+        .map_memory(hint, size, protection.into())
+        .map_or(SYSCALL_ERROR, |address| address.as_u64())
+}
+
+pub(crate) extern "C" fn handler_memory_unmap(
+    address: u64,
+    size: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    VirtAddr::try_new(address)
+        .ok()
+        .and_then(|address| process.unmap_memory(address, size))
+        .map_or(SYSCALL_ERROR, |_| 0)
+}
+
+pub(crate) extern "C" fn handler_memory_protect(
+    address: u64,
+    size: u64,
+    protection: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    let protection = Protection::from_bits_truncate(protection);
+    VirtAddr::try_new(address)
+        .ok()
+        .and_then(|address| process.protect_memory(address, size, protection.into()))
+        .map_or(SYSCALL_ERROR, |_| 0)
+}
+
+pub(crate) extern "C" fn handler_program_break(
+    program_break: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    match VirtAddr::try_new(program_break) {
+        Ok(program_break) if !program_break.is_null() => {
+            process.set_program_break(program_break).as_u64()
+        }
+        _ => process.program_break.as_u64(),
+    }
+}
+
+/// Reserves memory of the given size, which is mapped when it's first accessed.
+///
+/// The memory is placed at the hint if possible.
+pub fn mmap(hint: Option<*mut u8>, size: u64, protection: Protection) -> Option<*mut u8> {
+    let hint = hint.map_or(0, |hint| hint as u64);
+    let address = crate::syscall(SysCallName::MemoryMap, hint, size, protection.bits());
+    (address != SYSCALL_ERROR).then(|| address as *mut u8)
+}
+
+/// Releases the memory range, the memory is given back to the system.
+pub fn munmap(address: *mut u8, size: u64) -> Option<()> {
+    let result = crate::syscall(SysCallName::MemoryUnmap, address as u64, size, 0);
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// Changes what the process is allowed to do with the memory range.
+pub fn mprotect(address: *mut u8, size: u64, protection: Protection) -> Option<()> {
+    let result = crate::syscall(
+        SysCallName::MemoryProtect,
+        address as u64,
+        size,
+        protection.bits(),
+    );
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// Moves the end of the heap, returning the new end. Passing `None` just returns the current end.
+pub fn brk(program_break: Option<*mut u8>) -> *mut u8 {
+    let program_break = program_break.map_or(0, |program_break| program_break as u64);
+    crate::syscall(SysCallName::ProgramBreak, program_break, 0, 0) as *mut u8
+}
@@ -0,0 +1,322 @@
+use core::mem::size_of;
+use core::ptr::read_unaligned;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use internal_utils::constants::{KIB, MIB};
+use kernel::processes::dispatcher::{block_running_thread, switch_to_thread};
+use kernel::processes::process::{Process, EXIT_STATUS_FAULT, EXIT_STATUS_SIGNAL};
+use kernel::processes::signal::{can_send_signal, send_signal, set_foreground_process, Signal};
+use kernel::processes::thread::{Thread, ThreadState, WaitTarget};
+use kernel::processes::{exec_process, fork_process, get_scheduler};
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use x86_64::VirtAddr;
+
+use crate::syscall_name::SysCallName;
+
+/// The largest executable `exec` can load. It's copied to the kernel heap while it's loaded, so
+/// it has to stay a small part of the heap.
+const EXEC_IMAGE_MAX_SIZE: u64 = MIB;
+/// The most memory the arguments and environment variables passed to `exec` can take up.
+const EXEC_ARGUMENTS_MAX_SIZE: u64 = 128 * KIB;
+
+/// How a process stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The last thread of the process exited with the exit code, without its two highest bits.
+    Exited(u64),
+    /// The process was terminated because of the CPU exception with the vector.
+    Faulted(u64),
+    /// The process was terminated by the signal with the number.
+    Signaled(u64),
+}
+
+impl From<u64> for ExitStatus {
+    fn from(status: u64) -> Self {
+        if status & EXIT_STATUS_FAULT != 0 {
+            ExitStatus::Faulted(status & !EXIT_STATUS_FAULT)
+        } else if status & EXIT_STATUS_SIGNAL != 0 {
+            ExitStatus::Signaled(status & !EXIT_STATUS_SIGNAL)
+        } else {
+            ExitStatus::Exited(status)
+        }
+    }
+}
+
+/// The parameters of the `exec` system call.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ExecRequest {
+    /// The ELF64 executable to run.
+    pub image: *const u8,
+    /// The size of the executable in bytes.
+    pub image_size: u64,
+    /// A null-terminated array of null-terminated strings, the arguments of the program.
+    pub arguments: *const *const u8,
+    /// A null-terminated array of null-terminated strings, the environment variables of the program.
+    pub environment: *const *const u8,
+}
+
+pub(crate) extern "C" fn handler_process_fork(
+    _: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    fork_process(caller).map_or(SYSCALL_ERROR, |process| process.lock().id)
+}
+
+pub(crate) extern "C" fn handler_process_exec(
+    request: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let request = {
+        let process = caller.lock().process.clone();
+        let process = process.lock();
+        read_exec_request(&process, request)
+    };
+    let loaded = request.and_then(|request| {
+        exec_process(
+            caller.clone(),
+            &request.image,
+            &request.arguments,
+            &request.environment,
+        )
+        .ok()
+    });
+    if loaded.is_none() {
+        return SYSCALL_ERROR;
+    }
+    switch_to_thread(caller);
+}
+
+/// An `exec` request copied from the memory of the calling process.
+struct CopiedExecRequest {
+    image: Vec<u8>,
+    arguments: Vec<Vec<u8>>,
+    environment: Vec<Vec<u8>>,
+}
+
+/// Copies the executable, the arguments and the environment variables of an `exec` request
+/// from the memory of the process.
+fn read_exec_request(process: &Process, request: u64) -> Option<CopiedExecRequest> {
+    // Kernel processes share the address space of the kernel, it can't be replaced
+    if process.kernel_process {
+        return None;
+    }
+    let request = process.read_memory(
+        VirtAddr::try_new(request).ok()?,
+        size_of::<ExecRequest>() as u64,
+    )?;
+    let request = unsafe { read_unaligned(request.as_ptr() as *const ExecRequest) };
+    if request.image_size > EXEC_IMAGE_MAX_SIZE {
+        return None;
+    }
+    let image = process.read_memory(
+        VirtAddr::try_new(request.image as u64).ok()?,
+        request.image_size,
+    )?;
+
+    let mut remaining_size = EXEC_ARGUMENTS_MAX_SIZE;
+    let arguments = read_string_array(process, request.arguments as u64, &mut remaining_size)?;
+    let environment = read_string_array(process, request.environment as u64, &mut remaining_size)?;
+    Some(CopiedExecRequest {
+        image,
+        arguments,
+        environment,
+    })
+}
+
+/// Copies a null-terminated array of null-terminated strings from the memory of the process.
+///
+/// A null array is treated as an empty one.
+fn read_string_array(
+    process: &Process,
+    address: u64,
+    remaining_size: &mut u64,
+) -> Option<Vec<Vec<u8>>> {
+    let mut strings = Vec::new();
+    if address == 0 {
+        return Some(strings);
+    }
+    let mut address = VirtAddr::try_new(address).ok()?;
+    loop {
+        let pointer = process.read_memory(address, 8)?;
+        let pointer = u64::from_ne_bytes(pointer.try_into().ok()?);
+        if pointer == 0 {
+            return Some(strings);
+        }
+        let string = process.read_string(VirtAddr::try_new(pointer).ok()?, *remaining_size)?;
+        // Every string takes up its pointer and its terminator too
+        *remaining_size = remaining_size.checked_sub(string.len() as u64 + 9)?;
+        strings.push(string);
+        address += 8u64;
+    }
+}
+
+pub(crate) extern "C" fn handler_process_wait(
+    id: u64,
+    status_pointer: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let status_pointer = match VirtAddr::try_new(status_pointer) {
+        Ok(status_pointer) => status_pointer,
+        Err(_) => return SYSCALL_ERROR,
+    };
+    // Checking the pointer first, so the child isn't reaped if it's invalid
+    let is_writable = process
+        .lock()
+        .write_memory(status_pointer, &0u64.to_ne_bytes())
+        .is_some();
+    if !is_writable {
+        return SYSCALL_ERROR;
+    }
+
+    let parent_id = process.lock().id;
+    let mut scheduler = get_scheduler();
+    let zombie = scheduler.process_table_mut().take_zombie(id, parent_id);
+    let status = match zombie {
+        Some(zombie) => {
+            let exit_reason = zombie.lock().exit_reason;
+            match exit_reason {
+                Some(exit_reason) => exit_reason.status(),
+                None => return SYSCALL_ERROR,
+            }
+        }
+        None => {
+            let is_child = scheduler
+                .process_table()
+                .get(id)
+                .map_or(false, |process| process.lock().parent_id == Some(parent_id));
+            // Blocking the thread locks the scheduler again
+            drop(scheduler);
+            if !is_child {
+                return SYSCALL_ERROR;
+            }
+            // The thread is woken up with the exit status, which never has both of its highest
+            // bits set like `SYSCALL_ERROR`, or with the error by a signal
+            let status = block_running_thread(ThreadState::Waiting(WaitTarget::Process(id)));
+            if status == SYSCALL_ERROR {
+                return SYSCALL_ERROR;
+            }
+            status
+        }
+    };
+    let written = process
+        .lock()
+        .write_memory(status_pointer, &status.to_ne_bytes());
+    written.map_or(SYSCALL_ERROR, |_| 0)
+}
+
+pub(crate) extern "C" fn handler_process_kill(
+    id: u64,
+    signal: u64,
+    _: u64,
+    _caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let signal = match Signal::try_from(signal) {
+        Ok(signal) => signal,
+        Err(_) => return SYSCALL_ERROR,
+    };
+    let mut scheduler = get_scheduler();
+    let process = scheduler.process_table().get(id);
+    match process {
+        Some(process) => {
+            let can_send = can_send_signal(&process.lock(), signal);
+            if !can_send {
+                return SYSCALL_ERROR;
+            }
+            // The calling process handles the signal before the system call returns
+            send_signal(&mut scheduler, &process, signal);
+            0
+        }
+        None => SYSCALL_ERROR,
+    }
+}
+
+pub(crate) extern "C" fn handler_process_set_foreground(
+    id: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let caller_process = caller.lock().process.clone();
+    let caller_id = caller_process.lock().id;
+    let process = get_scheduler().process_table().get(id);
+    // A process can only give Ctrl+C to itself or to its children
+    let can_set = process.map_or(false, |process| {
+        let process = process.lock();
+        process.id == caller_id || process.parent_id == Some(caller_id)
+    });
+    if !can_set {
+        return SYSCALL_ERROR;
+    }
+    set_foreground_process(id);
+    0
+}
+
+/// Creates a copy of the calling process, which continues from the same place.
+///
+/// Returns the ID of the new process to the caller and 0 to the new process.
+pub fn fork() -> Option<u64> {
+    let id = crate::syscall(SysCallName::ProcessFork, 0, 0, 0);
+    (id != SYSCALL_ERROR).then(|| id)
+}
+
+/// Replaces the program of the calling process with an ELF64 executable, the other threads of
+/// the process are terminated.
+///
+/// Only returns if the executable can't be loaded.
+///
+/// # Safety
+/// `arguments` and `environment` have to be null-terminated arrays of pointers to null-terminated
+/// strings, or null.
+pub unsafe fn exec(image: &[u8], arguments: *const *const u8, environment: *const *const u8) {
+    let request = ExecRequest {
+        image: image.as_ptr(),
+        image_size: image.len() as u64,
+        arguments,
+        environment,
+    };
+    crate::syscall(
+        SysCallName::ProcessExec,
+        &request as *const ExecRequest as u64,
+        0,
+        0,
+    );
+}
+
+/// Waits until the child process with the given ID exits, returning how it stopped running.
+///
+/// The kernel writes the exit status encoded as described at `EXIT_STATUS_SIGNAL`.
+pub fn wait_pid(id: u64) -> Option<ExitStatus> {
+    let mut status = 0u64;
+    let result = crate::syscall(
+        SysCallName::ProcessWait,
+        id,
+        &mut status as *mut u64 as u64,
+        0,
+    );
+    (result != SYSCALL_ERROR).then(|| status.into())
+}
+
+/// Makes the calling process or its child with the given ID the one Ctrl+C interrupts.
+///
+/// Init becomes the foreground process again once it exits.
+pub fn set_foreground(id: u64) -> Option<()> {
+    let result = crate::syscall(SysCallName::ProcessSetForeground, id, 0, 0);
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// Sends the signal to the process with the given ID.
+///
+/// Fails for signals that would terminate init, it only gets the ones it handles or ignores.
+pub fn kill(id: u64, signal: Signal) -> Option<()> {
+    let result = crate::syscall(SysCallName::ProcessKill, id, signal as u64, 0);
+    (result != SYSCALL_ERROR).then(|| ())
+}
@@ -0,0 +1,129 @@
+use core::arch::asm;
+
+use alloc::sync::Arc;
+use kernel::processes::dispatcher::switch_to_thread;
+use kernel::processes::get_scheduler;
+use kernel::processes::signal::{
+    pop_signal_frame, Signal, SignalAction, SIGNAL_DEFAULT, SIGNAL_IGNORE,
+};
+use kernel::processes::thread::Thread;
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+
+use crate::syscall_name::SysCallName;
+
+/// How the blocked signals are changed.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskChange {
+    /// The signals in the mask are blocked too.
+    Block = 0,
+    /// The signals in the mask are unblocked.
+    Unblock = 1,
+    /// The signals in the mask are blocked, the others are unblocked.
+    Set = 2,
+}
+
+/// What the calling process does when it gets a signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignalHandler {
+    /// The process is terminated, unless the signal is ignored by default.
+    Default,
+    /// The signal is discarded.
+    Ignore,
+    /// The function is called with the number of the signal, on the stack of the thread that
+    /// handles it. The signal is blocked until the function returns.
+    Function(extern "C" fn(u64)),
+}
+
+pub(crate) extern "C" fn handler_signal_action(
+    signal: u64,
+    handler: u64,
+    restorer: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let signal = Signal::try_from(signal).ok();
+    let action = SignalAction::from_raw(handler, restorer);
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    if process.kernel_process {
+        return SYSCALL_ERROR;
+    }
+    signal
+        .zip(action)
+        .and_then(|(signal, action)| process.signals.set_action(signal, action))
+        .map_or(SYSCALL_ERROR, |previous| previous.raw_handler())
+}
+
+pub(crate) extern "C" fn handler_signal_mask(
+    change: u64,
+    mask: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    let mut process = process.lock();
+    let previous = process.signals.blocked();
+    let blocked = match change {
+        change if change == MaskChange::Block as u64 => previous | mask,
+        change if change == MaskChange::Unblock as u64 => previous & !mask,
+        change if change == MaskChange::Set as u64 => mask,
+        _ => return SYSCALL_ERROR,
+    };
+    // Signals that were unblocked are handled before the system call returns
+    process.signals.set_blocked(blocked);
+    previous
+}
+
+pub(crate) extern "C" fn handler_signal_return(
+    _: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    // The handler's FPU registers are replaced by the ones in the frame
+    get_scheduler().save_fpu_state();
+    let process = caller.lock().process.clone();
+    let popped = pop_signal_frame(&mut process.lock(), &mut caller.lock());
+    match popped {
+        // Continuing the interrupted code, which is where the next signal is handled too
+        Some(()) => switch_to_thread(caller),
+        None => SYSCALL_ERROR,
+    }
+}
+
+/// Where signal handlers return to, it's right above the frame the kernel put on the stack.
+#[naked]
+unsafe extern "C" fn signal_return() -> ! {
+    asm!(
+        "mov rdi, {name}",
+        "syscall",
+        // The frame wasn't valid
+        "ud2",
+        name = const SysCallName::SignalReturn as u64,
+        options(noreturn)
+    );
+}
+
+/// Sets what the calling process does when it gets the signal, the kill signal can't be
+/// handled.
+pub fn signal(signal: Signal, handler: SignalHandler) -> Option<()> {
+    let handler = match handler {
+        SignalHandler::Default => SIGNAL_DEFAULT,
+        SignalHandler::Ignore => SIGNAL_IGNORE,
+        SignalHandler::Function(function) => function as usize as u64,
+    };
+    let result = crate::syscall(
+        SysCallName::SignalAction,
+        signal as u64,
+        handler,
+        signal_return as usize as u64,
+    );
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// Changes which signals of the calling process are kept pending, returning the mask of the
+/// signals that were blocked before. The kill signal is never blocked.
+pub fn change_blocked_signals(change: MaskChange, mask: u64) -> u64 {
+    crate::syscall(SysCallName::SignalMask, change as u64, mask, 0)
+}
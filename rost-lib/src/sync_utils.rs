@@ -0,0 +1,381 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use alloc::sync::Arc;
+use kernel::processes::dispatcher::{block_running_thread, wake_futex};
+use kernel::processes::thread::{Thread, ThreadState, WaitTarget};
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use x86_64::VirtAddr;
+
+use crate::syscall_name::SysCallName;
+
+/// The state of a `Mutex` that is unlocked.
+const UNLOCKED: u32 = 0;
+/// The state of a `Mutex` that is locked and no thread waits for.
+const LOCKED: u32 = 1;
+/// The state of a `Mutex` that is locked and threads might wait for.
+const CONTENDED: u32 = 2;
+/// The state of a `RwLock` that is locked for writing, otherwise it's the number of readers.
+const WRITE_LOCKED: u32 = u32::MAX;
+
+pub(crate) extern "C" fn handler_futex_wait(
+    address: u64,
+    expected: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let address = match VirtAddr::try_new(address) {
+        Ok(address) if address.is_aligned(4u64) => address,
+        _ => return SYSCALL_ERROR,
+    };
+    let process = caller.lock().process.clone();
+    let value = process.lock().read_memory(address, 4);
+    // Futexes are woken up while holding the kernel lock, so none can be missed after this
+    let is_expected = value.map_or(false, |value| value[..] == (expected as u32).to_ne_bytes());
+    if !is_expected {
+        return SYSCALL_ERROR;
+    }
+    // The thread is woken up with 0 by `futex_wake`
+    block_running_thread(ThreadState::Waiting(WaitTarget::Futex(address)))
+}
+
+pub(crate) extern "C" fn handler_futex_wake(
+    address: u64,
+    count: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let address = match VirtAddr::try_new(address) {
+        Ok(address) if address.is_aligned(4u64) => address,
+        _ => return SYSCALL_ERROR,
+    };
+    let process = caller.lock().process.clone();
+    wake_futex(&process, address, count)
+}
+
+/// Blocks the calling thread while the futex has the expected value, until another thread of
+/// the process wakes it up.
+///
+/// Returns `false` if it had another value or the thread was interrupted by a signal, it can
+/// also wake up spuriously, so the value has to be checked again.
+pub fn futex_wait(futex: &AtomicU32, expected: u32) -> bool {
+    let result = crate::syscall(
+        SysCallName::FutexWait,
+        futex as *const AtomicU32 as u64,
+        expected as u64,
+        0,
+    );
+    result != SYSCALL_ERROR
+}
+
+/// Wakes up at most `count` threads of the calling process waiting for the futex, returning the
+/// number of threads that were woken up.
+pub fn futex_wake(futex: &AtomicU32, count: u32) -> u32 {
+    let woken = crate::syscall(
+        SysCallName::FutexWake,
+        futex as *const AtomicU32 as u64,
+        count as u64,
+        0,
+    );
+    if woken == SYSCALL_ERROR {
+        0
+    } else {
+        woken as u32
+    }
+}
+
+/// A lock the threads of a process can wait for without using the CPU.
+pub struct Mutex<T> {
+    /// `UNLOCKED`, `LOCKED` or `CONTENDED`.
+    state: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// The guard of a locked `Mutex`, it's unlocked once the guard is dropped.
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            state: AtomicU32::new(UNLOCKED),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks the calling thread until the value is unlocked.
+    pub fn lock(&self) -> MutexGuard<T> {
+        if self
+            .state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // The thread that unlocks it can't know if it was the only waiter
+            while self.state.swap(CONTENDED, Ordering::Acquire) != UNLOCKED {
+                futex_wait(&self.state, CONTENDED);
+            }
+        }
+        MutexGuard { mutex: self }
+    }
+
+    /// Locks the value if it's unlocked.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        self.state
+            .compare_exchange(UNLOCKED, LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        // Only waking up a waiter if there can be one, which saves a system call otherwise
+        if self.mutex.state.swap(UNLOCKED, Ordering::Release) == CONTENDED {
+            futex_wake(&self.mutex.state, 1);
+        }
+    }
+}
+
+/// Lets threads wait for a condition on the value of a `Mutex` to become true.
+#[derive(Default)]
+pub struct Condvar {
+    /// Changed on every notification, so waiters don't miss the ones sent while they unlock.
+    counter: AtomicU32,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            counter: AtomicU32::new(0),
+        }
+    }
+
+    /// Unlocks the mutex and blocks the calling thread until it's notified, then locks the mutex
+    /// again.
+    ///
+    /// The thread can wake up spuriously, so the condition has to be checked again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let counter = self.counter.load(Ordering::Relaxed);
+        let mutex = guard.mutex;
+        drop(guard);
+        futex_wait(&self.counter, counter);
+        mutex.lock()
+    }
+
+    /// Blocks the calling thread until the condition is false, the mutex is unlocked while it
+    /// waits.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut *guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    /// Wakes up one of the threads waiting for the condition variable.
+    pub fn notify_one(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.counter, 1);
+    }
+
+    /// Wakes up all the threads waiting for the condition variable.
+    pub fn notify_all(&self) {
+        self.counter.fetch_add(1, Ordering::Relaxed);
+        futex_wake(&self.counter, u32::MAX);
+    }
+}
+
+/// A lock that many threads can hold for reading or one for writing.
+pub struct RwLock<T> {
+    /// The number of readers, or `WRITE_LOCKED`.
+    state: AtomicU32,
+    /// Changed whenever the lock was released for writers, they wait for it.
+    writer_wake_counter: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+/// The guard of a `RwLock` locked for reading.
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+/// The guard of a `RwLock` locked for writing.
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicU32::new(0),
+            writer_wake_counter: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Blocks the calling thread until no thread holds the lock for writing.
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state == WRITE_LOCKED {
+                futex_wait(&self.state, WRITE_LOCKED);
+                state = self.state.load(Ordering::Relaxed);
+                continue;
+            }
+            assert!(state != WRITE_LOCKED - 1, "Too many readers");
+            match self.state.compare_exchange_weak(
+                state,
+                state + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return RwLockReadGuard { lock: self },
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    /// Blocks the calling thread until no thread holds the lock.
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        while self
+            .state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            let counter = self.writer_wake_counter.load(Ordering::Acquire);
+            if self.state.load(Ordering::Relaxed) != 0 {
+                futex_wait(&self.writer_wake_counter, counter);
+            }
+        }
+        RwLockWriteGuard { lock: self }
+    }
+
+    /// Locks the value for writing if no thread holds the lock.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.state
+            .compare_exchange(0, WRITE_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        // The last reader lets a writer in
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock
+                .writer_wake_counter
+                .fetch_add(1, Ordering::Release);
+            futex_wake(&self.lock.writer_wake_counter, 1);
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock
+            .writer_wake_counter
+            .fetch_add(1, Ordering::Release);
+        futex_wake(&self.lock.writer_wake_counter, 1);
+        futex_wake(&self.lock.state, u32::MAX);
+    }
+}
+
+/// A counter of permits, threads wait until they can take one.
+pub struct Semaphore {
+    /// The permits that are left.
+    permits: AtomicU32,
+    /// The number of threads that wait for a permit.
+    waiters: AtomicU32,
+}
+
+impl Semaphore {
+    pub const fn new(permits: u32) -> Self {
+        Semaphore {
+            permits: AtomicU32::new(permits),
+            waiters: AtomicU32::new(0),
+        }
+    }
+
+    /// Blocks the calling thread until it can take a permit.
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.waiters.fetch_add(1, Ordering::SeqCst);
+            futex_wait(&self.permits, 0);
+            self.waiters.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Takes a permit if there is one left.
+    pub fn try_acquire(&self) -> bool {
+        self.permits
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |permits| {
+                permits.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Gives back a permit, waking up a thread waiting for one.
+    pub fn release(&self) {
+        self.permits.fetch_add(1, Ordering::SeqCst);
+        // A thread that starts waiting after this sees the permit
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            futex_wake(&self.permits, 1);
+        }
+    }
+}
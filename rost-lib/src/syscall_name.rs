@@ -1,7 +1,30 @@
 #[repr(u64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum SysCallName {
+    ProcessFork = 200,
+    ProcessExec = 201,
+    ProcessWait = 202,
+    ProcessKill = 203,
+    ProcessSetForeground = 204,
     ThreadExit = 300,
     ThreadYield = 301,
     ThreadSleep = 302,
+    ThreadJoin = 303,
+    ThreadSpawn = 304,
+    ThreadSetPriority = 305,
+    ThreadSetTls = 306,
+    ThreadDetach = 307,
+    MemoryMap = 400,
+    MemoryUnmap = 401,
+    MemoryProtect = 402,
+    ProgramBreak = 403,
+    ClockGetTime = 500,
+    Time = 501,
+    SystemShutdown = 600,
+    SystemReboot = 601,
+    SignalAction = 700,
+    SignalMask = 701,
+    SignalReturn = 702,
+    FutexWait = 800,
+    FutexWake = 801,
 }
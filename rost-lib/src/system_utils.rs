@@ -0,0 +1,56 @@
+use alloc::sync::Arc;
+use kernel::acpi;
+use kernel::processes::thread::Thread;
+use kernel::processes::INIT_PROCESS_ID;
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+
+use crate::syscall_name::SysCallName;
+
+pub(crate) extern "C" fn handler_system_shutdown(
+    _: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    if !can_control_power(&caller) {
+        return SYSCALL_ERROR;
+    }
+    acpi::shutdown();
+}
+
+pub(crate) extern "C" fn handler_system_reboot(
+    _: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    if !can_control_power(&caller) {
+        return SYSCALL_ERROR;
+    }
+    acpi::reboot();
+}
+
+/// Checks if the thread's process may turn off or restart the system, only init and kernel
+/// processes may.
+fn can_control_power(thread: &Arc<IrqMutex<Thread>>) -> bool {
+    let process = thread.lock().process.clone();
+    let process = process.lock();
+    process.id == INIT_PROCESS_ID || process.kernel_process
+}
+
+/// Turns the system off.
+///
+/// Panics if the process is neither init nor a kernel process.
+pub fn shutdown() -> ! {
+    crate::syscall(SysCallName::SystemShutdown, 0, 0, 0);
+    panic!("The system didn't turn off");
+}
+
+/// Restarts the system.
+///
+/// Panics if the process is neither init nor a kernel process.
+pub fn reboot() -> ! {
+    crate::syscall(SysCallName::SystemReboot, 0, 0, 0);
+    panic!("The system didn't restart");
+}
@@ -1,48 +1,215 @@
-use core::cell::RefCell;
-
-use alloc::rc::Rc;
-use kernel::processes::dispatcher::exit_thread;
-use kernel::processes::run_next_thread;
-use kernel::processes::thread::{Thread, ThreadState};
+use alloc::sync::Arc;
+use kernel::processes::dispatcher::{block_running_thread, exit_thread, switch_to_next_thread};
+use kernel::processes::spawn_thread;
+use kernel::processes::thread::{Thread, ThreadState, WaitTarget, PRIORITY_LEVELS};
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use kernel::time::uptime_millis;
+use x86_64::VirtAddr;
 
 use crate::syscall_name::SysCallName;
 
-// TODO Add handling for process/thread exit code
-// We should probably have thread exit code handlers for non-zero exit codes, and pass them as the process exit code.
 pub(crate) extern "C" fn handler_thread_exit(
-    _code: u64,
+    code: u64,
+    _: u64,
     _: u64,
-    caller: Rc<RefCell<Thread>>,
+    caller: Arc<IrqMutex<Thread>>,
 ) -> u64 {
-    exit_thread(caller).unwrap();
-    run_next_thread();
-    panic!("No threads to run");
+    exit_thread(caller, code).unwrap();
+    switch_to_next_thread();
 }
 
-pub(crate) extern "C" fn handler_thread_yield(_: u64, _: u64, _caller: Rc<RefCell<Thread>>) -> u64 {
-    run_next_thread();
-    panic!("No threads to run");
+pub(crate) extern "C" fn handler_thread_yield(
+    _: u64,
+    _: u64,
+    _: u64,
+    _caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    block_running_thread(ThreadState::Ready);
+    0
 }
 
 pub(crate) extern "C" fn handler_thread_sleep(
     time: u64,
     _: u64,
-    caller: Rc<RefCell<Thread>>,
+    _: u64,
+    _caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let deadline = uptime_millis().saturating_add(time);
+    block_running_thread(ThreadState::Sleeping(deadline));
+    0
+}
+
+pub(crate) extern "C" fn handler_thread_join(
+    thread_id: u64,
+    exit_code_pointer: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let (process, caller_id) = {
+        let caller = caller.lock();
+        (caller.process.clone(), caller.id)
+    };
+    let exit_code_pointer = match VirtAddr::try_new(exit_code_pointer) {
+        Ok(exit_code_pointer) => exit_code_pointer,
+        Err(_) => return SYSCALL_ERROR,
+    };
+    // Checking the pointer first, so the exit code isn't lost if it's invalid
+    let is_writable = process
+        .lock()
+        .write_memory(exit_code_pointer, &0u64.to_ne_bytes())
+        .is_some();
+    if !is_writable {
+        return SYSCALL_ERROR;
+    }
+
+    let exit_code = process.lock().thread_exit_codes.remove(&thread_id);
+    let exit_code = match exit_code {
+        Some(exit_code) => exit_code,
+        None => {
+            let thread = process.lock().find_thread(thread_id);
+            let thread = match thread {
+                Some(thread) if caller_id != thread_id => thread,
+                _ => return SYSCALL_ERROR,
+            };
+            // Any exit code can be returned, so the thread's own one is used rather than the
+            // result a signal could wake it up with too
+            block_running_thread(ThreadState::Waiting(WaitTarget::Thread(thread_id)));
+            let exit_code = thread.lock().exit_code;
+            match exit_code {
+                Some(exit_code) => exit_code,
+                None => return SYSCALL_ERROR,
+            }
+        }
+    };
+    let written = process
+        .lock()
+        .write_memory(exit_code_pointer, &exit_code.to_ne_bytes());
+    written.map_or(SYSCALL_ERROR, |_| 0)
+}
+
+pub(crate) extern "C" fn handler_thread_detach(
+    thread_id: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
 ) -> u64 {
-    Thread::change_state(caller, ThreadState::Sleeping(time));
-    run_next_thread();
-    panic!("No threads to run");
+    let (process, caller_id) = {
+        let caller = caller.lock();
+        (caller.process.clone(), caller.id)
+    };
+    let exit_code = process.lock().thread_exit_codes.remove(&thread_id);
+    if exit_code.is_some() {
+        return 0;
+    }
+    let thread = if caller_id == thread_id {
+        Some(caller)
+    } else {
+        process.lock().find_thread(thread_id)
+    };
+    match thread {
+        Some(thread) => {
+            thread.lock().detached = true;
+            0
+        }
+        None => SYSCALL_ERROR,
+    }
+}
+
+pub(crate) extern "C" fn handler_thread_spawn(
+    entry_point: u64,
+    arg1: u64,
+    arg2: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let process = caller.lock().process.clone();
+    VirtAddr::try_new(entry_point)
+        .ok()
+        .and_then(|entry_point| spawn_thread(process, entry_point, [arg1, arg2]))
+        .map_or(SYSCALL_ERROR, |thread| thread.lock().id)
+}
+
+pub(crate) extern "C" fn handler_thread_set_priority(
+    thread_id: u64,
+    priority: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    if priority >= PRIORITY_LEVELS as u64 {
+        return SYSCALL_ERROR;
+    }
+    let process = caller.lock().process.clone();
+    let thread = process.lock().find_thread(thread_id);
+    match thread {
+        Some(thread) => {
+            thread.lock().set_priority(priority as u8);
+            0
+        }
+        None => SYSCALL_ERROR,
+    }
 }
 
 pub extern "C" fn thread_exit(status: u64) -> ! {
-    crate::syscall(SysCallName::ThreadExit, status, 0);
+    crate::syscall(SysCallName::ThreadExit, status, 0, 0);
     panic!("Thread exited");
 }
 
 pub extern "C" fn thread_yield() {
-    crate::syscall(SysCallName::ThreadYield, 0, 0);
+    crate::syscall(SysCallName::ThreadYield, 0, 0, 0);
 }
 
+/// Suspends the calling thread for the given number of milliseconds.
 pub extern "C" fn thread_sleep(time: u64) {
-    crate::syscall(SysCallName::ThreadSleep, time, 0);
+    crate::syscall(SysCallName::ThreadSleep, time, 0, 0);
+}
+
+/// Waits until the thread of the process with the given ID exits, returning its exit code.
+///
+/// The exit code is kept until the thread is joined, so every thread has to be joined or
+/// detached.
+pub fn thread_join(thread_id: u64) -> Option<u64> {
+    let mut exit_code = 0u64;
+    let result = crate::syscall(
+        SysCallName::ThreadJoin,
+        thread_id,
+        &mut exit_code as *mut u64 as u64,
+        0,
+    );
+    (result != SYSCALL_ERROR).then(|| exit_code)
+}
+
+/// Lets the thread of the process with the given ID exit without being joined, its exit code
+/// is dropped.
+pub fn thread_detach(thread_id: u64) -> Option<()> {
+    let result = crate::syscall(SysCallName::ThreadDetach, thread_id, 0, 0);
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// Sets the priority of the thread of the process with the given ID, 0 is the most important.
+/// The priority has to be below `PRIORITY_LEVELS`.
+pub fn set_priority(thread_id: u64, priority: u8) -> Option<()> {
+    let result = crate::syscall(
+        SysCallName::ThreadSetPriority,
+        thread_id,
+        priority as u64,
+        0,
+    );
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// The function spawned threads start in, it exits the thread with the result of the entry point.
+extern "C" fn thread_start(entry_point: extern "C" fn(u64) -> u64, arg: u64) -> ! {
+    thread_exit(entry_point(arg));
+}
+
+/// Runs the function on a new thread of the calling process with its own stack, returning the
+/// ID of the thread. The thread exits with the result of the function.
+pub fn spawn(entry_point: extern "C" fn(u64) -> u64, arg: u64) -> Option<u64> {
+    let id = crate::syscall(
+        SysCallName::ThreadSpawn,
+        thread_start as usize as u64,
+        entry_point as usize as u64,
+        arg,
+    );
+    (id != SYSCALL_ERROR).then(|| id)
 }
@@ -0,0 +1,78 @@
+use alloc::sync::Arc;
+use internal_utils::get_current_tick;
+use kernel::processes::thread::Thread;
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use kernel::time::{cycles_to_nanos, monotonic_nanos, wall_clock_nanos};
+
+use crate::syscall_name::SysCallName;
+
+/// The clocks `clock_gettime` can read.
+#[repr(u64)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// The time since boot, it never goes backwards.
+    Monotonic = 0,
+    /// The time the calling process spent running.
+    ProcessCpuTime = 1,
+    /// The time the calling thread spent running.
+    ThreadCpuTime = 2,
+    /// The time since the Unix epoch, it's only available if a clock driver set it.
+    Realtime = 3,
+}
+
+impl TryFrom<u64> for Clock {
+    type Error = ();
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Clock::Monotonic),
+            1 => Ok(Clock::ProcessCpuTime),
+            2 => Ok(Clock::ThreadCpuTime),
+            3 => Ok(Clock::Realtime),
+            _ => Err(()),
+        }
+    }
+}
+
+pub(crate) extern "C" fn handler_clock_get_time(
+    clock: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let (process, thread_ticks, last_tick) = {
+        let thread = caller.lock();
+        (thread.process.clone(), thread.total_ticks, thread.last_tick)
+    };
+    // The ticks of the running thread are only added up on timer interrupts
+    let running_ticks = get_current_tick().saturating_sub(last_tick);
+    match Clock::try_from(clock) {
+        Ok(Clock::Monotonic) => monotonic_nanos(),
+        Ok(Clock::ProcessCpuTime) => cycles_to_nanos(process.lock().total_ticks + running_ticks),
+        Ok(Clock::ThreadCpuTime) => cycles_to_nanos(thread_ticks + running_ticks),
+        Ok(Clock::Realtime) => wall_clock_nanos().unwrap_or(SYSCALL_ERROR),
+        Err(()) => SYSCALL_ERROR,
+    }
+}
+
+pub(crate) extern "C" fn handler_time(
+    _: u64,
+    _: u64,
+    _: u64,
+    _caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    wall_clock_nanos().map_or(SYSCALL_ERROR, |nanos| nanos / 1_000_000_000)
+}
+
+/// Returns the time of the clock in nanoseconds, `None` if the clock isn't available.
+pub fn clock_gettime(clock: Clock) -> Option<u64> {
+    let time = crate::syscall(SysCallName::ClockGetTime, clock as u64, 0, 0);
+    (time != SYSCALL_ERROR).then(|| time)
+}
+
+/// Returns the seconds since the Unix epoch, `None` if the wall-clock time isn't known.
+pub fn time() -> Option<u64> {
+    let time = crate::syscall(SysCallName::Time, 0, 0, 0);
+    (time != SYSCALL_ERROR).then(|| time)
+}
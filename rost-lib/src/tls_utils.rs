@@ -0,0 +1,75 @@
+use alloc::sync::Arc;
+use kernel::processes::thread::Thread;
+use kernel::processes::tls::set_fs_base;
+use kernel::sync::IrqMutex;
+use kernel::syscalls::system_call::SYSCALL_ERROR;
+use x86_64::VirtAddr;
+
+use crate::syscall_name::SysCallName;
+
+pub(crate) extern "C" fn handler_thread_set_tls(
+    thread_pointer: u64,
+    _: u64,
+    _: u64,
+    caller: Arc<IrqMutex<Thread>>,
+) -> u64 {
+    let thread_pointer = match VirtAddr::try_new(thread_pointer) {
+        Ok(thread_pointer) => thread_pointer,
+        Err(_) => return SYSCALL_ERROR,
+    };
+    caller.lock().fs_base = thread_pointer;
+    // The caller returns to user mode on this CPU without being switched to again
+    set_fs_base(thread_pointer);
+    0
+}
+
+/// Sets the FS base of the calling thread, thread-local variables are found through it.
+///
+/// Threads of programs with thread-local variables start with it pointing to their own copy of
+/// them, so this is only needed to set up thread-local storage differently.
+pub fn set_tls(thread_pointer: *mut u8) -> Option<()> {
+    let result = crate::syscall(SysCallName::ThreadSetTls, thread_pointer as u64, 0, 0);
+    (result != SYSCALL_ERROR).then(|| ())
+}
+
+/// A thread-local variable declared with `thread_local!`, each thread of the process has its own
+/// copy of it.
+pub struct LocalKey<T: 'static> {
+    /// Returns the address of the calling thread's copy.
+    accessor: fn() -> *const T,
+}
+
+impl<T: 'static> LocalKey<T> {
+    #[doc(hidden)]
+    pub const fn new(accessor: fn() -> *const T) -> Self {
+        LocalKey { accessor }
+    }
+
+    /// Calls the function with the calling thread's copy of the variable.
+    pub fn with<R>(&'static self, function: impl FnOnce(&T) -> R) -> R {
+        function(unsafe { &*(self.accessor)() })
+    }
+}
+
+/// Declares thread-local variables, every thread starts with its own copy of the initial value.
+///
+/// The variables are put in the program's TLS segment, so the crate using the macro needs
+/// `#![feature(thread_local)]` and the initial values have to be constant.
+#[macro_export]
+macro_rules! thread_local {
+    () => {};
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr; $($rest:tt)*) => {
+        $crate::thread_local!($(#[$attr])* $vis static $name: $t = $init);
+        $crate::thread_local!($($rest)*);
+    };
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $t:ty = $init:expr) => {
+        $(#[$attr])* $vis static $name: $crate::tls_utils::LocalKey<$t> = {
+            fn accessor() -> *const $t {
+                #[thread_local]
+                static VALUE: $t = $init;
+                &VALUE
+            }
+            $crate::tls_utils::LocalKey::new(accessor)
+        };
+    };
+}
@@ -0,0 +1,27 @@
+# A user-mode program checking that its SSE registers survive other threads using theirs.
+# The exit code is 0 if XMM0 still holds the value it put there before sleeping.
+#
+# Built with:
+#   as fpu_check.s -o fpu_check.o
+#   ld -static -nostdlib -z max-page-size=0x1000 -s fpu_check.o -o fpu_check.elf
+
+    .globl _start
+    .text
+_start:
+    rdtsc           # a value other instances don't have
+    shl $32, %rdx
+    or %rdx, %rax
+    mov %rax, %rbx
+    movq %rax, %xmm0  # the first use raises #NM, which loads the thread's registers
+    mov $302, %rdi  # SysCallName::ThreadSleep
+    mov $10, %rsi   # milliseconds
+    xor %edx, %edx
+    syscall
+    movq %xmm0, %rax
+    sub %rbx, %rax
+    mov %rax, %rsi  # exit code
+    mov $300, %rdi  # SysCallName::ThreadExit
+    xor %edx, %edx
+    syscall
+1:
+    jmp 1b
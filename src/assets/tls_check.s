@@ -0,0 +1,30 @@
+# A user-mode program reading its thread-local variables through the FS base.
+# The exit code is 0 if the loader copied .tdata and zeroed .tbss for the first thread.
+#
+# Built with:
+#   as tls_check.s -o tls_check.o
+#   ld -static -nostdlib -z max-page-size=0x1000 -s tls_check.o -o tls_check.elf
+
+    .globl _start
+    .text
+_start:
+    mov %fs:0, %rcx  # the thread pointer points to itself
+    mov initial@tpoff(%rcx), %rax
+    sub $42, %rax
+    or %fs:counter@tpoff, %rax
+    mov %rax, %rsi  # exit code
+    mov $300, %rdi  # SysCallName::ThreadExit
+    xor %edx, %edx
+    syscall
+1:
+    jmp 1b
+
+    .section .tdata, "awT", @progbits
+    .p2align 3
+initial:
+    .quad 42
+
+    .section .tbss, "awT", @nobits
+    .p2align 3
+counter:
+    .quad 0
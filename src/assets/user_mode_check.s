@@ -0,0 +1,28 @@
+# A minimal user-mode program touching its .data and .bss sections, then exiting.
+# The exit code is 0 if .bss was zero-filled by the loader.
+#
+# Built with:
+#   as user_mode_check.s -o user_mode_check.o
+#   ld -static -nostdlib -z max-page-size=0x1000 -s user_mode_check.o -o user_mode_check.elf
+
+    .globl _start
+    .text
+_start:
+    mov counter(%rip), %rax
+    add initial(%rip), %rax
+    mov %rax, counter(%rip)
+    sub initial(%rip), %rax
+    mov %rax, %rsi  # exit code
+    mov $300, %rdi  # SysCallName::ThreadExit
+    xor %edx, %edx
+    syscall
+1:
+    jmp 1b
+
+    .data
+initial:
+    .quad 42
+
+    .bss
+counter:
+    .quad 0
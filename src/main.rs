@@ -6,19 +6,18 @@
     abi_x86_interrupt,
     generic_const_exprs,
     core_intrinsics,
-    alloc_error_handler
+    alloc_error_handler,
+    thread_local
 )]
 #![test_runner(test_framework::test_runner::test_runner)]
 #![reexport_test_harness_main = "test_main"]
 extern crate alloc;
 
-use alloc::rc::Rc;
 use bootloader::{entry_point, BootInfo};
 use core::arch::asm;
-use core::cell::RefCell;
 use core::panic::PanicInfo;
+use internal_utils::serial_println;
 use internal_utils::structures::kernel_information::KernelInformation;
-use internal_utils::{constants::MIB, serial_println};
 use rost_lib::syscall_name::SysCallName;
 use tinytga::RawTga;
 use vga::vga_core::{Clearable, ImageDrawable};
@@ -42,6 +41,7 @@ fn bootup_sequence(kernel_info: KernelInformation) {
     rost_lib::__initialize_syscalls();
     kernel::register_driver(vga::driver_init);
     kernel::register_driver(ata::driver_init);
+    kernel::register_driver(rtc::driver_init);
     kernel::reload_drivers();
     let data = include_bytes!("./assets/rost-logo.tga");
     let logo = RawTga::from_slice(data).unwrap();
@@ -55,11 +55,6 @@ fn bootup_sequence(kernel_info: KernelInformation) {
     );
 }
 
-#[no_mangle]
-extern "C" fn user_mode_check_1() {
-    exit(0);
-}
-
 #[no_mangle]
 extern "C" fn user_mode_check_2() {
     let mut i = 1;
@@ -99,25 +94,23 @@ fn sleep(time: u64) {
 }
 
 pub fn kernel_main(kernel_info: KernelInformation) {
-    use kernel::processes::{
-        add_process,
-        process::Process,
-        run_processes,
-        thread::{Thread, ThreadState},
-    };
-    let process1: Rc<RefCell<Process>>;
-    let thread1: Rc<RefCell<Thread>>;
-    unsafe {
-        process1 = add_process(Process::from_extern(user_mode_check_1, 1));
-        thread1 = Thread::new_native(0x1000, 2 * MIB, process1);
+    use kernel::processes::{add_elf_process, run_processes};
+    // Assembled from ./assets/user_mode_check.s, the first process becomes init
+    add_elf_process(include_bytes!("./assets/user_mode_check.elf"))
+        .expect("Failed to load the user mode check program");
+    // Assembled from ./assets/fpu_check.s, two of them check that each keeps its SSE registers
+    for _ in 0..2 {
+        add_elf_process(include_bytes!("./assets/fpu_check.elf"))
+            .expect("Failed to load the FPU check program");
     }
-    Thread::change_state(thread1, ThreadState::Ready);
+    // Assembled from ./assets/tls_check.s
+    add_elf_process(include_bytes!("./assets/tls_check.elf"))
+        .expect("Failed to load the TLS check program");
 
     //let process2 = add_process(Process::new(user_mode_check_2, 2));
     //let _thread2 = Thread::new(0x1000, 2 * MIB, process2);
 
     run_processes();
-    serial_println!("Something went wrong");
     /*
         let test = Box::new(4);
         log_println!("New boxed value: {:#?}", test);
@@ -223,6 +216,386 @@ mod tests {
         assert_eq!(2 * 1024 * 1024, size - allocator.get_free_memory_size());
     }
 
+    #[test_case]
+    fn should_parse_elf_executable(_: KernelInformation) {
+        use kernel::processes::elf::{ElfFile, PT_LOAD};
+        let elf = ElfFile::parse(include_bytes!("./assets/user_mode_check.elf"))
+            .expect("Failed to parse the ELF file");
+        assert_eq!(0x40_1000, elf.entry_point().as_u64());
+        let segments = elf.program_headers().filter(|h| h.segment_type == PT_LOAD);
+        assert_eq!(3, segments.count());
+    }
+
+    #[test_case]
+    fn should_reject_invalid_elf(_: KernelInformation) {
+        use kernel::processes::elf::{ElfFile, ElfLoadError};
+        let mut data = [0u8; 128];
+        assert_eq!(
+            Some(ElfLoadError::InvalidMagic),
+            ElfFile::parse(&data).err()
+        );
+        data[0..4].copy_from_slice(b"\x7FELF");
+        assert_eq!(
+            Some(ElfLoadError::UnsupportedFormat),
+            ElfFile::parse(&data).err()
+        );
+        assert_eq!(
+            Some(ElfLoadError::Truncated),
+            ElfFile::parse(&data[..16]).err()
+        );
+    }
+
+    #[test_case]
+    fn should_copy_shared_read_only_page_made_writable(_: KernelInformation) {
+        use kernel::processes::dispatcher::release_address_space;
+        use kernel::processes::memory_area::MemoryPermissions;
+        use kernel::processes::process::Process;
+        use kernel::processes::IDLE_PROCESS_ID;
+
+        let (parent, entry_point, _) = Process::from_elf(
+            include_bytes!("./assets/user_mode_check.elf"),
+            IDLE_PROCESS_ID,
+        )
+        .expect("Failed to load the user mode check program");
+        let mut child = parent.fork(IDLE_PROCESS_ID).expect("Failed to fork");
+        // The code is read-only, so it was shared without being marked copy-on-write
+        let page = entry_point;
+        let code = parent.read_memory(page, 1).unwrap();
+        let permissions = MemoryPermissions {
+            writable: true,
+            executable: true,
+        };
+        child.protect_memory(page, 0x1000, permissions).unwrap();
+        child.write_memory(page, &[!code[0]]).unwrap();
+
+        assert_eq!(Some(alloc::vec![!code[0]]), child.read_memory(page, 1));
+        assert_eq!(Some(code), parent.read_memory(page, 1));
+        release_address_space(child.cr3).expect("Failed to clear the user mode mapping");
+        release_address_space(parent.cr3).expect("Failed to clear the user mode mapping");
+    }
+
+    #[test_case]
+    fn should_calibrate_tsc(_: KernelInformation) {
+        use kernel::time::{monotonic_nanos, tsc_frequency};
+        assert_ne!(0, tsc_frequency());
+        let start = monotonic_nanos();
+        assert!(monotonic_nanos() >= start);
+    }
+
+    #[test_case]
+    fn should_run_tests_on_boot_cpu(_: KernelInformation) {
+        use kernel::smp::{cpu_count, current_cpu_index, BOOT_CPU, MAX_CPUS};
+        assert_eq!(BOOT_CPU, current_cpu_index());
+        assert!((1..=MAX_CPUS).contains(&cpu_count()));
+    }
+
+    #[test_case]
+    fn should_disable_interrupts_while_locked(_: KernelInformation) {
+        use kernel::sync::IrqMutex;
+        use x86_64::instructions::interrupts;
+        let were_enabled = interrupts::are_enabled();
+        let mutex = IrqMutex::new(4);
+        {
+            let mut value = mutex.lock();
+            *value += 1;
+            assert!(!interrupts::are_enabled());
+        }
+        assert_eq!(were_enabled, interrupts::are_enabled());
+        assert_eq!(5, *mutex.lock());
+    }
+
+    #[test_case]
+    fn should_reparent_orphans_to_init(_: KernelInformation) {
+        use kernel::processes::process::Process;
+        use kernel::processes::{ProcessTable, INIT_PROCESS_ID};
+        let mut table = ProcessTable::default();
+        let add_child = |table: &mut ProcessTable, parent_id: Option<u64>| {
+            let mut process = Process::new_kernel(table.allocate_process_id());
+            process.parent_id = parent_id;
+            let id = process.id;
+            table.insert(process);
+            id
+        };
+        let init = add_child(&mut table, None);
+        let parent = add_child(&mut table, Some(init));
+        let child = add_child(&mut table, Some(parent));
+        assert_eq!(INIT_PROCESS_ID, init);
+        assert_ne!(table.allocate_thread_id(), table.allocate_thread_id());
+
+        let exited_child = add_child(&mut table, Some(parent));
+        let exited_child = table.remove(exited_child).expect("The child wasn't added");
+        table.add_zombie(exited_child.clone());
+        let init_child = add_child(&mut table, Some(init));
+        let init_child = table.remove(init_child).expect("The child wasn't added");
+        table.add_zombie(init_child.clone());
+
+        assert!(table.remove(parent).is_some());
+        assert!(table.get(parent).is_none());
+        let exited_child_id = exited_child.lock().id;
+        assert!(table
+            .take_zombie(exited_child_id, INIT_PROCESS_ID)
+            .is_none());
+        let child = table.get(child).expect("The orphan was removed");
+        assert_eq!(Some(INIT_PROCESS_ID), child.lock().parent_id);
+
+        // Init never waits for the orphan, so it's reaped once it exits
+        let child_id = child.lock().id;
+        assert!(table.remove(child_id).is_some());
+        table.add_zombie(child);
+        assert!(table.take_zombie(child_id, INIT_PROCESS_ID).is_none());
+        let init_child_id = init_child.lock().id;
+        assert!(table.take_zombie(init_child_id, INIT_PROCESS_ID).is_some());
+    }
+
+    #[test_case]
+    fn should_keep_blocked_signals_pending(_: KernelInformation) {
+        use kernel::processes::signal::{Signal, SignalAction, Signals};
+        let mut signals = Signals::default();
+        assert!(!signals.raise(Signal::Child));
+        signals.set_blocked(Signal::Interrupt.mask() | Signal::Kill.mask());
+        assert!(!signals.raise(Signal::Interrupt));
+        assert!(signals.raise(Signal::Kill));
+        assert_eq!(Some(Signal::Kill), signals.take_deliverable());
+        assert_eq!(None, signals.take_deliverable());
+        assert_eq!(None, signals.set_action(Signal::Kill, SignalAction::Ignore));
+
+        signals.set_blocked(0);
+        assert_eq!(Some(Signal::Interrupt), signals.take_deliverable());
+    }
+
+    #[test_case]
+    fn should_only_send_handled_signals_to_init(_: KernelInformation) {
+        use kernel::processes::process::Process;
+        use kernel::processes::signal::{can_send_signal, Signal, SignalAction};
+        use kernel::processes::INIT_PROCESS_ID;
+        let mut init = Process::new_kernel(INIT_PROCESS_ID);
+        assert!(!can_send_signal(&init, Signal::Kill));
+        assert!(!can_send_signal(&init, Signal::Terminate));
+        assert!(can_send_signal(&init, Signal::Child));
+        init.signals
+            .set_action(Signal::Terminate, SignalAction::Ignore)
+            .unwrap();
+        assert!(can_send_signal(&init, Signal::Terminate));
+        assert!(can_send_signal(&Process::new_kernel(2), Signal::Kill));
+    }
+
+    #[test_case]
+    fn should_interrupt_foreground_process(_: KernelInformation) {
+        use kernel::processes::dispatcher::kill_process;
+        use kernel::processes::get_scheduler;
+        use kernel::processes::process::{ExitReason, Process};
+        use kernel::processes::signal::{
+            interrupt_foreground_process, set_foreground_process, Signal,
+        };
+
+        let process = {
+            let mut scheduler = get_scheduler();
+            let id = scheduler.process_table_mut().allocate_process_id();
+            let (process, _, _) =
+                Process::from_elf(include_bytes!("./assets/user_mode_check.elf"), id)
+                    .expect("Failed to load the user mode check program");
+            scheduler.process_table_mut().insert(process)
+        };
+        let id = process.lock().id;
+        set_foreground_process(id);
+        interrupt_foreground_process();
+        let signal = process.lock().signals.take_deliverable();
+        assert_eq!(Some(Signal::Interrupt), signal);
+
+        // The process has no threads, so killing it only cleans it up
+        kill_process(process, ExitReason::Signaled(Signal::Interrupt))
+            .expect("Failed to clear the user mode mapping");
+    }
+
+    #[test_case]
+    fn should_lock_without_system_calls_when_uncontended(_: KernelInformation) {
+        use rost_lib::sync_utils::{Mutex, Semaphore};
+        let mutex = Mutex::new(4);
+        {
+            let mut value = mutex.lock();
+            *value += 1;
+            assert!(mutex.try_lock().is_none());
+        }
+        assert_eq!(5, *mutex.lock());
+
+        let semaphore = Semaphore::new(2);
+        assert!(semaphore.try_acquire());
+        semaphore.acquire();
+        assert!(!semaphore.try_acquire());
+    }
+
+    #[test_case]
+    fn should_keep_sse_registers_per_thread(_: KernelInformation) {
+        use alloc::sync::Arc;
+        use core::arch::asm;
+        use kernel::processes::fpu::FpuState;
+        use kernel::processes::process::Process;
+        use kernel::processes::thread::Thread;
+        use kernel::processes::{get_scheduler, IDLE_PROCESS_ID};
+        use kernel::sync::IrqMutex;
+        use x86_64::instructions::interrupts::without_interrupts;
+
+        let mut state = FpuState::default();
+        assert_eq!(0x1F80, state.mxcsr());
+        assert_eq!(Some(0), state.xmm(0));
+        assert_eq!(None, state.xmm(16));
+        state.set_mxcsr(u32::MAX);
+        state.sanitize();
+        assert_eq!(0xFFBF, state.mxcsr());
+
+        let write_xmm0 = |value: u64| unsafe {
+            asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack));
+        };
+        let read_xmm0 = || {
+            let value: u64;
+            unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+            value
+        };
+        // Switching like the dispatcher does, the registers are only loaded once they are used
+        let switch_to = |thread: Option<&Arc<IrqMutex<Thread>>>| {
+            let mut scheduler = get_scheduler();
+            scheduler.save_fpu_state();
+            scheduler.set_running_thread(thread.cloned());
+        };
+
+        let process = Arc::new(IrqMutex::new(Process::new_kernel(IDLE_PROCESS_ID)));
+        let new_thread = || {
+            let id = get_scheduler().process_table_mut().allocate_thread_id();
+            unsafe { Thread::new_native(id, 0, 0, process.clone()) }
+                .expect("Failed to create a thread")
+        };
+        let (first, second) = (new_thread(), new_thread());
+        // The timer must not switch to the threads, they never run
+        without_interrupts(|| {
+            switch_to(Some(&first));
+            write_xmm0(0xA);
+            switch_to(Some(&second));
+            assert_eq!(0, read_xmm0());
+            write_xmm0(0xB);
+            switch_to(Some(&first));
+            assert_eq!(0xA, read_xmm0());
+            switch_to(None);
+        });
+        assert_eq!(Some(0xA), first.lock().fpu_state.xmm(0));
+        assert_eq!(Some(0xB), second.lock().fpu_state.xmm(0));
+
+        process.lock().not_started_threads.clear();
+        first.lock().terminate();
+        second.lock().terminate();
+    }
+
+    #[test_case]
+    fn should_place_tls_block_below_thread_pointer(_: KernelInformation) {
+        use kernel::processes::elf::ElfFile;
+        use kernel::processes::tls::{TlsTemplate, TCB_SIZE};
+        use x86_64::VirtAddr;
+        let elf = ElfFile::parse(include_bytes!("./assets/user_mode_check.elf"))
+            .expect("Failed to parse the ELF file");
+        assert_eq!(Ok(None), elf.tls_template(u64::MAX));
+
+        let template = TlsTemplate {
+            address: VirtAddr::new(0x40_2000),
+            file_size: 4,
+            memory_size: 12,
+            align: 8,
+        };
+        assert_eq!(16 + TCB_SIZE, template.size());
+        assert_eq!(
+            VirtAddr::new(0x1010),
+            template.thread_pointer(VirtAddr::new(0x1000))
+        );
+    }
+
+    #[test_case]
+    fn should_read_thread_locals_of_loaded_program(_: KernelInformation) {
+        use core::arch::asm;
+        use kernel::processes::dispatcher::release_address_space;
+        use kernel::processes::process::Process;
+        use kernel::processes::tls::set_fs_base;
+        use kernel::processes::IDLE_PROCESS_ID;
+        use x86_64::instructions::interrupts::without_interrupts;
+        use x86_64::registers::control::Cr3;
+        use x86_64::structures::paging::PhysFrame;
+        use x86_64::VirtAddr;
+
+        // Assembled from ./assets/tls_check.s
+        let (process, _, tls) =
+            Process::from_elf(include_bytes!("./assets/tls_check.elf"), IDLE_PROCESS_ID)
+                .expect("Failed to load the TLS check program");
+        let tls = tls.expect("The program has no thread-local storage");
+        // Reading the variables like the program does, from its address space
+        let (thread_pointer, initial, counter) = without_interrupts(|| {
+            let (kernel_frame, flags) = Cr3::read();
+            unsafe { Cr3::write(PhysFrame::containing_address(process.cr3), flags) };
+            set_fs_base(tls.thread_pointer);
+            let (thread_pointer, initial, counter): (u64, u64, u64);
+            unsafe {
+                asm!(
+                    "mov {}, qword ptr fs:[0]",
+                    "mov {}, qword ptr fs:[-16]",
+                    "mov {}, qword ptr fs:[-8]",
+                    out(reg) thread_pointer,
+                    out(reg) initial,
+                    out(reg) counter,
+                    options(nostack, readonly),
+                );
+            }
+            set_fs_base(VirtAddr::zero());
+            unsafe { Cr3::write(kernel_frame, flags) };
+            (thread_pointer, initial, counter)
+        });
+        assert_eq!(tls.thread_pointer.as_u64(), thread_pointer);
+        assert_eq!(42, initial);
+        assert_eq!(0, counter);
+        release_address_space(process.cr3).expect("Failed to clear the user mode mapping");
+    }
+
+    #[test_case]
+    fn should_give_each_thread_its_own_thread_local(_: KernelInformation) {
+        use core::ptr::write_volatile;
+        use kernel::processes::tls::set_fs_base;
+        use x86_64::instructions::interrupts::without_interrupts;
+        use x86_64::VirtAddr;
+
+        rost_lib::thread_local! {
+            static COUNTER: u64 = 0;
+        }
+        // The kernel's thread-local variables fit below the thread pointers at the ends
+        let mut blocks = [[0u64; 64]; 2];
+        without_interrupts(|| {
+            for (index, block) in blocks.iter_mut().enumerate() {
+                let start = block.as_mut_ptr();
+                let thread_pointer = unsafe { start.add(63) };
+                unsafe { write_volatile(thread_pointer, thread_pointer as u64) };
+                set_fs_base(VirtAddr::from_ptr(thread_pointer));
+                let copy = COUNTER.with(|counter| counter as *const u64 as *mut u64);
+                assert!(start <= copy && copy < thread_pointer);
+                unsafe { write_volatile(copy, index as u64 + 1) };
+            }
+            for (index, block) in blocks.iter_mut().enumerate() {
+                set_fs_base(VirtAddr::from_ptr(unsafe { block.as_mut_ptr().add(63) }));
+                assert_eq!(index as u64 + 1, COUNTER.with(|counter| *counter));
+            }
+            set_fs_base(VirtAddr::zero());
+        });
+    }
+
+    #[test_case]
+    fn should_convert_unix_time(_: KernelInformation) {
+        use rtc::DateTime;
+        let date_time = DateTime {
+            year: 2024,
+            month: 2,
+            day: 29,
+            hour: 13,
+            minute: 37,
+            second: 42,
+        };
+        assert_eq!(1_709_213_862, date_time.unix_seconds());
+        assert_eq!(date_time, DateTime::from_unix_seconds(1_709_213_862));
+        assert_eq!(0, DateTime::from_unix_seconds(0).unix_seconds());
+    }
+
     #[test_case]
     fn should_allocate_small_box(_: KernelInformation) {
         let boxed = Box::new(4);